@@ -1,8 +1,12 @@
 #[macro_use] extern crate log;
 extern crate env_logger;
 
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 use tonic::Request;
 
+use flowty_types::config::{Config, ConfigOpt};
+
 use openworkflow::execution_broker_client::ExecutionBrokerClient;
 use openworkflow::{
 	RegistrationRequest,
@@ -18,14 +22,51 @@ pub mod openworkflow {
 	tonic::include_proto!("openworkflow");
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+	pub execution_broker_uri: String,
+	/// URI the executor is registered with
+	pub executor_uri: String,
+}
+
+impl Default for ClientConfig {
+	fn default() -> ClientConfig {
+		ClientConfig {
+			execution_broker_uri: "http://[::1]:50051".into(),
+			executor_uri: "http://[::1]:10000".into(),
+		}
+	}
+}
+
+impl Config for ClientConfig {
+	fn validate(&self) -> Result<(), String> {
+		if self.execution_broker_uri.parse::<tonic::transport::Uri>().is_err() {
+			return Err(format!("execution_broker_uri '{}' is not a valid URI", self.execution_broker_uri));
+		}
+		if self.executor_uri.parse::<tonic::transport::Uri>().is_err() {
+			return Err(format!("executor_uri '{}' is not a valid URI", self.executor_uri));
+		}
+		Ok(())
+	}
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "execution-registration-client", about = "Registers an executor with the ExecutionBroker")]
+struct Opt {
+	#[structopt(flatten)]
+	config: ConfigOpt,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	env_logger::init();
+	let config: ClientConfig = Opt::from_args().config.load_or_exit();
 
-	let mut client = ExecutionBrokerClient::connect("http://[::1]:50051".to_string()).await?;
+	let mut client = ExecutionBrokerClient::connect(config.execution_broker_uri.clone()).await?;
 	let response = client
 		.register_executor(Request::new(RegistrationRequest {
-			uri: "http://[::1]:10000".to_string(),
+			uri: config.executor_uri.clone(),
 			executor_definition: Some(ExecutorDefinition{
 				kind: 0,
 				specs: Some(ExecutorSpecification{
@@ -4,8 +4,14 @@ extern crate env_logger;
 use uuid::Uuid;
 use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use tonic::{transport::Server, Request, Response, Status};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tonic::{transport::Server, Request, Response, Status, Code};
+use tokio::signal::unix::{signal, SignalKind};
+
+use flowty_types::config::{Config, ConfigOpt};
 
 use openworkflow::execution_broker_server::{ExecutionBroker, ExecutionBrokerServer};
 use openworkflow::{
@@ -53,10 +59,15 @@ impl Executor {
 #[derive(Default)]
 pub struct FlowtyExecutionBroker {
 	executors: Arc<Mutex<Vec<Executor>>>,
+	/// Set on shutdown. No new registrations are accepted afterwards.
+	shutting_down: Arc<AtomicBool>,
 }
 impl FlowtyExecutionBroker {
 	pub fn new() -> FlowtyExecutionBroker {
-		FlowtyExecutionBroker{executors: Arc::new(Mutex::new(Vec::new()))}
+		FlowtyExecutionBroker{
+			executors: Arc::new(Mutex::new(Vec::new())),
+			shutting_down: Arc::new(AtomicBool::new(false)),
+		}
 	}
 
 	pub fn find_by_uri(&self, uri: std::string::String) -> Option<Executor> {
@@ -91,6 +102,10 @@ impl FlowtyExecutionBroker {
 impl ExecutionBroker for FlowtyExecutionBroker {
 	async fn register_executor(&self, request: Request<RegistrationRequest>) -> Result<Response<RegistrationReply>, Status> {
 		info!("Got a request from {:?}", request.remote_addr());
+		if self.shutting_down.load(Ordering::SeqCst) {
+			warn!("Rejecting registration, broker is shutting down");
+			return Err(Status::new(Code::Unavailable, "ExecutionBroker is shutting down"));
+		}
 		let request = request.into_inner();
 
 		let existing_executor = self.find_by_uri(request.uri.clone());
@@ -142,18 +157,71 @@ impl ExecutionBroker for FlowtyExecutionBroker {
 	}
 }
 
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+	let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => (),
+		_ = sigterm.recv() => (),
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+	/// Address the ExecutionBroker listens on
+	pub broker_addr: String,
+	/// How long in-flight requests may take to complete on shutdown
+	pub shutdown_drain_sec: u64,
+}
+
+impl Default for BrokerConfig {
+	fn default() -> BrokerConfig {
+		BrokerConfig {
+			broker_addr: "[::1]:50051".into(),
+			shutdown_drain_sec: 5,
+		}
+	}
+}
+
+impl Config for BrokerConfig {
+	fn validate(&self) -> Result<(), String> {
+		if self.broker_addr.parse::<std::net::SocketAddr>().is_err() {
+			return Err(format!("broker_addr '{}' is not a valid socket address", self.broker_addr));
+		}
+		Ok(())
+	}
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "execution-broker", about = "The flowty ExecutionBroker")]
+struct Opt {
+	#[structopt(flatten)]
+	config: ConfigOpt,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	env_logger::init();
+	let config: BrokerConfig = Opt::from_args().config.load_or_exit();
+
+	let addr = config.broker_addr.parse()?;
+	let drain_period = Duration::from_secs(config.shutdown_drain_sec);
+	let execution_broker = FlowtyExecutionBroker::new();
+	let shutting_down = Arc::clone(&execution_broker.shutting_down);
 
-	let addr = "[::1]:50051".parse().unwrap();
-	let execution_broker = FlowtyExecutionBroker::default();
+	let shutdown = async move {
+		shutdown_signal().await;
+		info!("Shutting down. No longer accepting registrations");
+		shutting_down.store(true, Ordering::SeqCst);
+		tokio::time::delay_for(drain_period).await;
+	};
 
 	info!("ExecutionBrokerServer listening on {}", addr);
 
 	Server::builder()
 		.add_service(ExecutionBrokerServer::new(execution_broker))
-		.serve(addr)
+		.serve_with_shutdown(addr, shutdown)
 		.await?;
 
 	Ok(())
@@ -1,3 +1,4 @@
 fn main() {
 	tonic_build::compile_protos("../OpenWorkflow/proto/openworkflow.proto").unwrap();
-}
\ No newline at end of file
+	tonic_build::compile_protos("proto/flowty.proto").unwrap();
+}
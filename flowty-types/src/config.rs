@@ -0,0 +1,262 @@
+//! Layered configuration shared by all flowty binaries.
+//!
+//! The effective configuration is built from, in increasing precedence:
+//! 1. the defaults of the binary
+//! 2. a TOML file, given by `--config` or `FLOWTY_CONFIG`. `flowty.toml` in the working directory is used if present
+//! 3. environment variables, named like the key in upper case, e.g. `LOOP_INTERVAL_SEC` for `loop_interval_sec`
+//! 4. `--set key=value` flags
+//!
+//! Every layer is merged into the defaults. Values from the environment and flags are parsed as the type of the
+//! default, so a typo fails at startup instead of silently falling back to the default.
+
+use std::path::PathBuf;
+
+use serde::{de::DeserializeOwned, Serialize};
+use structopt::StructOpt;
+use toml::Value;
+
+use crate::FlowtyError;
+
+const DEFAULT_CONFIG_FILE: &str = "flowty.toml";
+
+/// Configuration of a flowty binary.
+pub trait Config: Serialize + DeserializeOwned + Default {
+	/// Checks the values beyond their types. Returns a description of the first invalid value.
+	fn validate(&self) -> Result<(), String>;
+}
+
+/// Configuration flags every binary accepts.
+#[derive(Debug, StructOpt)]
+pub struct ConfigOpt {
+	/// TOML configuration file
+	#[structopt(long = "config", parse(from_os_str), env = "FLOWTY_CONFIG")]
+	pub config_file: Option<PathBuf>,
+	/// Overrides a configuration value, e.g. `--set loop_interval_sec=10`. Can be repeated
+	#[structopt(long = "set", number_of_values = 1)]
+	pub overrides: Vec<String>,
+	/// Prints the effective configuration as TOML and exits
+	#[structopt(long)]
+	pub dump_config: bool,
+}
+
+impl ConfigOpt {
+	/// Builds and validates the effective configuration.
+	pub fn load<C: Config>(&self) -> Result<C, FlowtyError> {
+		let mut config = Value::try_from(C::default()).map_err(|e| invalid(e.to_string()))?;
+
+		let file = match &self.config_file {
+			Some(file) => Some(file.clone()),
+			None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|f| f.exists()),
+		};
+		if let Some(file) = file {
+			let content = std::fs::read_to_string(&file)
+				.map_err(|e| invalid(format!("Failed to read '{}': {}", file.display(), e)))?;
+			let layer: Value = toml::from_str(&content)
+				.map_err(|e| invalid(format!("Failed to parse '{}': {}", file.display(), e)))?;
+			merge(&mut config, layer, &file.display().to_string())?;
+		}
+
+		let keys: Vec<String> = config.as_table().map(|t| t.keys().cloned().collect()).unwrap_or_default();
+		for key in keys {
+			if let Ok(raw) = std::env::var(key.to_uppercase()) {
+				set(&mut config, &key, &raw, "environment")?;
+			}
+		}
+
+		for o in &self.overrides {
+			let mut parts = o.splitn(2, '=');
+			match (parts.next(), parts.next()) {
+				(Some(key), Some(raw)) => set(&mut config, key.trim(), raw.trim(), "--set")?,
+				_ => return Err(invalid(format!("Expected key=value in --set, got '{}'", o))),
+			}
+		}
+
+		let config: C = config.try_into().map_err(|e| invalid(e.to_string()))?;
+		config.validate().map_err(invalid)?;
+		Ok(config)
+	}
+
+	/// Loads the configuration and handles `--dump-config`.
+	/// Exits the process if the configuration is invalid or was dumped.
+	pub fn load_or_exit<C: Config>(&self) -> C {
+		let config: C = match self.load() {
+			Ok(config) => config,
+			Err(e) => {
+				eprintln!("{}", e);
+				std::process::exit(2);
+			}
+		};
+		if self.dump_config {
+			match toml::to_string_pretty(&config) {
+				Ok(dump) => print!("{}", dump),
+				Err(e) => eprintln!("Failed to dump config: {}", e),
+			}
+			std::process::exit(0);
+		}
+		config
+	}
+}
+
+fn invalid(message: String) -> FlowtyError {
+	FlowtyError::InvalidConfig { message }
+}
+
+/// Merges the keys of a file into the config. Unknown keys are rejected.
+fn merge(config: &mut Value, layer: Value, source: &str) -> Result<(), FlowtyError> {
+	let table = config.as_table_mut().ok_or_else(|| invalid("Config has to be a table".into()))?;
+	let layer = match layer {
+		Value::Table(layer) => layer,
+		_ => return Err(invalid(format!("{} has to be a table", source))),
+	};
+	for (key, value) in layer {
+		match table.get(&key).map(|default| (default.same_type(&value), default.type_str())) {
+			Some((true, _)) => { table.insert(key, value); },
+			Some((false, expected)) => {
+				return Err(invalid(format!("'{}' in {} has to be a {}", key, source, expected)));
+			},
+			None => return Err(invalid(format!("Unknown key '{}' in {}", key, source))),
+		}
+	}
+	Ok(())
+}
+
+/// Sets a key from a raw string, parsed as the type of its default.
+fn set(config: &mut Value, key: &str, raw: &str, source: &str) -> Result<(), FlowtyError> {
+	let table = config.as_table_mut().ok_or_else(|| invalid("Config has to be a table".into()))?;
+	let value = match table.get(key) {
+		Some(Value::String(_)) => Some(Value::String(raw.to_string())),
+		Some(Value::Integer(_)) => raw.parse().map(Value::Integer).ok(),
+		Some(Value::Float(_)) => raw.parse().map(Value::Float).ok(),
+		Some(Value::Boolean(_)) => raw.parse().map(Value::Boolean).ok(),
+		Some(default) => {
+			return Err(invalid(format!("'{}' of type {} cannot be set from {}", key, default.type_str(), source)));
+		},
+		None => return Err(invalid(format!("Unknown key '{}' in {}", key, source))),
+	}.ok_or_else(|| invalid(format!(
+		"Invalid value '{}' for '{}' in {}, expected a {}", raw, key, source, table[key].type_str()
+	)))?;
+	table.insert(key.to_string(), value);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use serde::Deserialize;
+
+	/// Keys are prefixed, so the environment variables set here do not collide with the ones of other tests.
+	#[derive(Debug, Serialize, Deserialize)]
+	struct TestConfig {
+		layering_name: String,
+		layering_interval_sec: i64,
+		layering_ratio: f64,
+		layering_enabled: bool,
+		layering_tags: Vec<String>,
+	}
+
+	impl Default for TestConfig {
+		fn default() -> Self {
+			TestConfig {
+				layering_name: "default".to_string(),
+				layering_interval_sec: 30,
+				layering_ratio: 0.5,
+				layering_enabled: false,
+				layering_tags: Vec::new(),
+			}
+		}
+	}
+
+	impl Config for TestConfig {
+		fn validate(&self) -> Result<(), String> {
+			if self.layering_interval_sec <= 0 {
+				return Err("layering_interval_sec has to be positive".to_string());
+			}
+			Ok(())
+		}
+	}
+
+	/// Writes `content` to a file unique to the test `name`.
+	fn config_file(name: &str, content: &str) -> PathBuf {
+		let file = std::env::temp_dir().join(format!("flowty-config-{}-{}.toml", name, std::process::id()));
+		std::fs::write(&file, content).unwrap();
+		file
+	}
+
+	fn opt(config_file: Option<PathBuf>, overrides: &[&str]) -> ConfigOpt {
+		ConfigOpt {
+			config_file,
+			overrides: overrides.iter().map(|o| o.to_string()).collect(),
+			dump_config: false,
+		}
+	}
+
+	fn error<C: Config + std::fmt::Debug>(result: Result<C, FlowtyError>) -> String {
+		result.unwrap_err().to_string()
+	}
+
+	#[test]
+	fn defaults_apply_without_layers() {
+		let config: TestConfig = opt(None, &[]).load().unwrap();
+		assert_eq!(config.layering_interval_sec, 30);
+		assert!(config.layering_tags.is_empty());
+	}
+
+	#[test]
+	fn later_layers_take_precedence() {
+		let file = config_file("precedence", "layering_name = 'file'\nlayering_ratio = 0.25\nlayering_tags = ['a']\n");
+		let config: TestConfig = opt(Some(file.clone()), &[]).load().unwrap();
+		assert_eq!(config.layering_name, "file");
+		assert_eq!(config.layering_tags, vec!["a"]);
+		assert!(!config.layering_enabled);
+
+		// Only this test sets the environment variable of layering_name
+		std::env::set_var("LAYERING_NAME", "env");
+		let config: TestConfig = opt(Some(file.clone()), &[]).load().unwrap();
+		assert_eq!(config.layering_name, "env");
+		assert_eq!(config.layering_tags, vec!["a"]);
+
+		let overrides = ["layering_name = flag", "layering_enabled=true"];
+		let config: TestConfig = opt(Some(file.clone()), &overrides).load().unwrap();
+		assert_eq!(config.layering_name, "flag");
+		assert!(config.layering_enabled);
+		std::env::remove_var("LAYERING_NAME");
+		std::fs::remove_file(file).unwrap();
+	}
+
+	#[test]
+	fn values_have_the_type_of_their_default() {
+		let file = config_file("types", "layering_interval_sec = '10'\n");
+		let message = error(opt(Some(file.clone()), &[]).load::<TestConfig>());
+		assert!(message.contains("'layering_interval_sec'"), "{}", message);
+		assert!(message.contains("integer"), "{}", message);
+		std::fs::remove_file(file).unwrap();
+
+		let message = error(opt(None, &["layering_interval_sec=ten"]).load::<TestConfig>());
+		assert!(message.contains("Invalid value 'ten' for 'layering_interval_sec' in --set"), "{}", message);
+		let message = error(opt(None, &["layering_enabled=yes"]).load::<TestConfig>());
+		assert!(message.contains("'layering_enabled'"), "{}", message);
+		// Lists cannot be set from a single string
+		let message = error(opt(None, &["layering_tags=a"]).load::<TestConfig>());
+		assert!(message.contains("cannot be set from --set"), "{}", message);
+	}
+
+	#[test]
+	fn unknown_keys_are_rejected() {
+		let file = config_file("unknown", "layering_nmae = 'typo'\n");
+		let message = error(opt(Some(file.clone()), &[]).load::<TestConfig>());
+		assert!(message.contains("Unknown key 'layering_nmae'"), "{}", message);
+		std::fs::remove_file(file).unwrap();
+
+		let message = error(opt(None, &["layering_nmae=typo"]).load::<TestConfig>());
+		assert!(message.contains("Unknown key 'layering_nmae' in --set"), "{}", message);
+		let message = error(opt(None, &["layering_name"]).load::<TestConfig>());
+		assert!(message.contains("Expected key=value"), "{}", message);
+	}
+
+	#[test]
+	fn invalid_values_are_rejected() {
+		let message = error(opt(None, &["layering_interval_sec=0"]).load::<TestConfig>());
+		assert!(message.contains("has to be positive"), "{}", message);
+	}
+}
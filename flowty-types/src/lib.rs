@@ -2,13 +2,15 @@ use std::convert::TryInto;
 use std::convert::TryFrom;
 use std::io::Cursor;
 use petgraph::{Graph, Direction};
-use petgraph::graph::NodeIndex;
+pub use petgraph::graph::NodeIndex;
 use petgraph::algo;
 use chrono::Duration;
 use prost::{Message, DecodeError};
 
 use openworkflow::{Execution, Task, RunCondition, ExecutionStatus};
 
+pub mod config;
+
 // ===== ===== ===== ===== ===== \\
 // protobuf interactions
 // ===== ===== ===== ===== ===== //
@@ -16,6 +18,14 @@ pub mod openworkflow {
 	tonic::include_proto!("openworkflow");
 }
 
+/// Services of flowty itself, which OpenWorkflow does not specify
+pub mod flowty {
+	tonic::include_proto!("flowty");
+}
+
+/// Request metadata identifying an execution, so the scheduler can cancel it later through `TaskControl`.
+pub const EXECUTION_ID_METADATA: &str = "x-flowty-execution-id";
+
 pub fn validate_openworkflow(openworkflow: Result<openworkflow::Workflow, DecodeError>) -> Result<openworkflow::Workflow, DecodeError> {
 	// #TODO
 	match openworkflow {
@@ -66,9 +76,22 @@ pub struct TaskInstance {
 	execution_status: Option<ExecutionStatus>,
 	run_condition: RunCondition,
 	downstream_tasks: Vec<String>,
+	skipped: bool,
 }
 
 impl TaskInstance {
+	pub fn get_task_id(&self) -> &str {
+		&self.task_id
+	}
+
+	pub fn get_execution_status(&self) -> Option<ExecutionStatus> {
+		self.execution_status
+	}
+
+	pub fn is_skipped(&self) -> bool {
+		self.skipped
+	}
+
 	pub fn get_executor_definition(&self) -> Result<&openworkflow::ExecutorDefinition, FlowtyError> {
 		match &self.execution_details.executor {
 			Some(executor_definition) => Ok(&executor_definition),
@@ -104,6 +127,46 @@ impl Dag {
 	pub fn get_task_instance(&self, node_index: NodeIndex) -> &Node {
 		&self.graph[node_index]
 	}
+
+	pub fn node_indices(&self) -> impl Iterator<Item = NodeIndex> {
+		self.graph.node_indices()
+	}
+
+	pub fn set_execution_status(&mut self, node_index: NodeIndex, execution_status: ExecutionStatus) {
+		self.graph[node_index].execution_status = Some(execution_status);
+	}
+
+	pub fn task_instances(&self) -> impl Iterator<Item = &Node> {
+		self.graph.node_indices().map(move |i| &self.graph[i])
+	}
+
+	/// Resets a failed task for another attempt, if it has retries left.
+	/// Returns the retry_interval to wait before the next attempt.
+	pub fn retry(&mut self, node_index: NodeIndex) -> Option<Duration> {
+		let ti = &mut self.graph[node_index];
+		if ti.retries >= ti.max_retries {
+			return None;
+		}
+		ti.retries += 1;
+		ti.execution_status = None;
+		Some(ti.retry_interval)
+	}
+
+	/// Number of attempts made so far, not counting the first one.
+	pub fn get_retries(&self, node_index: NodeIndex) -> u32 {
+		self.graph[node_index].retries
+	}
+
+	/// Marks a task as skipped. Skipped tasks count as done, but neither as succeeded nor as failed.
+	pub fn skip(&mut self, node_index: NodeIndex) {
+		self.graph[node_index].skipped = true;
+	}
+
+	/// True if no task of the Dag failed.
+	/// Skipped tasks and tasks whose run_condition was never met do not fail the Dag.
+	pub fn is_success(&self) -> bool {
+		!self.task_instances().any(|ti| task_instance_is_failed(ti))
+	}
 }
 
 impl TryFrom<&Vec<Task>> for Dag {
@@ -131,6 +194,7 @@ impl TryFrom<&Vec<Task>> for Dag {
 				execution_status: None,
 				run_condition: RunCondition::from(task.condition),
 				downstream_tasks: task.downstream_tasks.clone(),
+				skipped: false,
 			};
 			graph.add_node(ti);
 		}
@@ -156,6 +220,9 @@ impl TryFrom<&Vec<Task>> for Dag {
 }
 
 pub fn task_instance_is_ready(ti: &TaskInstance) -> bool {
+	if ti.skipped {
+		return false;
+	}
 	match ti.execution_status {
 		None | Some(ExecutionStatus::Initializing) | Some(ExecutionStatus::Running) => true,
 		_ => false,
@@ -163,12 +230,47 @@ pub fn task_instance_is_ready(ti: &TaskInstance) -> bool {
 }
 
 pub fn task_instance_is_done(ti: &TaskInstance) -> bool {
+	if ti.skipped {
+		return true;
+	}
 	match ti.execution_status {
 		Some(ExecutionStatus::Failed) | Some(ExecutionStatus::Success) => true,
 		_ => false,
 	}
 }
 
+pub fn task_instance_is_success(ti: &TaskInstance) -> bool {
+	!ti.skipped && matches!(ti.execution_status, Some(ExecutionStatus::Success))
+}
+
+pub fn task_instance_is_failed(ti: &TaskInstance) -> bool {
+	!ti.skipped && matches!(ti.execution_status, Some(ExecutionStatus::Failed))
+}
+
+impl Dag {
+	/// Checks the run_condition of a task against its parents.
+	/// A task without a run_condition runs right away, regardless of its parents.
+	fn run_condition_met(&self, node: NodeIndex) -> bool {
+		let parents: Vec<&TaskInstance> = self.graph
+			.neighbors_directed(node, Direction::Incoming)
+			.map(|parent| &self.graph[parent])
+			.collect();
+		if parents.is_empty() {
+			return true;
+		}
+
+		match self.graph[node].run_condition {
+			RunCondition::AllDone => parents.iter().all(|p| task_instance_is_done(p)),
+			RunCondition::OneDone => parents.iter().any(|p| task_instance_is_done(p)),
+			RunCondition::None => true,
+			RunCondition::AllSuccess => parents.iter().all(|p| task_instance_is_success(p)),
+			RunCondition::OneSuccess => parents.iter().any(|p| task_instance_is_success(p)),
+			RunCondition::AllFailed => parents.iter().all(|p| task_instance_is_failed(p)),
+			RunCondition::OneFailed => parents.iter().any(|p| task_instance_is_failed(p)),
+		}
+	}
+}
+
 impl Iterator for Dag {
 	type Item = Vec<NodeIndex>;
 
@@ -176,96 +278,17 @@ impl Iterator for Dag {
 	/// Returns the current execution stage of the Dag. Meaning all TaskInstances which are currently executing or
 	/// ready for execution.
 	///
-	/// Uses toposort to start from the top, then checks if a task's run_condition is met.
-	/// Whenever a task is added to the current stage, it's downstream tasks are saved.
-	/// If a task does not appear in the saved downstream list, it's immediatly added to the stage.
-	/// If a task is inside the downstream list, its run_condition is checked.
+	/// A task is part of the stage if it is not done yet and its run_condition is met by its parents.
+	/// Returns None once no task can make progress anymore, either because all are done or because the
+	/// run_conditions of the remaining tasks can not be met.
 	fn next(&mut self) -> Option<Self::Item> {
-		let mut stage: Self::Item = Vec::new();
-		let mut downstream: Vec<String> = Vec::new();
-		for node in algo::toposort(&self.graph, None).unwrap() {
-			if task_instance_is_done(&self.graph[node]) {
-				continue;
-			}
-			let downstream_tasks = &self.graph[node].downstream_tasks;
-			if downstream.contains(&self.graph[node].task_id) {
-				match self.graph[node].run_condition {
-					RunCondition::None => {
-						stage.push(node);
-						downstream.append(&mut downstream_tasks.clone());
-					},
-					RunCondition::AllDone => {
-						let mut all_done = true;
-						for parent in self.graph.neighbors_directed(node, Direction::Incoming) {
-							if !task_instance_is_done(&self.graph[parent]) {
-								all_done = false;
-								break;
-							}
-						}
-						if all_done {
-							stage.push(node);
-							downstream.append(&mut downstream_tasks.clone());
-						}
-					},
-					RunCondition::OneDone => {
-						for parent in self.graph.neighbors_directed(node, Direction::Incoming) {
-							if task_instance_is_done(&self.graph[parent]) {
-								stage.push(node);
-								downstream.append(&mut downstream_tasks.clone());
-								break;
-							}
-						}
-					},
-					RunCondition::AllSuccess => {
-						let mut all_success = true;
-						for parent in self.graph.neighbors_directed(node, Direction::Incoming) {
-							if !matches!(self.graph[parent].execution_status, Some(ExecutionStatus::Failed)) {
-								all_success = false;
-							}
-						}
-						if all_success {
-							stage.push(node);
-							downstream.append(&mut downstream_tasks.clone());
-						}
-					},
-					RunCondition::OneSuccess => {
-						for parent in self.graph.neighbors_directed(node, Direction::Incoming) {
-							if matches!(self.graph[parent].execution_status, Some(ExecutionStatus::Success)) {
-								stage.push(node);
-								downstream.append(&mut downstream_tasks.clone());
-								break;
-							}
-						}
-					},
-					RunCondition::AllFailed => {
-						let mut all_failed = true;
-						for parent in self.graph.neighbors_directed(node, Direction::Incoming) {
-							if !matches!(self.graph[parent].execution_status, Some(ExecutionStatus::Failed)) {
-								all_failed = false;
-							}
-						}
-						if all_failed {
-							stage.push(node);
-							downstream.append(&mut downstream_tasks.clone());
-						}
-					},
-					RunCondition::OneFailed => {
-						for parent in self.graph.neighbors_directed(node, Direction::Incoming) {
-							if matches!(self.graph[parent].execution_status, Some(ExecutionStatus::Failed)) {
-								stage.push(node);
-								downstream.append(&mut downstream_tasks.clone());
-								break;
-							}
-						}
-					},
-				};
-			} else {
-				stage.push(node);
-				downstream.append(&mut downstream_tasks.clone());
-			}
-		}
+		let stage: Self::Item = algo::toposort(&self.graph, None)
+			.unwrap()
+			.into_iter()
+			.filter(|node| task_instance_is_ready(&self.graph[*node]) && self.run_condition_met(*node))
+			.collect();
 
-		if stage.len() == 0 {
+		if stage.is_empty() {
 			None
 		} else {
 			Some(stage)
@@ -297,4 +320,160 @@ pub enum FlowtyError {
 	ExecutorNotFound,
 	#[snafu(display("Cyclic dependency detected!"))]
 	CyclicDependencyError,
+	#[snafu(display("Invalid schedule '{}': {}", schedule, message))]
+	InvalidSchedule {
+		schedule: String,
+		message: String,
+	},
+	#[snafu(display("Invalid configuration: {}", message))]
+	InvalidConfig {
+		message: String,
+	},
+	#[snafu(display("Run '{}' cannot change from {} to {}", run_id, from, to))]
+	IllegalRunStateTransition {
+		run_id: String,
+		from: String,
+		to: String,
+	},
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn task(task_id: &str, condition: RunCondition, downstream_tasks: &[&str]) -> Task {
+		Task {
+			task_id: task_id.to_string(),
+			condition: condition as i32,
+			downstream_tasks: downstream_tasks.iter().map(|t| t.to_string()).collect(),
+			execution: Some(Execution::default()),
+			..Default::default()
+		}
+	}
+
+	/// The parents `a` and `b` and their `child`, which runs on `condition`.
+	fn parents_and_child(condition: RunCondition) -> Dag {
+		Dag::try_from(&vec![
+			task("a", RunCondition::None, &["child"]),
+			task("b", RunCondition::None, &["child"]),
+			task("child", condition, &[]),
+		]).unwrap()
+	}
+
+	fn node(dag: &Dag, task_id: &str) -> NodeIndex {
+		dag.node_indices().find(|n| dag.get_task_instance(*n).get_task_id() == task_id).unwrap()
+	}
+
+	fn set(dag: &mut Dag, task_id: &str, status: ExecutionStatus) {
+		let node = node(dag, task_id);
+		dag.set_execution_status(node, status);
+	}
+
+	/// The task_ids of the current stage, sorted.
+	fn stage(dag: &mut Dag) -> Vec<String> {
+		let mut stage: Vec<String> = dag
+			.next()
+			.unwrap_or_default()
+			.into_iter()
+			.map(|n| dag.get_task_instance(n).get_task_id().to_string())
+			.collect();
+		stage.sort();
+		stage
+	}
+
+	#[test]
+	fn none_runs_right_away() {
+		let mut dag = parents_and_child(RunCondition::None);
+		assert_eq!(stage(&mut dag), vec!["a", "b", "child"]);
+		set(&mut dag, "a", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["b", "child"]);
+	}
+
+	#[test]
+	fn all_done_waits_for_every_parent() {
+		let mut dag = parents_and_child(RunCondition::AllDone);
+		assert_eq!(stage(&mut dag), vec!["a", "b"]);
+		set(&mut dag, "a", ExecutionStatus::Success);
+		assert_eq!(stage(&mut dag), vec!["b"]);
+		set(&mut dag, "b", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["child"]);
+	}
+
+	#[test]
+	fn one_done_waits_for_any_parent() {
+		let mut dag = parents_and_child(RunCondition::OneDone);
+		assert_eq!(stage(&mut dag), vec!["a", "b"]);
+		set(&mut dag, "a", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["b", "child"]);
+	}
+
+	#[test]
+	fn all_success_needs_every_parent_to_succeed() {
+		let mut dag = parents_and_child(RunCondition::AllSuccess);
+		set(&mut dag, "a", ExecutionStatus::Success);
+		assert_eq!(stage(&mut dag), vec!["b"]);
+		set(&mut dag, "b", ExecutionStatus::Success);
+		assert_eq!(stage(&mut dag), vec!["child"]);
+
+		let mut dag = parents_and_child(RunCondition::AllSuccess);
+		set(&mut dag, "a", ExecutionStatus::Success);
+		set(&mut dag, "b", ExecutionStatus::Failed);
+		assert!(stage(&mut dag).is_empty());
+	}
+
+	#[test]
+	fn one_success_needs_any_parent_to_succeed() {
+		let mut dag = parents_and_child(RunCondition::OneSuccess);
+		set(&mut dag, "a", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["b"]);
+		set(&mut dag, "b", ExecutionStatus::Success);
+		assert_eq!(stage(&mut dag), vec!["child"]);
+	}
+
+	#[test]
+	fn all_failed_needs_every_parent_to_fail() {
+		let mut dag = parents_and_child(RunCondition::AllFailed);
+		set(&mut dag, "a", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["b"]);
+		set(&mut dag, "b", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["child"]);
+
+		let mut dag = parents_and_child(RunCondition::AllFailed);
+		set(&mut dag, "a", ExecutionStatus::Failed);
+		set(&mut dag, "b", ExecutionStatus::Success);
+		assert!(stage(&mut dag).is_empty());
+	}
+
+	#[test]
+	fn one_failed_needs_any_parent_to_fail() {
+		let mut dag = parents_and_child(RunCondition::OneFailed);
+		set(&mut dag, "a", ExecutionStatus::Success);
+		assert_eq!(stage(&mut dag), vec!["b"]);
+		set(&mut dag, "b", ExecutionStatus::Failed);
+		assert_eq!(stage(&mut dag), vec!["child"]);
+	}
+
+	#[test]
+	fn skipped_parents_are_done_but_did_not_succeed() {
+		let mut dag = parents_and_child(RunCondition::AllDone);
+		let a = node(&dag, "a");
+		dag.skip(a);
+		set(&mut dag, "b", ExecutionStatus::Success);
+		assert_eq!(stage(&mut dag), vec!["child"]);
+
+		let mut dag = parents_and_child(RunCondition::AllSuccess);
+		let a = node(&dag, "a");
+		dag.skip(a);
+		set(&mut dag, "b", ExecutionStatus::Success);
+		assert!(stage(&mut dag).is_empty());
+		assert!(dag.is_success());
+	}
+
+	#[test]
+	fn failed_tasks_fail_the_dag() {
+		let mut dag = parents_and_child(RunCondition::AllSuccess);
+		set(&mut dag, "a", ExecutionStatus::Success);
+		set(&mut dag, "b", ExecutionStatus::Failed);
+		assert!(!dag.is_success());
+	}
 }
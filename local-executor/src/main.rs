@@ -1,21 +1,195 @@
 #[macro_use] extern crate log;
 extern crate env_logger;
 
-use std::process::{Command, Stdio};
-use std::io::{BufReader, BufRead};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 use tonic::{transport::Server, Request, Response, Status, Code};
-use tokio::sync::mpsc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::stream::StreamExt;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
 
+use flowty_types::EXECUTION_ID_METADATA;
+use flowty_types::config::{Config, ConfigOpt};
+use flowty_types::flowty::task_control_server::{TaskControl, TaskControlServer};
+use flowty_types::flowty::{CancelTaskRequest, CancelTaskResponse};
 use flowty_types::openworkflow::executor_server::{Executor, ExecutorServer};
 use flowty_types::openworkflow::{
+	execution,
 	Task,
 	ExecutionOutput,
 	ExecutionStatus
 };
 
-#[derive(Default)]
-pub struct LocalExecutor {}
+/// How often a task without output reports that it is still running.
+/// A scheduler hanging up on the stream is only noticed when sending.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct LocalExecutor {
+	/// Set on shutdown. No new tasks are accepted afterwards.
+	shutting_down: Arc<AtomicBool>,
+	/// Number of tasks currently executing
+	running: Arc<AtomicUsize>,
+	/// Broadcasts `true` when running tasks have to be killed
+	kill: watch::Receiver<bool>,
+	/// Cancels the running tasks, by the execution id the scheduler sent along
+	cancels: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
+
+/// Cancels the tasks of the `LocalExecutor` sharing `cancels`.
+pub struct LocalTaskControl {
+	cancels: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
+
+/// Keeps the count of running tasks up to date, even if the task's future is dropped.
+struct RunningGuard(Arc<AtomicUsize>);
+
+impl RunningGuard {
+	fn new(running: Arc<AtomicUsize>) -> RunningGuard {
+		running.fetch_add(1, Ordering::SeqCst);
+		RunningGuard(running)
+	}
+}
+
+impl Drop for RunningGuard {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Resolves once running tasks have to be killed.
+async fn killed(kill: &mut watch::Receiver<bool>) {
+	while let Some(kill) = kill.recv().await {
+		if kill {
+			return;
+		}
+	}
+	futures::future::pending::<()>().await;
+}
+
+/// Resolves once the scheduler cancelled the task.
+async fn cancelled(cancel: &mut Option<oneshot::Receiver<()>>) {
+	if let Some(receiver) = cancel {
+		let requested = receiver.await.is_ok();
+		*cancel = None;
+		if requested {
+			return;
+		}
+	}
+	futures::future::pending::<()>().await;
+}
+
+/// Reads the metadata `key` of a request.
+fn metadata<T>(request: &Request<T>, key: &str) -> Option<String> {
+	request.metadata().get(key).and_then(|value| value.to_str().ok()).map(String::from)
+}
+
+/// Runs the command and streams its output. The child is killed if the executor shuts down before it finishes,
+/// if the scheduler cancelled the task, or if the scheduler hung up on the stream.
+async fn execute_command(
+	command: String,
+	mut tx: mpsc::Sender<Result<ExecutionOutput, Status>>,
+	mut kill: watch::Receiver<bool>,
+	cancel: oneshot::Receiver<()>
+) {
+	let mut cancel = Some(cancel);
+	let _ = tx.send(Ok(ExecutionOutput{
+		status: ExecutionStatus::Initializing as i32,
+		message: String::from("Local-Executor: Initializing task"),
+	})).await;
+	info!("Executing command: {}", command);
+	let mut cmd = match Command::new(&command)
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn() {
+		Ok(cmd) => cmd,
+		Err(e) => {
+			error!("Failed to execute '{}': {}", command, e);
+			let _ = tx.send(Err(Status::new(Code::Aborted, "Failed to execute task"))).await;
+			return;
+		}
+	};
+
+	let stdout = BufReader::new(cmd.stdout.take().unwrap()).lines();
+	let stderr = BufReader::new(cmd.stderr.take().unwrap()).lines();
+	let mut output = stdout.merge(stderr);
+	let mut liveness = time::interval(LIVENESS_INTERVAL);
+	loop {
+		let sent = tokio::select! {
+			line = output.next() => match line {
+				Some(Ok(line)) => {
+					tx.send(Ok(ExecutionOutput{
+						status: ExecutionStatus::Running as i32,
+						message: line,
+					})).await
+				},
+				Some(Err(e)) => {
+					warn!("Failed to read output of '{}': {}", command, e);
+					Ok(())
+				},
+				None => break,
+			},
+			_ = liveness.tick() => {
+				tx.send(Ok(ExecutionOutput{
+					status: ExecutionStatus::Running as i32,
+					message: String::from("Local-Executor: Task is running"),
+				})).await
+			},
+			_ = killed(&mut kill) => {
+				warn!("Killing '{}' (pid {}) as the executor is shutting down", command, cmd.id());
+				let _ = cmd.kill();
+				let _ = tx.send(Ok(ExecutionOutput{
+					status: ExecutionStatus::Failed as i32,
+					message: String::from("Local-Executor: Task killed, executor is shutting down"),
+				})).await;
+				return;
+			},
+			_ = cancelled(&mut cancel) => {
+				warn!("Killing '{}' (pid {}) as the scheduler cancelled it", command, cmd.id());
+				let _ = cmd.kill();
+				// The stream only ends once the child is gone, so the scheduler can rely on it
+				let _ = (&mut cmd).await;
+				let _ = tx.send(Ok(ExecutionOutput{
+					status: ExecutionStatus::Failed as i32,
+					message: String::from("Local-Executor: Task killed, it was cancelled"),
+				})).await;
+				return;
+			},
+		};
+		if sent.is_err() {
+			warn!("Task '{}' was cancelled. Killing it (pid {})", command, cmd.id());
+			let _ = cmd.kill();
+			return;
+		}
+	}
+
+	match cmd.await {
+		Ok(s) if s.success() => {
+			let _ = tx.send(Ok(ExecutionOutput{
+				status: ExecutionStatus::Success as i32,
+				message: s.code().unwrap_or_default().to_string(),
+			})).await;
+		},
+		Ok(s) => {
+			let _ = tx.send(Ok(ExecutionOutput{
+				status: ExecutionStatus::Failed as i32,
+				message: s.code().map_or_else(|| "Terminated by signal".to_string(), |c| c.to_string()),
+			})).await;
+		},
+		_ => {
+			let _ = tx.send(Err(Status::new(Code::Aborted, "Failed to execute task"))).await;
+		}
+	}
+}
 
 #[tonic::async_trait]
 impl Executor for LocalExecutor {
@@ -23,61 +197,31 @@ impl Executor for LocalExecutor {
 
 	async fn execute_task(&self, request: Request<Task>) -> Result<Response<Self::ExecuteTaskStream>, Status> {
 		trace!("ExecuteTask = {:?}", request);
+		let execution_id = metadata(&request, EXECUTION_ID_METADATA);
 		let task = request.into_inner();
+		if self.shutting_down.load(Ordering::SeqCst) {
+			warn!("Rejecting task '{}', executor is shutting down", task.task_id);
+			return Err(Status::new(Code::Unavailable, "Local-Executor is shutting down"));
+		}
 		info!("Trying to execute task '{}'", task.task_id);
 
-		match task.execution.unwrap().execution.unwrap().exec {
-			Some(openworkflow::execution_definition::Exec::Local(local_execution)) => {
-				let (mut tx, rx) = mpsc::channel(1);
+		match task.execution.and_then(|e| e.exec) {
+			Some(execution::Exec::Local(local_execution)) => {
+				let (tx, rx) = mpsc::channel(1);
+				let running = RunningGuard::new(Arc::clone(&self.running));
+				let kill = self.kill.clone();
+				let (cancel_tx, cancel_rx) = oneshot::channel();
+				let cancels = Arc::clone(&self.cancels);
+				// Without an execution id the task can only be cancelled by hanging up
+				if let Some(execution_id) = &execution_id {
+					cancels.lock().unwrap().insert(execution_id.clone(), cancel_tx);
+				}
 
 				tokio::spawn(async move {
-					tx.send(Ok(ExecutionOutput{
-						status: ExecutionStatus::Initializing as i32,
-						message: String::from("Local-Executor: Initializing task"),
-					})).await.unwrap();
-					info!("Executing command: {}", local_execution.command.clone());
-					let mut cmd = Command::new(local_execution.command)
-						.stdout(Stdio::piped())
-						.stderr(Stdio::piped())
-						.spawn()
-						.expect("failed to execute process");
-					{
-						let stdout = cmd.stdout.as_mut().unwrap();
-						let stdout_reader = BufReader::new(stdout);
-						let stderr = cmd.stderr.as_mut().unwrap();
-						let stderr_reader = BufReader::new(stderr);
-
-						// Todo: Pack this into threads
-						for line in stdout_reader.lines() {
-							tx.send(Ok(ExecutionOutput{
-								status: ExecutionStatus::Running as i32,
-								message: line.unwrap(),
-							})).await.unwrap();
-						}
-						for line in stderr_reader.lines() {
-							tx.send(Ok(ExecutionOutput{
-								status: ExecutionStatus::Running as i32,
-								message: line.unwrap(),
-							})).await.unwrap();
-						}
-
-						match cmd.wait() {
-							Ok(s) if s.success() => {
-								tx.send(Ok(ExecutionOutput{
-									status: ExecutionStatus::Success as i32,
-									message: s.code().unwrap().to_string(),
-								})).await.unwrap();
-							},
-							Ok(s) => {
-								tx.send(Ok(ExecutionOutput{
-									status: ExecutionStatus::Failed as i32,
-									message: s.code().unwrap().to_string(),
-								})).await.unwrap();
-							},
-							_ => {
-								tx.send(Err(Status::new(Code::Aborted, "Failed to execute task"))).await.unwrap();
-							}
-						}
+					let _running = running;
+					execute_command(local_execution.command, tx, kill, cancel_rx).await;
+					if let Some(execution_id) = execution_id {
+						cancels.lock().unwrap().remove(&execution_id);
 					}
 				});
 
@@ -91,21 +235,110 @@ impl Executor for LocalExecutor {
 	}
 }
 
-const URI: &str = "[::1]:50052";
+#[tonic::async_trait]
+impl TaskControl for LocalTaskControl {
+	/// Kills the task of the execution. The outcome is reported on the stream of the execution itself.
+	async fn cancel_task(&self, request: Request<CancelTaskRequest>) -> Result<Response<CancelTaskResponse>, Status> {
+		let execution_id = request.into_inner().execution_id;
+		match self.cancels.lock().unwrap().remove(&execution_id) {
+			Some(cancel) => {
+				info!("Cancelling execution '{}'", execution_id);
+				let _ = cancel.send(());
+				Ok(Response::new(CancelTaskResponse {}))
+			},
+			None => Err(Status::new(Code::NotFound, format!("Execution '{}' is not running", execution_id))),
+		}
+	}
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+	let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => (),
+		_ = sigterm.recv() => (),
+	}
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecutorConfig {
+	/// Address the executor listens on
+	pub executor_addr: String,
+	/// How long running tasks may take to finish on shutdown, before they are killed
+	pub shutdown_grace_sec: u64,
+}
+
+impl Default for ExecutorConfig {
+	fn default() -> ExecutorConfig {
+		ExecutorConfig {
+			executor_addr: "[::1]:50052".into(),
+			shutdown_grace_sec: 30,
+		}
+	}
+}
+
+impl Config for ExecutorConfig {
+	fn validate(&self) -> Result<(), String> {
+		if self.executor_addr.parse::<std::net::SocketAddr>().is_err() {
+			return Err(format!("executor_addr '{}' is not a valid socket address", self.executor_addr));
+		}
+		Ok(())
+	}
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "local-executor", about = "Executes tasks as local processes")]
+struct Opt {
+	#[structopt(flatten)]
+	config: ConfigOpt,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 	env_logger::init();
+	let config: ExecutorConfig = Opt::from_args().config.load_or_exit();
+
+	let addr = config.executor_addr.parse()?;
+	let grace_period = Duration::from_secs(config.shutdown_grace_sec);
+	let shutting_down = Arc::new(AtomicBool::new(false));
+	let running = Arc::new(AtomicUsize::new(0));
+	let (kill_tx, kill_rx) = watch::channel(false);
+	let cancels = Arc::new(Mutex::new(HashMap::new()));
+	let executor = LocalExecutor {
+		shutting_down: Arc::clone(&shutting_down),
+		running: Arc::clone(&running),
+		kill: kill_rx,
+		cancels: Arc::clone(&cancels),
+	};
+	let task_control = LocalTaskControl { cancels };
 
-	let addr = URI.parse().unwrap();
-	let executor = LocalExecutor::default();
+	let shutdown = async move {
+		shutdown_signal().await;
+		info!("Shutting down. No longer accepting tasks");
+		shutting_down.store(true, Ordering::SeqCst);
+
+		let deadline = Instant::now() + grace_period;
+		while running.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+			info!("Waiting for {} running tasks to finish", running.load(Ordering::SeqCst));
+			time::delay_for(Duration::from_secs(1)).await;
+		}
+		if running.load(Ordering::SeqCst) > 0 {
+			warn!("Grace period is over. Killing {} running tasks", running.load(Ordering::SeqCst));
+			let _ = kill_tx.broadcast(true);
+			while running.load(Ordering::SeqCst) > 0 {
+				time::delay_for(Duration::from_millis(100)).await;
+			}
+		}
+	};
 
 	info!("Executor listening on {}", addr);
 
 	Server::builder()
 		.add_service(ExecutorServer::new(executor))
-		.serve(addr)
+		.add_service(TaskControlServer::new(task_control))
+		.serve_with_shutdown(addr, shutdown)
 		.await?;
 
 	Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,7 @@
+CREATE FUNCTION notify_scheduler() RETURNS trigger AS $$
+BEGIN
+	PERFORM pg_notify('flowty_scheduler', TG_TABLE_NAME || ':' || NEW.workflow_id);
+
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Extends runstate of existing databases. New databases get the full type from type_runstate.sql.
+-- New enum values cannot be used in the transaction adding them, so run this without wrapping it in a transaction.
+ALTER TYPE runstate ADD VALUE IF NOT EXISTS 'waiting_upstream' BEFORE 'running';
+ALTER TYPE runstate ADD VALUE IF NOT EXISTS 'cancelled';
+ALTER TYPE runstate ADD VALUE IF NOT EXISTS 'skipped';
+ALTER TYPE runstate ADD VALUE IF NOT EXISTS 'up_for_retry';
+ALTER TYPE runstate ADD VALUE IF NOT EXISTS 'timed_out';
+
+-- Runs which timed out or were cancelled before stay failed. Their reason is free text and nothing else recorded
+-- why they ended, so they cannot be told apart reliably.
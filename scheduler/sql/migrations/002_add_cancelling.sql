@@ -0,0 +1,3 @@
+-- Adds the cancelling state to existing databases. Apply trg_notify_scheduler.sql again afterwards, so cancel
+-- requests wake the schedulers up.
+ALTER TYPE runstate ADD VALUE IF NOT EXISTS 'cancelling' BEFORE 'cancelled';
@@ -0,0 +1,10 @@
+DROP TABLE IF EXISTS calendar_date;
+
+CREATE TABLE IF NOT EXISTS calendar_date (
+	calendar_id TEXT,
+	excluded_date DATE,
+
+	created_at TIMESTAMP DEFAULT NOW(),
+
+	PRIMARY KEY (calendar_id, excluded_date)
+);
@@ -0,0 +1,26 @@
+DROP TABLE IF EXISTS notification_hook;
+
+-- Notifies about the runs of a workflow, or about a single task if task_id is set.
+-- target is a URL for 'webhook', comma separated addresses for 'email' and a shell command for 'command'.
+-- template is the JSON body of a webhook or the body of an email, subject the subject of an email.
+-- Defaults are used if they are not set. target, subject and template support notification templates, except
+-- for 'command' targets, which get the notification as FLOWTY_* environment variables instead.
+CREATE TABLE IF NOT EXISTS notification_hook (
+	nhid SERIAL PRIMARY KEY,
+	workflow_id TEXT NOT NULL,
+	task_id TEXT,
+	events TEXT[] NOT NULL DEFAULT '{failure}' CHECK (events <@ ARRAY['failure', 'retry', 'success', 'sla_miss']),
+	sink TEXT NOT NULL CHECK (sink IN ('webhook', 'email', 'command')),
+	target TEXT NOT NULL,
+	subject TEXT,
+	template TEXT,
+
+	created_at TIMESTAMP DEFAULT NOW(),
+	modified_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS ix_notification_hook_workflow_id ON notification_hook(workflow_id);
+
+CREATE TRIGGER last_modified_at
+BEFORE UPDATE ON notification_hook
+FOR EACH ROW EXECUTE PROCEDURE last_modified_at();
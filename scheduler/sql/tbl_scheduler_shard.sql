@@ -0,0 +1,15 @@
+DROP TABLE IF EXISTS scheduler_member;
+DROP TABLE IF EXISTS scheduler_shard;
+
+-- Live schedulers in sharded mode. Members which stop sending heartbeats lose their shards.
+CREATE TABLE IF NOT EXISTS scheduler_member (
+	member_id TEXT PRIMARY KEY,
+	heartbeat_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+-- Ownership of workflow shards. A workflow belongs to shard (hashtext(workflow_id) & 2147483647) % shard_count.
+CREATE TABLE IF NOT EXISTS scheduler_shard (
+	shard INTEGER PRIMARY KEY,
+	owner TEXT,
+	lease_until TIMESTAMPTZ
+);
@@ -0,0 +1,23 @@
+DROP TABLE IF EXISTS sla;
+
+-- Service level agreement of the runs of a workflow, or of a single task if task_id is set.
+-- A run or task has to finish deadline_offset_sec after its run_date and max_duration_sec after it started running.
+-- Either of them may be omitted.
+CREATE TABLE IF NOT EXISTS sla (
+	slaid SERIAL PRIMARY KEY,
+	workflow_id TEXT NOT NULL,
+	task_id TEXT,
+	deadline_offset_sec BIGINT CHECK (deadline_offset_sec >= 0),
+	max_duration_sec BIGINT CHECK (max_duration_sec > 0),
+
+	created_at TIMESTAMP DEFAULT NOW(),
+	modified_at TIMESTAMP DEFAULT NOW(),
+
+	CHECK (deadline_offset_sec IS NOT NULL OR max_duration_sec IS NOT NULL)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS ux_sla_workflow_task ON sla(workflow_id, (COALESCE(task_id, '')));
+
+CREATE TRIGGER last_modified_at
+BEFORE UPDATE ON sla
+FOR EACH ROW EXECUTE PROCEDURE last_modified_at();
@@ -0,0 +1,17 @@
+DROP TABLE IF EXISTS sla_miss;
+
+-- Missed SLAs, recorded once per run, task and kind. Runs which did not start yet are included,
+-- their run_id is the one the scheduled instance will get.
+CREATE TABLE IF NOT EXISTS sla_miss (
+	smid SERIAL PRIMARY KEY,
+	workflow_id TEXT NOT NULL,
+	run_id TEXT NOT NULL,
+	run_date TIMESTAMPTZ NOT NULL,
+	task_id TEXT,
+	kind TEXT NOT NULL CHECK (kind IN ('deadline', 'max_duration')),
+	due_at TIMESTAMPTZ NOT NULL,
+
+	created_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS ux_sla_miss ON sla_miss(workflow_id, run_id, (COALESCE(task_id, '')), kind);
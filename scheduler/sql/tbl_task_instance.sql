@@ -0,0 +1,17 @@
+DROP TABLE IF EXISTS task_instance;
+
+CREATE TABLE IF NOT EXISTS task_instance (
+	tiid SERIAL PRIMARY KEY,
+	wiid INTEGER REFERENCES workflow_instance(wiid) ON DELETE CASCADE,
+	task_id TEXT,
+	state TEXT,
+
+	created_at TIMESTAMP DEFAULT NOW(),
+	modified_at TIMESTAMP DEFAULT NOW(),
+
+	UNIQUE (wiid, task_id)
+);
+
+CREATE TRIGGER last_modified_at
+BEFORE UPDATE ON task_instance
+FOR EACH ROW EXECUTE PROCEDURE last_modified_at();
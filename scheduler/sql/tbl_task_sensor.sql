@@ -0,0 +1,23 @@
+DROP TABLE IF EXISTS task_sensor;
+
+-- Turns a task of a workflow into a sensor, which the scheduler evaluates itself instead of dispatching it.
+-- target is a path for 'file', a query for 'sql' and a URL for 'http'. Paths and URLs support task templates.
+-- Downstream tasks only wait for the sensor if their run_condition says so, e.g. all_success.
+CREATE TABLE IF NOT EXISTS task_sensor (
+	workflow_id TEXT,
+	task_id TEXT,
+	kind TEXT NOT NULL CHECK (kind IN ('file', 'sql', 'http')),
+	target TEXT NOT NULL,
+	poke_interval_sec BIGINT DEFAULT 60,
+	timeout_sec BIGINT,
+	soft_fail BOOLEAN DEFAULT FALSE,
+
+	created_at TIMESTAMP DEFAULT NOW(),
+	modified_at TIMESTAMP DEFAULT NOW(),
+
+	PRIMARY KEY (workflow_id, task_id)
+);
+
+CREATE TRIGGER last_modified_at
+BEFORE UPDATE ON task_sensor
+FOR EACH ROW EXECUTE PROCEDURE last_modified_at();
@@ -0,0 +1,22 @@
+DROP TABLE IF EXISTS workflow_dependency;
+
+-- A workflow, or one of its tasks if task_id is set, waits for the upstream workflow,
+-- or one of its tasks if upstream_task_id is set, to succeed for run_date + run_date_offset_sec.
+CREATE TABLE IF NOT EXISTS workflow_dependency (
+	wdid SERIAL PRIMARY KEY,
+	workflow_id TEXT NOT NULL,
+	task_id TEXT,
+	upstream_workflow_id TEXT NOT NULL,
+	upstream_task_id TEXT,
+	run_date_offset_sec BIGINT DEFAULT 0,
+	timeout_sec BIGINT,
+
+	created_at TIMESTAMP DEFAULT NOW(),
+	modified_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS ix_workflow_dependency_workflow_id ON workflow_dependency(workflow_id);
+
+CREATE TRIGGER last_modified_at
+BEFORE UPDATE ON workflow_dependency
+FOR EACH ROW EXECUTE PROCEDURE last_modified_at();
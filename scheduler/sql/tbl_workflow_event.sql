@@ -0,0 +1,12 @@
+DROP TABLE IF EXISTS workflow_event;
+
+CREATE TABLE IF NOT EXISTS workflow_event (
+	weid SERIAL PRIMARY KEY,
+	workflow_id TEXT,
+	event TEXT,
+	message TEXT,
+
+	created_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS ix_workflow_event_workflow_id ON workflow_event(workflow_id);
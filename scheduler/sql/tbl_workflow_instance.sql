@@ -3,11 +3,22 @@ DROP TABLE IF EXISTS workflow_instance;
 CREATE TABLE IF NOT EXISTS workflow_instance (
 	wiid SERIAL PRIMARY KEY,
 	workflow_id TEXT,
+	-- Version of the workflow definition the instance was created from
+	wid INTEGER,
+	run_id TEXT,
+	run_type TEXT DEFAULT 'scheduled' CHECK (run_type IN ('scheduled', 'manual')),
 	run_state runstate DEFAULT 'nothing',
-	run_date TIMESTAMP,
+	run_date TIMESTAMPTZ,
+	timezone TEXT DEFAULT 'UTC',
+	conf JSONB DEFAULT '{}',
+	-- Explains the run_state, e.g. why the run failed
+	reason TEXT,
+	running_since TIMESTAMPTZ,
 
 	created_at TIMESTAMP DEFAULT NOW(),
-	modified_at TIMESTAMP DEFAULT NOW()
+	modified_at TIMESTAMP DEFAULT NOW(),
+
+	UNIQUE (workflow_id, run_id)
 );
 
 CREATE INDEX IF NOT EXISTS ix_workflow_instance_workflow_id ON workflow_instance(workflow_id);
@@ -0,0 +1,27 @@
+DROP TABLE IF EXISTS workflow_setting;
+
+CREATE TABLE IF NOT EXISTS workflow_setting (
+	workflow_id TEXT PRIMARY KEY,
+	start_date TIMESTAMPTZ,
+	end_date TIMESTAMPTZ,
+	catchup TEXT DEFAULT 'all' CHECK (catchup IN ('all', 'latest_only', 'none')),
+	overlap_policy TEXT DEFAULT 'allow' CHECK (overlap_policy IN ('allow', 'skip', 'cancel_previous', 'queue')),
+	timezone TEXT DEFAULT 'UTC',
+	exclusion_calendars TEXT[] DEFAULT '{}',
+	is_paused BOOLEAN DEFAULT FALSE,
+	is_disabled BOOLEAN DEFAULT FALSE,
+	skip_until TIMESTAMPTZ,
+	max_active_tasks INTEGER CHECK (max_active_tasks > 0),
+	task_priorities JSONB DEFAULT '{}',
+	run_timeout_sec INTEGER CHECK (run_timeout_sec > 0),
+	-- Retention of finished runs, overriding the scheduler config. 0 keeps them
+	retention_keep_runs INTEGER CHECK (retention_keep_runs >= 0),
+	retention_max_age_days INTEGER CHECK (retention_max_age_days >= 0),
+
+	created_at TIMESTAMP DEFAULT NOW(),
+	modified_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE TRIGGER last_modified_at
+BEFORE UPDATE ON workflow_setting
+FOR EACH ROW EXECUTE PROCEDURE last_modified_at();
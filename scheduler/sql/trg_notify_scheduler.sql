@@ -0,0 +1,24 @@
+-- Wakes the schedulers up as soon as something they have to act on changes.
+-- Run state changes made by the schedulers themselves are not notified, they already know about them.
+
+DROP TRIGGER IF EXISTS notify_scheduler ON workflow;
+CREATE TRIGGER notify_scheduler
+AFTER INSERT OR UPDATE ON workflow
+FOR EACH ROW EXECUTE PROCEDURE notify_scheduler();
+
+DROP TRIGGER IF EXISTS notify_scheduler ON workflow_setting;
+CREATE TRIGGER notify_scheduler
+AFTER INSERT OR UPDATE ON workflow_setting
+FOR EACH ROW EXECUTE PROCEDURE notify_scheduler();
+
+DROP TRIGGER IF EXISTS notify_scheduler ON workflow_instance;
+CREATE TRIGGER notify_scheduler
+AFTER INSERT ON workflow_instance
+FOR EACH ROW WHEN (NEW.run_type = 'manual')
+EXECUTE PROCEDURE notify_scheduler();
+
+DROP TRIGGER IF EXISTS notify_scheduler_cancel ON workflow_instance;
+CREATE TRIGGER notify_scheduler_cancel
+AFTER UPDATE ON workflow_instance
+FOR EACH ROW WHEN (NEW.run_state = 'cancelling' AND OLD.run_state <> 'cancelling')
+EXECUTE PROCEDURE notify_scheduler();
@@ -1,7 +1,13 @@
 CREATE TYPE runstate AS ENUM (
 	'nothing',
 	'queued',
+	'waiting_upstream',
 	'running',
 	'success',
 	'failed',
+	'cancelling',
+	'cancelled',
+	'skipped',
+	'up_for_retry',
+	'timed_out'
 );
@@ -0,0 +1,4 @@
+SELECT wiid, wid, run_id, run_type, run_state::TEXT AS run_state, run_date, timezone, conf, running_since
+FROM workflow_instance
+WHERE workflow_id = $1 AND run_state IN ('queued', 'waiting_upstream', 'running', 'up_for_retry', 'cancelling')
+ORDER BY run_date, wiid;
\ No newline at end of file
@@ -0,0 +1,223 @@
+use std::fmt;
+
+use chrono::prelude::*;
+use chrono_tz::Tz;
+
+use super::db::{Db, DbError};
+use super::event;
+use super::workflow_instance::SCHEDULED_PREFIX;
+
+/// Why a manual run was not triggered.
+#[derive(Debug)]
+pub enum TriggerError {
+	/// The run_id is taken from the namespace of scheduled runs.
+	ReservedRunId(String),
+	Db(DbError),
+}
+
+impl From<DbError> for TriggerError {
+	fn from(e: DbError) -> TriggerError {
+		TriggerError::Db(e)
+	}
+}
+
+impl fmt::Display for TriggerError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TriggerError::ReservedRunId(run_id) => {
+				write!(f, "Run id '{}' is reserved for scheduled runs, they start with '{}'", run_id, SCHEDULED_PREFIX)
+			},
+			TriggerError::Db(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+/// Stops a workflow from creating new instances. Running instances finish.
+pub async fn pause(sql_client: &Db, workflow_id: &str) -> Result<(), DbError> {
+	sql_client.execute(include_str!("pause_workflow.sql"), &[&workflow_id]).await?;
+	event::record(sql_client, workflow_id, "paused", "Workflow paused").await;
+	Ok(())
+}
+
+/// Resumes scheduling of a paused workflow.
+/// With `catchup` the occurrences missed while paused are scheduled according to the workflow's catchup policy,
+/// otherwise they are skipped.
+pub async fn unpause(
+	sql_client: &Db,
+	workflow_id: &str,
+	catchup: bool
+) -> Result<(), DbError> {
+	let skip_until: Option<DateTime<Utc>> = if catchup { None } else { Some(Utc::now()) };
+	sql_client.execute(include_str!("unpause_workflow.sql"), &[&workflow_id, &skip_until]).await?;
+	let message = if catchup {
+		"Workflow unpaused, catching up on missed occurrences"
+	} else {
+		"Workflow unpaused, skipping missed occurrences"
+	};
+	event::record(sql_client, workflow_id, "unpaused", message).await;
+	Ok(())
+}
+
+/// Disables or enables a workflow. A disabled workflow is retired by the schedulers: active instances finish,
+/// no new ones are created.
+pub async fn set_disabled(
+	sql_client: &Db,
+	workflow_id: &str,
+	disabled: bool
+) -> Result<(), DbError> {
+	sql_client.execute(include_str!("set_workflow_disabled.sql"), &[&workflow_id, &disabled]).await?;
+	if disabled {
+		event::record(sql_client, workflow_id, "disabled", "Workflow disabled").await;
+	} else {
+		event::record(sql_client, workflow_id, "enabled", "Workflow enabled").await;
+	}
+	Ok(())
+}
+
+/// Creates the settings of a newly harvested workflow in the paused state.
+pub async fn pause_new_workflow(sql_client: &Db, workflow_id: &str) -> Result<(), DbError> {
+	sql_client.execute(include_str!("pause_workflow.sql"), &[&workflow_id]).await?;
+	event::record(sql_client, workflow_id, "paused", "New workflow created in paused state").await;
+	Ok(())
+}
+
+/// Triggers a manual run of a workflow, which the scheduler picks up on its next tick.
+/// The run_date defaults to now and the run_id to one derived from it.
+/// Triggering an existing run_id again does not create a second run, which makes triggers idempotent.
+/// Run ids of scheduled runs are rejected, the scheduler would take such a run for its own.
+///
+/// Returns the wiid of the run, or `None` if the workflow does not exist.
+pub async fn trigger(
+	sql_client: &Db,
+	workflow_id: &str,
+	run_id: Option<String>,
+	run_date: Option<DateTime<Utc>>,
+	conf: serde_json::Value
+) -> Result<Option<i32>, TriggerError> {
+	let run_date = run_date.unwrap_or_else(Utc::now);
+	let run_id = manual_run_id(run_id, run_date)?;
+
+	let row = sql_client.query_opt(
+		include_str!("trigger_workflow.sql"), &[&workflow_id, &run_id, &run_date, &conf]
+	).await?;
+	if let Some(row) = row {
+		event::record(sql_client, workflow_id, "triggered", &format!("Triggered run '{}' for {}", run_id, run_date)).await;
+		return Ok(Some(row.get("wiid")));
+	}
+
+	trace!("Run '{}' of '{}' was not created. Looking for an existing one", run_id, workflow_id);
+	let row = sql_client.query_opt(include_str!("find_run.sql"), &[&workflow_id, &run_id]).await?;
+	Ok(row.map(|row| row.get("wiid")))
+}
+
+/// The run_id of a manual run, `run_id` if the caller supplied one.
+fn manual_run_id(run_id: Option<String>, run_date: DateTime<Utc>) -> Result<String, TriggerError> {
+	match run_id {
+		Some(run_id) if run_id.starts_with(SCHEDULED_PREFIX) => Err(TriggerError::ReservedRunId(run_id)),
+		Some(run_id) => Ok(run_id),
+		None => Ok(format!("manual__{}", run_date.to_rfc3339())),
+	}
+}
+
+/// Cancels a run. Runs which were not picked up yet are cancelled right away, active ones are cancelled by their
+/// scheduler: it terminates the in-flight tasks and skips the remaining ones.
+/// Returns the new run_state, or None if there is no such run or it is not active.
+pub async fn cancel(sql_client: &Db, workflow_id: &str, run_id: &str) -> Result<Option<String>, DbError> {
+	let row = sql_client.query_opt(include_str!("cancel_run.sql"), &[&workflow_id, &run_id]).await?;
+	if row.is_some() {
+		event::record(sql_client, workflow_id, "cancel_requested", &format!("Cancel of run '{}' requested", run_id)).await;
+	}
+	Ok(row.map(|row| row.get("run_state")))
+}
+
+/// Lists the versions of a workflow, newest first, with the number of runs created from each.
+pub async fn list_versions(
+	sql_client: &Db,
+	workflow_id: &str
+) -> Result<Vec<(i32, i64)>, DbError> {
+	let rows = sql_client.query(include_str!("workflow_versions.sql"), &[&workflow_id]).await?;
+	Ok(rows.iter().map(|row| (row.get("wid"), row.get("runs"))).collect())
+}
+
+/// Fetches the encoded definition of a workflow version, the latest one if `wid` is `None`.
+/// Returns the wid of the version and its openworkflow message.
+pub async fn fetch_version(
+	sql_client: &Db,
+	workflow_id: &str,
+	wid: Option<i32>
+) -> Result<Option<(i32, Vec<u8>)>, DbError> {
+	let row = match wid {
+		Some(wid) => sql_client.query_opt(include_str!("workflow_version.sql"), &[&workflow_id, &wid]).await?,
+		None => sql_client.query_opt(include_str!("latest_workflow_version.sql"), &[&workflow_id]).await?,
+	};
+	Ok(row.map(|row| (row.get("wid"), row.get("openworkflow_message"))))
+}
+
+/// A past or active run of a workflow, together with the workflow version it used.
+pub struct RunRecord {
+	pub wiid: i32,
+	pub wid: Option<i32>,
+	pub run_id: String,
+	pub run_type: String,
+	pub run_state: String,
+	/// Local to the timezone the run was scheduled in
+	pub run_date: DateTime<Tz>,
+	pub reason: Option<String>,
+}
+
+/// Lists the latest runs of a workflow, newest first.
+pub async fn run_history(
+	sql_client: &Db,
+	workflow_id: &str,
+	limit: i64
+) -> Result<Vec<RunRecord>, DbError> {
+	let rows = sql_client.query(include_str!("run_history.sql"), &[&workflow_id, &limit]).await?;
+	Ok(rows.iter().map(|row| {
+		let run_date: DateTime<Utc> = row.get("run_date");
+		let timezone: &str = row.get("timezone");
+		RunRecord {
+			wiid: row.get("wiid"),
+			wid: row.get("wid"),
+			run_id: row.get("run_id"),
+			run_type: row.get("run_type"),
+			run_state: row.get("run_state"),
+			run_date: run_date.with_timezone(&timezone.parse().unwrap_or(Tz::UTC)),
+			reason: row.get("reason"),
+		}
+	}).collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::config::SchedulerConfig;
+	use super::super::workflow_instance::scheduled_run_id;
+
+	#[test]
+	fn manual_run_ids() {
+		let run_date = Utc.ymd(2020, 6, 1).and_hms(0, 0, 0);
+		assert_eq!(manual_run_id(None, run_date).unwrap(), "manual__2020-06-01T00:00:00+00:00");
+		assert_eq!(manual_run_id(Some("backfill-june".to_string()), run_date).unwrap(), "backfill-june");
+		assert!(matches!(
+			manual_run_id(Some(scheduled_run_id(run_date)), run_date),
+			Err(TriggerError::ReservedRunId(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn scheduled_run_ids_are_rejected_before_touching_the_db() {
+		let config = SchedulerConfig {
+			psql_url: "postgres://postgres@127.0.0.1:1".to_string(),
+			db_max_retries: 0,
+			..Default::default()
+		};
+		let db = Db::new(&config).unwrap().0;
+		let run_id = Some("scheduled__2020-06-01T00:00:00+00:00".to_string());
+		let result = trigger(&db, "wf", run_id, None, serde_json::json!({})).await;
+		assert!(matches!(result, Err(TriggerError::ReservedRunId(_))));
+		// Valid run ids are inserted, which fails without a DB
+		let result = trigger(&db, "wf", Some("backfill-june".to_string()), None, serde_json::json!({})).await;
+		assert!(matches!(result, Err(TriggerError::Db(_))));
+	}
+}
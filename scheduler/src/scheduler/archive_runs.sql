@@ -0,0 +1,10 @@
+SELECT (
+	to_jsonb(wi) || jsonb_build_object('tasks', (
+		SELECT COALESCE(jsonb_agg(to_jsonb(ti) ORDER BY ti.tiid), '[]'::JSONB)
+		FROM task_instance AS ti
+		WHERE ti.wiid = wi.wiid
+	))
+)::TEXT AS line
+FROM workflow_instance AS wi
+WHERE wi.wiid = ANY($1)
+ORDER BY wi.wiid;
@@ -0,0 +1 @@
+SELECT wiid FROM workflow_instance WHERE workflow_id = $1 AND run_state = 'cancelling';
@@ -0,0 +1,8 @@
+-- Runs which were not picked up yet are cancelled right away. Active ones are cancelled by their scheduler.
+UPDATE workflow_instance
+SET
+	run_state = (CASE WHEN run_state = 'nothing' THEN 'cancelled' ELSE 'cancelling' END)::runstate,
+	reason = 'Cancelled on request'
+WHERE workflow_id = $1 AND run_id = $2
+	AND run_state IN ('nothing', 'queued', 'waiting_upstream', 'running', 'up_for_retry')
+RETURNING wiid, run_state::TEXT AS run_state;
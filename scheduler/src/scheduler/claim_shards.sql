@@ -0,0 +1,9 @@
+UPDATE scheduler_shard SET owner = $1, lease_until = NOW() + make_interval(secs => $2)
+WHERE shard IN (
+	SELECT shard FROM scheduler_shard
+	WHERE (owner IS NULL OR lease_until < NOW()) AND shard < $3
+	ORDER BY shard
+	LIMIT $4
+	FOR UPDATE SKIP LOCKED
+)
+RETURNING shard;
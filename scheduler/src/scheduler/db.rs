@@ -0,0 +1,310 @@
+//! Database access of the scheduler, built to survive DB outages.
+//!
+//! Statements run on pooled connections, which are replaced transparently once they broke. Statements failing
+//! because the DB is unreachable are retried with exponential backoff.
+//! State writes of instances and tasks are never lost: while the DB is unreachable they are buffered and replayed
+//! in order, before the scheduler ticks again.
+//!
+//! Session state, i.e. LISTEN and the leader lock, lives on a dedicated connection, which is re-established
+//! when it broke.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use deadpool_postgres::{Manager, Pool};
+use futures::{stream, StreamExt};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{AsyncMessage, Client, NoTls, Notification, Row};
+
+use crate::config::SchedulerConfig;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Idle time of the session connection before TCP keepalives are sent. They notice a DB host which is gone.
+const SESSION_KEEPALIVE_IDLE: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub enum DbError {
+	/// The DB could not be reached. Retrying later may succeed.
+	Unavailable(String),
+	/// The DB rejected the statement.
+	Query(tokio_postgres::Error),
+}
+
+impl DbError {
+	pub fn is_unavailable(&self) -> bool {
+		matches!(self, DbError::Unavailable(_))
+	}
+}
+
+impl From<tokio_postgres::Error> for DbError {
+	fn from(e: tokio_postgres::Error) -> DbError {
+		let io_error = std::error::Error::source(&e).map_or(false, |s| s.is::<std::io::Error>());
+		if e.is_closed() || io_error {
+			DbError::Unavailable(e.to_string())
+		} else {
+			DbError::Query(e)
+		}
+	}
+}
+
+impl fmt::Display for DbError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			DbError::Unavailable(message) => write!(f, "Database unavailable: {}", message),
+			DbError::Query(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl std::error::Error for DbError {}
+
+/// A state change which has to reach the DB eventually.
+#[derive(Debug)]
+pub enum StateWrite {
+	/// `reason` explains the state, e.g. why a run failed. It is cleared by the next state change.
+	RunState { wiid: i32, run_state: &'static str, reason: Option<String> },
+	TaskState { wiid: i32, task_id: String, state: String },
+}
+
+pub struct Db {
+	pool: Pool,
+	pg_config: tokio_postgres::Config,
+	max_retries: u64,
+	backoff_max: Duration,
+	/// Dedicated connection for LISTEN and the leader lock
+	session: Mutex<Option<Client>>,
+	session_timeout: Duration,
+	notification_tx: mpsc::UnboundedSender<Notification>,
+	pending_writes: Mutex<VecDeque<StateWrite>>,
+	unavailable: AtomicBool,
+	/// Set when the DB became reachable again after an outage
+	recovered: AtomicBool,
+}
+
+impl Db {
+	/// Creates the DB layer. Connections are established lazily.
+	/// Returns the notifications received on the session connection alongside.
+	pub fn new(config: &SchedulerConfig) -> Result<(Db, mpsc::UnboundedReceiver<Notification>), DbError> {
+		let pg_config: tokio_postgres::Config = config.psql_url.parse()?;
+		let pool = Pool::new(Manager::new(pg_config.clone(), NoTls), config.db_pool_size as usize);
+		let (notification_tx, notification_rx) = mpsc::unbounded_channel();
+		let db = Db {
+			pool,
+			pg_config,
+			max_retries: config.db_max_retries,
+			backoff_max: Duration::from_millis(config.db_backoff_max_ms),
+			session: Mutex::new(None),
+			session_timeout: Duration::from_secs(config.db_session_timeout_sec),
+			notification_tx,
+			pending_writes: Mutex::new(VecDeque::new()),
+			unavailable: AtomicBool::new(false),
+			recovered: AtomicBool::new(false),
+		};
+		Ok((db, notification_rx))
+	}
+
+	pub async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, DbError> {
+		self.run(self.max_retries, |client| async move { Ok(client.query(sql, params).await?) }).await
+	}
+
+	pub async fn query_one(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Row, DbError> {
+		self.run(self.max_retries, |client| async move { Ok(client.query_one(sql, params).await?) }).await
+	}
+
+	pub async fn query_opt(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>, DbError> {
+		self.run(self.max_retries, |client| async move { Ok(client.query_opt(sql, params).await?) }).await
+	}
+
+	pub async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, DbError> {
+		self.run(self.max_retries, |client| async move { Ok(client.execute(sql, params).await?) }).await
+	}
+
+	/// Runs a statement on a pooled connection, retrying up to `retries` times while the DB is unavailable.
+	async fn run<T, F, Fut>(&self, retries: u64, statement: F) -> Result<T, DbError>
+	where
+		F: Fn(deadpool_postgres::Client) -> Fut,
+		Fut: Future<Output = Result<T, DbError>>,
+	{
+		let mut backoff = INITIAL_BACKOFF;
+		let mut attempt = 0;
+		loop {
+			let result = match self.pool.get().await {
+				Ok(client) => statement(client).await,
+				Err(e) => Err(DbError::Unavailable(e.to_string())),
+			};
+			match result {
+				Ok(value) => {
+					self.mark_available();
+					return Ok(value);
+				},
+				Err(e) if e.is_unavailable() => {
+					self.mark_unavailable(&e);
+					if attempt >= retries {
+						return Err(e);
+					}
+					attempt += 1;
+					trace!("Retrying statement in {:?} ({}/{})", backoff, attempt, retries);
+					time::delay_for(backoff).await;
+					backoff = (backoff * 2).min(self.backoff_max);
+				},
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	fn mark_unavailable(&self, e: &DbError) {
+		if !self.unavailable.swap(true, Ordering::SeqCst) {
+			error!("Lost the database: {}", e);
+		}
+	}
+
+	fn mark_available(&self) {
+		if self.unavailable.swap(false, Ordering::SeqCst) {
+			info!("Database is reachable again");
+			self.recovered.store(true, Ordering::SeqCst);
+		}
+	}
+
+	/// Returns true once after the DB became reachable again. The caller has to reconcile its state with the DB.
+	pub fn take_recovered(&self) -> bool {
+		self.recovered.swap(false, Ordering::SeqCst)
+	}
+
+	/// Persists a state change. If the DB is unavailable, the write is buffered and replayed by `flush`.
+	/// Writes are applied in order, so a buffered write is never overtaken by a later one.
+	/// Returns the state the run is in after a run state write which was applied right away. It differs from the
+	/// written one if a cancel was requested in the meantime. None if the write was buffered or did not apply.
+	pub async fn write_state(&self, write: StateWrite) -> Option<String> {
+		let mut pending = self.pending_writes.lock().await;
+		if !self.replay(&mut pending).await {
+			pending.push_back(write);
+			return None;
+		}
+		match self.apply(&write).await {
+			Ok(run_state) => run_state,
+			Err(e) if e.is_unavailable() => {
+				pending.push_back(write);
+				warn!("Buffering {} state writes until the database is reachable again", pending.len());
+				None
+			},
+			Err(e) => {
+				error!("Dropping state write {:?}:\n{}", write, e);
+				None
+			},
+		}
+	}
+
+	/// Replays buffered state writes. Returns false if the DB is still unavailable and writes are pending.
+	pub async fn flush(&self) -> bool {
+		let mut pending = self.pending_writes.lock().await;
+		self.replay(&mut pending).await
+	}
+
+	async fn replay(&self, pending: &mut VecDeque<StateWrite>) -> bool {
+		while let Some(write) = pending.front() {
+			match self.apply(write).await {
+				Ok(_) => { pending.pop_front(); },
+				Err(e) if e.is_unavailable() => {
+					warn!("Buffering {} state writes until the database is reachable again", pending.len());
+					return false;
+				},
+				Err(e) => {
+					error!("Dropping state write {:?}:\n{}", write, e);
+					pending.pop_front();
+				},
+			}
+		}
+		true
+	}
+
+	/// Applies a single state write. Returns the resulting run state of a run state write which applied.
+	async fn apply(&self, write: &StateWrite) -> Result<Option<String>, DbError> {
+		match write {
+			StateWrite::RunState { wiid, run_state, reason } => {
+				self.run(0, |client| async move {
+					let row = client.query_opt(include_str!("update_run_state.sql"), &[wiid, run_state, reason]).await?;
+					Ok(row.map(|row| row.get("run_state")))
+				}).await
+			},
+			StateWrite::TaskState { wiid, task_id, state } => {
+				self.run(0, |client| async move {
+					client.execute(include_str!("update_task_state.sql"), &[wiid, task_id, state]).await?;
+					Ok(None)
+				}).await
+			},
+		}
+	}
+
+	/// Runs a statement on the session connection, which holds the leader lock and listens for notifications.
+	pub async fn session_query_one(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Row, DbError> {
+		let mut session = self.session.lock().await;
+		if session.as_ref().map_or(true, |client| client.is_closed()) {
+			*session = None;
+			*session = Some(self.connect_session().await?);
+		}
+		match session.as_ref() {
+			Some(client) => Ok(client.query_one(sql, params).await?),
+			None => Err(DbError::Unavailable("No session".into())),
+		}
+	}
+
+	/// Establishes the session connection, unless it is up already.
+	pub async fn ensure_session(&self) -> bool {
+		let mut session = self.session.lock().await;
+		if session.as_ref().map_or(false, |client| !client.is_closed()) {
+			return true;
+		}
+		match self.connect_session().await {
+			Ok(client) => {
+				*session = Some(client);
+				true
+			},
+			Err(e) => {
+				self.mark_unavailable(&e);
+				*session = None;
+				false
+			}
+		}
+	}
+
+	async fn connect_session(&self) -> Result<Client, DbError> {
+		let mut session_config = self.pg_config.clone();
+		session_config.keepalives(true).keepalives_idle(SESSION_KEEPALIVE_IDLE);
+		let (client, mut connection) = session_config.connect(NoTls).await?;
+		// Drives the connection and forwards notifications to the scheduler loop.
+		let notification_tx = self.notification_tx.clone();
+		let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+		tokio::spawn(async move {
+			while let Some(message) = messages.next().await {
+				match message {
+					Ok(AsyncMessage::Notification(notification)) => {
+						let _ = notification_tx.send(notification);
+					},
+					Ok(_) => (),
+					Err(e) => {
+						error!("Session connection error: {}", e);
+						break;
+					},
+				}
+			}
+		});
+		if let Err(e) = client.batch_execute(include_str!("listen.sql")).await {
+			error!("Failed to listen for notifications. Falling back to polling:\n{}", e);
+		}
+		// The DB drops a session which went quiet, e.g. of a hung scheduler, so its leader lock is released.
+		// Older servers do not know every setting, the session works without them.
+		let timeout_ms = self.session_timeout.as_millis();
+		for setting in &["idle_session_timeout", "tcp_user_timeout"] {
+			if let Err(e) = client.batch_execute(&format!("SET {} = {}", setting, timeout_ms)).await {
+				warn!("Failed to set {} of the database session: {}", setting, e);
+			}
+		}
+		info!("Established database session");
+		Ok(client)
+	}
+}
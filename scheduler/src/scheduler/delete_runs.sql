@@ -0,0 +1,5 @@
+-- Task rows are removed by the cascade. Runs which became active again in the meantime are kept.
+DELETE FROM workflow_instance
+WHERE wiid = ANY($1)
+	AND run_state IN ('success', 'failed', 'cancelled', 'skipped', 'timed_out')
+RETURNING wiid;
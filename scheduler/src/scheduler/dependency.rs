@@ -0,0 +1,131 @@
+use chrono::prelude::*;
+use chrono::Duration;
+
+use super::db::Db;
+
+/// Result of checking a dependency for a run_date.
+#[derive(Debug, PartialEq)]
+pub enum DependencyState {
+	Met,
+	Waiting,
+	TimedOut,
+}
+
+/// A workflow, or one of its tasks, waits for another workflow's run to succeed.
+/// Stored in the `workflow_dependency` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+	/// Task which waits. `None` makes the whole workflow instance wait.
+	pub task_id: Option<String>,
+	pub upstream_workflow_id: String,
+	/// Task of the upstream workflow which has to succeed. `None` requires the whole upstream instance to succeed.
+	pub upstream_task_id: Option<String>,
+	/// Added to the run_date to find the upstream run, e.g. -1 day to wait for yesterday's run.
+	/// The upstream run has to be for exactly that date. An upstream workflow on a different schedule, e.g. hourly
+	/// runs for a daily workflow, needs an offset which hits one of its occurrences. An older upstream run never
+	/// satisfies the dependency, otherwise a run due but not created yet would be mistaken for being done.
+	pub run_date_offset: Duration,
+	/// How long to wait before giving up. `None` waits forever.
+	pub timeout: Option<Duration>,
+}
+
+impl Dependency {
+	pub fn from_row(row: &tokio_postgres::Row) -> Dependency {
+		let run_date_offset_sec: Option<i64> = row.get("run_date_offset_sec");
+		let timeout_sec: Option<i64> = row.get("timeout_sec");
+		Dependency {
+			task_id: row.get("task_id"),
+			upstream_workflow_id: row.get("upstream_workflow_id"),
+			upstream_task_id: row.get("upstream_task_id"),
+			run_date_offset: Duration::seconds(run_date_offset_sec.unwrap_or(0)),
+			timeout: timeout_sec.map(Duration::seconds),
+		}
+	}
+
+	/// Checks whether the upstream run for `run_date` succeeded.
+	/// `waiting_since` is when the instance or task started waiting and is compared against the timeout.
+	pub async fn check(
+		&self,
+		sql_client: &Db,
+		run_date: &DateTime<Utc>,
+		waiting_since: &DateTime<Utc>
+	) -> DependencyState {
+		let upstream_run_date = self.upstream_run_date(*run_date);
+		let result = sql_client.query_one(
+			include_str!("upstream_succeeded.sql"),
+			&[&self.upstream_workflow_id, &upstream_run_date, &self.upstream_task_id]
+		).await;
+		match result {
+			Ok(row) if row.get::<_, bool>("succeeded") => return DependencyState::Met,
+			Ok(_) => (),
+			Err(e) => error!("Failed to check upstream workflow '{}':\n{}", self.upstream_workflow_id, e),
+		};
+
+		self.unmet(*waiting_since, Utc::now())
+	}
+
+	/// The run_date of the upstream run the run at `run_date` waits for.
+	pub fn upstream_run_date(&self, run_date: DateTime<Utc>) -> DateTime<Utc> {
+		run_date + self.run_date_offset
+	}
+
+	/// State of the unmet dependency at `now`, waited on since `waiting_since`.
+	fn unmet(&self, waiting_since: DateTime<Utc>, now: DateTime<Utc>) -> DependencyState {
+		match self.timeout {
+			Some(timeout) if now - waiting_since > timeout => DependencyState::TimedOut,
+			_ => DependencyState::Waiting,
+		}
+	}
+
+	pub fn describe(&self) -> String {
+		match &self.upstream_task_id {
+			Some(task_id) => format!("'{}.{}' ({:+}s)", self.upstream_workflow_id, task_id, self.run_date_offset.num_seconds()),
+			None => format!("'{}' ({:+}s)", self.upstream_workflow_id, self.run_date_offset.num_seconds()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn dependency(run_date_offset: Duration, timeout: Option<Duration>) -> Dependency {
+		Dependency {
+			task_id: Some("load".to_string()),
+			upstream_workflow_id: "upstream".to_string(),
+			upstream_task_id: None,
+			run_date_offset,
+			timeout,
+		}
+	}
+
+	#[test]
+	fn upstream_run_date_is_offset() {
+		let run_date = Utc.ymd(2020, 6, 2).and_hms(0, 0, 0);
+
+		assert_eq!(dependency(Duration::zero(), None).upstream_run_date(run_date), run_date);
+		assert_eq!(
+			dependency(Duration::days(-1), None).upstream_run_date(run_date),
+			Utc.ymd(2020, 6, 1).and_hms(0, 0, 0)
+		);
+	}
+
+	#[test]
+	fn unmet_dependencies_time_out_after_waiting() {
+		let waiting_since = Utc.ymd(2020, 6, 1).and_hms(12, 0, 0);
+		let dependency = dependency(Duration::zero(), Some(Duration::hours(1)));
+
+		assert_eq!(dependency.unmet(waiting_since, waiting_since + Duration::minutes(59)), DependencyState::Waiting);
+		assert_eq!(dependency.unmet(waiting_since, waiting_since + Duration::hours(1)), DependencyState::Waiting);
+		assert_eq!(dependency.unmet(waiting_since, waiting_since + Duration::minutes(61)), DependencyState::TimedOut);
+	}
+
+	#[test]
+	fn unmet_dependencies_without_timeout_wait_forever() {
+		let waiting_since = Utc.ymd(2020, 6, 1).and_hms(12, 0, 0);
+
+		let state = dependency(Duration::zero(), None).unmet(waiting_since, waiting_since + Duration::days(365));
+
+		assert_eq!(state, DependencyState::Waiting);
+	}
+}
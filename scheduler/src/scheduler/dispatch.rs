@@ -0,0 +1,303 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use tonic::Request;
+use tonic::metadata::MetadataValue;
+
+use flowty_types::{FlowtyError, NodeIndex, EXECUTION_ID_METADATA};
+use flowty_types::flowty::CancelTaskRequest;
+use flowty_types::flowty::task_control_client::TaskControlClient;
+use flowty_types::openworkflow::execution_broker_client::ExecutionBrokerClient;
+use flowty_types::openworkflow::executor_client::ExecutorClient;
+use flowty_types::openworkflow::{
+	Task,
+	SearchRequest,
+	ExecutorDefinition,
+	ExecutorKind,
+	ExecutionStatus
+};
+
+/// Status update of a task. The attempt tells updates of a retried task apart from the ones of earlier attempts.
+pub type TaskStatus = (NodeIndex, u32, ExecutionStatus);
+
+/// How long the executor has to terminate a cancelled task, before the scheduler hangs up on it.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Handle of a task executing in the background.
+pub struct TaskHandle {
+	cancel: Option<oneshot::Sender<()>>,
+	abort: AbortHandle,
+}
+
+impl TaskHandle {
+	/// Asks the executor to terminate the task. Its stream ends once the task is gone, which reports it as failed.
+	pub fn cancel(&mut self) {
+		if let Some(cancel) = self.cancel.take() {
+			let _ = cancel.send(());
+		}
+	}
+
+	/// Hangs up on the executor without waiting for it to terminate the task. The task is reported as failed.
+	pub fn abort(&self) {
+		self.abort.abort();
+	}
+}
+
+/// One of the scheduler-wide task slots. The slot is released when dropped.
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Sends tasks to executors on behalf of all workflow instances of a scheduler.
+pub struct Dispatcher {
+	broker_uri: String,
+	/// Maximum number of tasks running at the same time. 0 means unlimited
+	max_active_tasks: usize,
+	/// Number of tasks currently running
+	running: Arc<AtomicUsize>,
+	/// Signalled whenever a task finished, so the scheduler loop can pick up its downstream tasks right away.
+	task_done: mpsc::UnboundedSender<()>,
+}
+
+impl Dispatcher {
+	pub fn new(broker_uri: String, max_active_tasks: usize, task_done: mpsc::UnboundedSender<()>) -> Dispatcher {
+		Dispatcher {
+			broker_uri,
+			max_active_tasks,
+			running: Arc::new(AtomicUsize::new(0)),
+			task_done,
+		}
+	}
+
+	/// Claims a task slot. Returns None if the scheduler-wide limit of running tasks is reached.
+	/// Workflows tick concurrently, so the slot is claimed atomically.
+	pub fn acquire_slot(&self) -> Option<Slot> {
+		let mut running = self.running.load(Ordering::SeqCst);
+		loop {
+			if self.max_active_tasks > 0 && running >= self.max_active_tasks {
+				return None;
+			}
+			match self.running.compare_exchange(running, running + 1, Ordering::SeqCst, Ordering::SeqCst) {
+				Ok(_) => return Some(Slot(Arc::clone(&self.running))),
+				Err(actual) => running = actual,
+			}
+		}
+	}
+
+	/// Asks the ExecutionBroker for a fitting executor.
+	/// Returns the URI to the executor
+	pub async fn find_executor(&self, definition: &ExecutorDefinition) -> Result<String, FlowtyError> {
+		match ExecutionBrokerClient::connect(self.broker_uri.clone()).await {
+			Ok(mut client) => {
+				match client.find_executor(Request::new(SearchRequest {
+					executor_definition: Some(ExecutorDefinition {
+						kind: ExecutorKind::Local.into(),
+						specs: definition.specs.clone(),
+					})
+				})).await {
+					Ok(response) => Ok(response.into_inner().uri),
+					Err(_) => Err(FlowtyError::ExecutorNotFound)
+				}
+			},
+			_ => Err(FlowtyError::ExecutionBrokerUnreachable)
+		}
+	}
+
+	/// Executes the task in the background. Status updates are reported on `status_tx`.
+	/// `execution_id` has to be unique across all schedulers, the executor cancels the task by it.
+	/// The slot is held until the task finished.
+	pub fn spawn(
+		&self,
+		slot: Slot,
+		executor_uri: String,
+		task: Task,
+		node: NodeIndex,
+		attempt: u32,
+		execution_id: String,
+		status_tx: mpsc::UnboundedSender<TaskStatus>
+	) -> TaskHandle {
+		let task_done = self.task_done.clone();
+		let (abort_handle, abort_registration) = AbortHandle::new_pair();
+		let (cancel_tx, cancel_rx) = oneshot::channel();
+		tokio::spawn(async move {
+			let aborted_tx = status_tx.clone();
+			let execution = Abortable::new(
+				execute_task(executor_uri, task, node, attempt, execution_id, cancel_rx, status_tx),
+				abort_registration
+			);
+			if execution.await.is_err() {
+				let _ = aborted_tx.send((node, attempt, ExecutionStatus::Failed));
+			}
+			drop(slot);
+			let _ = task_done.send(());
+		});
+		TaskHandle { cancel: Some(cancel_tx), abort: abort_handle }
+	}
+}
+
+/// Resolves once the task was cancelled.
+async fn cancelled(cancel: &mut Option<oneshot::Receiver<()>>) {
+	if let Some(receiver) = cancel {
+		let requested = receiver.await.is_ok();
+		*cancel = None;
+		if requested {
+			return;
+		}
+	}
+	futures::future::pending::<()>().await;
+}
+
+/// Resolves at `deadline`, never without one.
+async fn expired(deadline: Option<time::Instant>) {
+	match deadline {
+		Some(deadline) => time::delay_until(deadline).await,
+		None => futures::future::pending::<()>().await,
+	}
+}
+
+/// Asks the executor at `executor_uri` to terminate the execution `execution_id`.
+async fn request_cancel(executor_uri: String, task_id: &str, execution_id: String) {
+	let result = match TaskControlClient::connect(executor_uri).await {
+		Ok(mut control) => control.cancel_task(Request::new(CancelTaskRequest { execution_id })).await.map(|_| ()),
+		Err(e) => {
+			warn!("Failed to connect to the executor to cancel task '{}': {}", task_id, e);
+			return;
+		}
+	};
+	if let Err(e) = result {
+		warn!("Executor did not accept the cancellation of task '{}': {}", task_id, e);
+	}
+}
+
+/// Sends the task to the executor and reports every status update of the output stream.
+/// A stream ending without a final status counts as failed.
+/// Once cancelled, the executor is asked to terminate the task and its stream is read until it ended, at most
+/// `CANCEL_TIMEOUT` long.
+async fn execute_task(
+	executor_uri: String,
+	task: Task,
+	node: NodeIndex,
+	attempt: u32,
+	execution_id: String,
+	cancel: oneshot::Receiver<()>,
+	status_tx: mpsc::UnboundedSender<TaskStatus>
+) {
+	let mut status = ExecutionStatus::Failed;
+	let task_id = task.task_id.clone();
+	let mut request = Request::new(task);
+	let cancellable = match MetadataValue::from_str(&execution_id) {
+		Ok(value) => {
+			request.metadata_mut().insert(EXECUTION_ID_METADATA, value);
+			true
+		},
+		Err(_) => {
+			warn!("Execution id '{}' of task '{}' is not valid metadata. It can not be cancelled", execution_id, task_id);
+			false
+		}
+	};
+	match ExecutorClient::connect(executor_uri.clone()).await {
+		Ok(mut executor) => {
+			match executor.execute_task(request).await {
+				Ok(response) => {
+					let mut stream = response.into_inner();
+					let mut cancel = Some(cancel);
+					let mut cancel_deadline = None;
+					loop {
+						let message = tokio::select! {
+							message = stream.message() => message,
+							_ = cancelled(&mut cancel) => {
+								info!("Cancelling task '{}'", task_id);
+								if cancellable {
+									request_cancel(executor_uri.clone(), &task_id, execution_id.clone()).await;
+								}
+								cancel_deadline = Some(time::Instant::now() + CANCEL_TIMEOUT);
+								continue;
+							},
+							_ = expired(cancel_deadline) => {
+								warn!("Task '{}' was not terminated within {}s. Hanging up", task_id, CANCEL_TIMEOUT.as_secs());
+								status = ExecutionStatus::Failed;
+								break;
+							},
+						};
+						match message {
+							Ok(Some(output)) => {
+								trace!("{}", output.message);
+								status = ExecutionStatus::from_i32(output.status).unwrap_or(status);
+								let _ = status_tx.send((node, attempt, status));
+							},
+							Ok(None) => break,
+							Err(e) => {
+								error!("Executor stream failed: {}", e);
+								status = ExecutionStatus::Failed;
+								break;
+							}
+						}
+					}
+				},
+				Err(e) => error!("Executor rejected task: {}", e),
+			}
+		},
+		Err(e) => error!("Failed to connect to executor: {}", e),
+	};
+	if !matches!(status, ExecutionStatus::Success | ExecutionStatus::Failed) {
+		status = ExecutionStatus::Failed;
+	}
+	let _ = status_tx.send((node, attempt, status));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn dispatcher(max_active_tasks: usize) -> Dispatcher {
+		let (task_done, _) = mpsc::unbounded_channel();
+		Dispatcher::new("http://127.0.0.1:1".to_string(), max_active_tasks, task_done)
+	}
+
+	#[test]
+	fn slots_are_limited() {
+		let dispatcher = dispatcher(2);
+		let first = dispatcher.acquire_slot();
+		let second = dispatcher.acquire_slot();
+		assert!(first.is_some() && second.is_some());
+		assert!(dispatcher.acquire_slot().is_none());
+
+		drop(first);
+		let third = dispatcher.acquire_slot();
+		assert!(third.is_some());
+		assert!(dispatcher.acquire_slot().is_none());
+		drop((second, third));
+		assert_eq!(dispatcher.running.load(Ordering::SeqCst), 0);
+	}
+
+	#[test]
+	fn zero_means_unlimited() {
+		let dispatcher = dispatcher(0);
+		let slots: Vec<Slot> = (0..100).filter_map(|_| dispatcher.acquire_slot()).collect();
+		assert_eq!(slots.len(), 100);
+		drop(slots);
+		assert_eq!(dispatcher.running.load(Ordering::SeqCst), 0);
+	}
+
+	#[test]
+	fn concurrent_claims_respect_the_limit() {
+		let dispatcher = Arc::new(dispatcher(5));
+		let claims: Vec<_> = (0..8)
+			.map(|_| {
+				let dispatcher = Arc::clone(&dispatcher);
+				std::thread::spawn(move || (0..10).filter_map(|_| dispatcher.acquire_slot()).collect::<Vec<Slot>>())
+			})
+			.collect();
+		let slots: Vec<Slot> = claims.into_iter().flat_map(|claim| claim.join().unwrap()).collect();
+		assert_eq!(slots.len(), 5);
+		assert_eq!(dispatcher.running.load(Ordering::SeqCst), 5);
+	}
+}
@@ -0,0 +1,11 @@
+use super::db::Db;
+
+/// Records an event of a workflow in the `workflow_event` table, so operators can follow what the scheduler did.
+/// Failing to record an event is logged, but does not interrupt scheduling.
+pub async fn record(sql_client: &Db, workflow_id: &str, event: &str, message: &str) {
+	info!("Workflow '{}' {}: {}", workflow_id, event, message);
+	let result = sql_client.execute(include_str!("record_event.sql"), &[&workflow_id, &event, &message]).await;
+	if let Err(e) = result {
+		error!("Failed to record event '{}' of workflow '{}':\n{}", event, workflow_id, e);
+	}
+}
@@ -0,0 +1,5 @@
+WITH expired AS (
+	DELETE FROM scheduler_member WHERE heartbeat_at < NOW() - make_interval(secs => $1) RETURNING member_id
+)
+UPDATE scheduler_shard SET owner = NULL, lease_until = NULL
+WHERE owner IN (SELECT member_id FROM expired) OR lease_until < NOW();
@@ -0,0 +1,39 @@
+-- Finished runs of the workflows $1 which exceed the retention policy of their workflow, oldest first.
+-- $2 and $3 are the defaults for workflows without an own policy. 0 disables either limit.
+-- The newest scheduled run of a workflow is always kept, as scheduling resumes after it.
+-- The facts the policy decided on are returned, the scheduler checks them again before removing a run.
+WITH ranked AS (
+	SELECT
+		wi.wiid,
+		wi.run_state,
+		wi.created_at,
+		wi.run_type,
+		ROW_NUMBER() OVER (PARTITION BY wi.workflow_id ORDER BY wi.run_date DESC, wi.wiid DESC) AS position,
+		ROW_NUMBER() OVER (
+			PARTITION BY wi.workflow_id, wi.run_type ORDER BY wi.run_date DESC, wi.wiid DESC
+		) AS type_position,
+		COALESCE(ws.retention_keep_runs::BIGINT, $2) AS keep_runs,
+		COALESCE(ws.retention_max_age_days::BIGINT, $3) AS max_age_days
+	FROM workflow_instance AS wi
+	LEFT JOIN workflow_setting AS ws ON ws.workflow_id = wi.workflow_id
+	WHERE wi.workflow_id = ANY($1)
+)
+SELECT
+	wiid,
+	run_type,
+	run_state::TEXT AS run_state,
+	created_at,
+	position,
+	type_position,
+	keep_runs,
+	max_age_days,
+	NOW() AS now
+FROM ranked
+WHERE NOT (run_type = 'scheduled' AND type_position = 1)
+	AND run_state IN ('success', 'failed', 'cancelled', 'skipped', 'timed_out')
+	AND (
+		(keep_runs > 0 AND position > keep_runs)
+		OR (max_age_days > 0 AND created_at < NOW() - max_age_days * INTERVAL '1 day')
+	)
+ORDER BY wiid
+LIMIT $4;
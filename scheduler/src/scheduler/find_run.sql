@@ -0,0 +1 @@
+SELECT wiid FROM workflow_instance WHERE workflow_id = $1 AND run_id = $2;
\ No newline at end of file
@@ -0,0 +1,4 @@
+SELECT workflow_id, task_id, upstream_workflow_id, upstream_task_id, run_date_offset_sec, timeout_sec
+FROM workflow_dependency
+WHERE workflow_id = ANY($1)
+ORDER BY wdid;
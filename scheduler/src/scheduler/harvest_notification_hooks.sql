@@ -0,0 +1,4 @@
+SELECT workflow_id, task_id, events, sink, target, subject, template
+FROM notification_hook
+WHERE workflow_id = ANY($1)
+ORDER BY nhid;
@@ -0,0 +1,3 @@
+SELECT workflow_id, task_id, kind, target, poke_interval_sec, timeout_sec, soft_fail
+FROM task_sensor
+WHERE workflow_id = ANY($1);
@@ -0,0 +1,3 @@
+SELECT workflow_id, task_id, deadline_offset_sec, max_duration_sec
+FROM sla
+WHERE workflow_id = ANY($1);
@@ -0,0 +1 @@
+SELECT NOW() AS now, COALESCE(MAX(wid), 0) AS max_wid FROM workflow;
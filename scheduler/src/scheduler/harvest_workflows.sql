@@ -1,5 +1,55 @@
+-- Workflows which changed since the watermark: a new version after wid $4, or settings, calendars, dependencies,
+-- sensors, notification hooks or SLAs modified after $3. Both lag behind the last harvest by the overlap, to catch
+-- changes committed late. Without a watermark, i.e. $3 being NULL, all are returned.
 WITH latest AS (
 	SELECT MAX(wid) AS wid, workflow_id FROM workflow GROUP BY workflow_id
 )
-SELECT workflow.workflow_id, workflow.openworkflow_message
-FROM workflow JOIN latest ON workflow.wid = latest.wid;
\ No newline at end of file
+SELECT
+	workflow.workflow_id,
+	-- Versions the scheduler already holds are not transferred again
+	CASE WHEN workflow.wid = ANY($2) THEN NULL ELSE workflow.openworkflow_message END AS openworkflow_message,
+	workflow.wid,
+	COALESCE(workflow_setting.is_disabled, FALSE) AS is_disabled,
+	workflow_setting.start_date,
+	workflow_setting.end_date,
+	workflow_setting.catchup,
+	workflow_setting.overlap_policy,
+	workflow_setting.timezone,
+	workflow_setting.is_paused,
+	workflow_setting.skip_until,
+	workflow_setting.max_active_tasks,
+	workflow_setting.task_priorities,
+	workflow_setting.run_timeout_sec,
+	workflow_setting.workflow_id IS NOT NULL AS has_setting,
+	(hashtext(workflow.workflow_id) & 2147483647) % $1 AS shard,
+	ARRAY(
+		SELECT calendar_date.excluded_date
+		FROM calendar_date
+		WHERE calendar_date.calendar_id = ANY(workflow_setting.exclusion_calendars)
+	) AS excluded_dates
+FROM workflow
+JOIN latest ON workflow.wid = latest.wid
+LEFT JOIN workflow_setting ON workflow.workflow_id = workflow_setting.workflow_id
+WHERE $3::TIMESTAMPTZ IS NULL
+	OR workflow.wid > $4
+	OR workflow_setting.modified_at > $3
+	OR EXISTS (
+		SELECT 1 FROM calendar_date
+		WHERE calendar_date.calendar_id = ANY(workflow_setting.exclusion_calendars) AND calendar_date.created_at > $3
+	)
+	OR EXISTS (
+		SELECT 1 FROM workflow_dependency
+		WHERE workflow_dependency.workflow_id = workflow.workflow_id AND workflow_dependency.modified_at > $3
+	)
+	OR EXISTS (
+		SELECT 1 FROM task_sensor
+		WHERE task_sensor.workflow_id = workflow.workflow_id AND task_sensor.modified_at > $3
+	)
+	OR EXISTS (
+		SELECT 1 FROM notification_hook
+		WHERE notification_hook.workflow_id = workflow.workflow_id AND notification_hook.modified_at > $3
+	)
+	OR EXISTS (
+		SELECT 1 FROM sla
+		WHERE sla.workflow_id = workflow.workflow_id AND sla.modified_at > $3
+	);
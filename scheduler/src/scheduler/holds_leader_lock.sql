@@ -0,0 +1,7 @@
+SELECT EXISTS (
+	SELECT 1 FROM pg_locks
+	WHERE locktype = 'advisory'
+	AND pid = pg_backend_pid()
+	AND granted
+	AND ((classid::BIGINT << 32) | objid::BIGINT) = $1
+) AS held;
@@ -0,0 +1 @@
+INSERT INTO scheduler_shard (shard) SELECT generate_series(0, $1 - 1) ON CONFLICT (shard) DO NOTHING;
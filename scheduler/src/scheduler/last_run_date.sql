@@ -0,0 +1,2 @@
+-- Manual runs may have any run_date, only scheduled ones tell where the schedule stands
+SELECT MAX(run_date) AS run_date FROM workflow_instance WHERE workflow_id = $1 AND run_type = 'scheduled';
\ No newline at end of file
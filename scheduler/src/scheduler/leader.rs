@@ -0,0 +1,34 @@
+//! Leader election between multiple schedulers, based on a Postgres session-level advisory lock.
+//!
+//! The lock is held by the scheduler's DB session connection. If the leader dies, Postgres drops its connection
+//! and releases the lock, so a standby takes over within its poll interval.
+//! A leader whose session broke loses the lock as well and has to acquire it again. The session uses TCP keepalives
+//! and the DB ends it once it went quiet for `db_session_timeout_sec`, so a leader which hangs, or whose host
+//! vanished without closing the connection, releases the lock too. The leader checks the lock right before ticking.
+
+use super::db::Db;
+
+/// Key of the advisory lock. Spells "flowty".
+const LEADER_LOCK_KEY: i64 = 0x0066_6c6f_7774_79;
+
+/// Tries to become the leader without blocking.
+pub async fn try_acquire(sql_client: &Db) -> bool {
+	match sql_client.session_query_one("SELECT pg_try_advisory_lock($1) AS acquired", &[&LEADER_LOCK_KEY]).await {
+		Ok(row) => row.get("acquired"),
+		Err(e) => {
+			error!("Failed to acquire leader lock:\n{}", e);
+			false
+		}
+	}
+}
+
+/// Checks that this session still holds the leader lock.
+pub async fn holds_lock(sql_client: &Db) -> bool {
+	match sql_client.session_query_one(include_str!("holds_leader_lock.sql"), &[&LEADER_LOCK_KEY]).await {
+		Ok(row) => row.get("held"),
+		Err(e) => {
+			error!("Failed to check leader lock:\n{}", e);
+			false
+		}
+	}
+}
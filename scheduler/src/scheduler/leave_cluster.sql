@@ -0,0 +1,5 @@
+WITH released AS (
+	UPDATE scheduler_shard SET owner = NULL, lease_until = NULL
+	WHERE owner = $1
+)
+DELETE FROM scheduler_member WHERE member_id = $1;
@@ -0,0 +1 @@
+LISTEN flowty_scheduler;
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT COUNT(*) AS members FROM scheduler_member;
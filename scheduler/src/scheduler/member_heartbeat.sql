@@ -0,0 +1,2 @@
+INSERT INTO scheduler_member (member_id, heartbeat_at) VALUES ($1, NOW())
+ON CONFLICT (member_id) DO UPDATE SET heartbeat_at = NOW();
@@ -2,27 +2,99 @@ extern crate log;
 
 use log::{info, trace, warn};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::time::{Duration, Instant};
 
+use tokio::sync::{mpsc, watch};
 use tokio::time;
 
 use chrono::prelude::*;
+use futures::{stream, StreamExt};
+use tokio_postgres::Notification;
 
-use crate::utils;
+use crate::config::SchedulerConfig;
+use db::{Db, DbError};
 use flowty_types;
 
+pub mod admin;
+pub mod db;
+mod dependency;
+mod dispatch;
+mod event;
+mod leader;
+mod notification;
+mod retention;
+mod schedule;
+mod sensor;
+mod settings;
+mod shard;
+mod sla;
+mod template;
 mod workflow;
 mod workflow_instance;
 
+/// How a scheduler coordinates with other schedulers on the same DB.
+enum Membership {
+	/// A single leader schedules all workflows, the others stand by.
+	Leader { is_leader: bool },
+	/// Every member schedules the workflows of the shards it owns.
+	Sharded(shard::ShardMember),
+}
+
+/// Transactions still running when a harvest starts may commit changes stamped before it, or versions with a wid
+/// below the newest one visible. Changes this much older than the last harvest are fetched again, to not miss them.
+const HARVEST_OVERLAP_SEC: i64 = 30;
+
+/// Changes up to this point have been harvested.
+#[derive(Debug, Clone, Copy)]
+struct HarvestWatermark {
+	/// Settings, dependencies, sensors, notification hooks and SLAs modified after this are harvested again.
+	modified_at: DateTime<Utc>,
+	/// Versions newer than this are harvested. It is the newest wid seen at least `HARVEST_OVERLAP_SEC` before the
+	/// last harvest, see `wid_watermark`.
+	wid: i32,
+}
+
+/// Records `max_wid` seen at `now` and returns the newest wid seen at least `HARVEST_OVERLAP_SEC` earlier.
+/// Wids are drawn from a sequence when a version is inserted, not when it is committed, so a version with a lower wid
+/// may still show up after a higher one was seen. It is committed within the overlap though, like any other change.
+/// Without such an old observation, 0 is returned and all versions are considered.
+fn wid_watermark(observations: &mut VecDeque<(DateTime<Utc>, i32)>, now: DateTime<Utc>, max_wid: i32) -> i32 {
+	let cutoff = now - chrono::Duration::seconds(HARVEST_OVERLAP_SEC);
+	observations.push_back((now, max_wid));
+	// Only the newest observation before the cutoff is needed
+	while observations.get(1).map_or(false, |(seen_at, _)| *seen_at <= cutoff) {
+		observations.pop_front();
+	}
+	match observations.front() {
+		Some((seen_at, wid)) if *seen_at <= cutoff => *wid,
+		_ => 0,
+	}
+}
+
 pub struct Scheduler {
+	config: SchedulerConfig,
+	dispatcher: dispatch::Dispatcher,
+	/// Signalled by the dispatcher whenever a task finished
+	task_done: mpsc::UnboundedReceiver<()>,
+	notifier: notification::Notifier,
 	workflow_bundle: HashMap<String, workflow::Workflow>,
+	/// Shard of every harvested workflow
+	workflow_shards: HashMap<String, i32>,
+	/// Versions whose definition was rejected. They are not transferred again, only newer ones are.
+	rejected_wids: HashSet<i32>,
+	harvest_watermark: Option<HarvestWatermark>,
+	/// The newest wid seen by recent harvests and when, oldest first
+	wid_observations: VecDeque<(DateTime<Utc>, i32)>,
+	/// When all workflows were harvested last. `None` harvests all of them on the next loop.
+	last_full_harvest: Option<Instant>,
+	membership: Membership,
+	/// The workflows this scheduler schedules, published to the retention job
+	owned_workflows: watch::Sender<Vec<String>>,
+	owned_workflows_rx: watch::Receiver<Vec<String>>,
 }
 
-fn calc_loop_pause(loop_start: Instant) -> u64 {
-	let loop_interval_sec: u64 = utils::get_env("LOOP_INTERVAL_SEC", "30".into())
-								.parse()
-								.unwrap_or_else(|_| 30);
+fn calc_loop_pause(loop_start: Instant, loop_interval_sec: u64) -> u64 {
 	let elapsed_sec: u64 = loop_start.elapsed().as_secs();
 
 	trace!(
@@ -41,70 +113,594 @@ fn calc_loop_pause(loop_start: Instant) -> u64 {
 }
 
 impl Scheduler {
-	pub fn new() -> Scheduler {
-		Scheduler{workflow_bundle: HashMap::new()}
+	pub fn new(config: SchedulerConfig) -> Scheduler {
+		let membership = match config.cluster_mode.as_str() {
+			"sharded" => {
+				let member_id = format!(
+					"{}-{}-{}",
+					std::env::var("HOSTNAME").unwrap_or_else(|_| "scheduler".into()),
+					std::process::id(),
+					Utc::now().timestamp()
+				);
+				info!("Joining the scheduler cluster as '{}' with {} shards", member_id, config.shard_count);
+				Membership::Sharded(shard::ShardMember::new(member_id, config.shard_count, config.shard_lease_sec))
+			},
+			_ => Membership::Leader { is_leader: false },
+		};
+		let (task_done_tx, task_done) = mpsc::unbounded_channel();
+		let (owned_workflows, owned_workflows_rx) = watch::channel(Vec::new());
+		Scheduler{
+			dispatcher: dispatch::Dispatcher::new(
+				config.execution_broker_uri.clone(),
+				config.max_active_tasks as usize,
+				task_done_tx
+			),
+			task_done,
+			notifier: notification::Notifier::new(&config),
+			config,
+			workflow_bundle: HashMap::new(),
+			workflow_shards: HashMap::new(),
+			rejected_wids: HashSet::new(),
+			harvest_watermark: None,
+			wid_observations: VecDeque::new(),
+			last_full_harvest: None,
+			membership,
+			owned_workflows,
+			owned_workflows_rx,
+		}
+	}
+
+	/// Creates the job which removes expired runs of the workflows this scheduler schedules.
+	/// It is meant to run next to `run`.
+	pub fn retention(&self) -> retention::Retention {
+		retention::Retention::new(&self.config, self.owned_workflows_rx.clone())
 	}
 
-	pub async fn run(&mut self, client: &tokio_postgres::Client) {
+	/// Runs the scheduler loop until `shutdown` turns true.
+	/// A tick in progress is always completed, so no instance is left half created.
+	///
+	/// Between ticks the loop sleeps until whichever comes first: the next schedule occurrence of any workflow,
+	/// a finished task, a `NOTIFY` on the `flowty_scheduler` channel, or the loop interval as a safety net.
+	pub async fn run(
+		&mut self,
+		client: &Db,
+		mut notifications: mpsc::UnboundedReceiver<Notification>,
+		mut shutdown: watch::Receiver<bool>
+	) {
 		info!("Starting scheduler loop");
-		loop {
+		while !*shutdown.borrow() {
 			let now = Instant::now();
 
-			self.harvest_workflows(client).await;
-			self.process_workflows(client).await;
+			let active = self.prepare_tick(client).await
+				&& self.harvest_workflows(client).await
+				&& self.still_leading(client).await;
+			let pause = if active {
+				self.process_workflows(client).await;
+				self.calc_pause(now)
+			} else {
+				Duration::from_secs(self.config.standby_interval_sec)
+			};
+			self.publish_owned_workflows(active);
 
-			time::delay_for(Duration::from_secs(calc_loop_pause(now))).await;
-			break;  // Remove
+			trace!("Sleeping for up to {:?}", pause);
+			tokio::select! {
+				_ = time::delay_for(pause) => (),
+				Some(notification) = notifications.recv(), if active => {
+					trace!("Woken up by notification '{}'", notification.payload());
+				},
+				Some(_) = self.task_done.recv(), if active => trace!("Woken up by a finished task"),
+				_ = shutdown_requested(&mut shutdown) => (),
+			}
+			// Everything that happened in the meantime is handled by the next tick.
+			while notifications.try_recv().is_ok() {}
+			while self.task_done.try_recv().is_ok() {}
 		}
+		self.shutdown(client).await;
 	}
 
-	async fn harvest_workflows(&mut self, client: &tokio_postgres::Client) {
-		let result = client.query(include_str!("harvest_workflows.sql"), &[]).await;
+	/// Brings the DB in line with memory and coordinates with the other schedulers.
+	/// Returns false if this scheduler must not tick, because the DB is unavailable or it stands by.
+	async fn prepare_tick(&mut self, client: &Db) -> bool {
+		if !client.flush().await {
+			warn!("Database unavailable. Not ticking until buffered state writes are persisted");
+			return false;
+		}
+		if client.take_recovered() {
+			info!("Reconciling in-memory state with the database");
+			for workflow in self.workflow_bundle.values_mut() {
+				workflow.reconcile();
+			}
+			self.last_full_harvest = None;
+		}
+		client.ensure_session().await;
+		self.coordinate(client).await
+	}
 
-		match result {
-			Ok(rows) => {
-				for row in rows {
-					let workflow_id: &str = row.get(0);
-					let openworkflow: Option<&[u8]> = row.get(1);
+	/// Checks the leader lock again right before ticking, so a leader which lost its session while harvesting does
+	/// not dispatch tasks next to the new leader.
+	async fn still_leading(&mut self, client: &Db) -> bool {
+		if !matches!(self.membership, Membership::Leader { is_leader: true }) || leader::holds_lock(client).await {
+			return true;
+		}
+		warn!("Lost leadership before ticking. Going back to standby");
+		self.membership = Membership::Leader { is_leader: false };
+		self.workflow_bundle.clear();
+		self.last_full_harvest = None;
+		false
+	}
 
-					info!("Parsing workflow with workflow_id '{}' from db", workflow_id);
-					if let Some(w) = openworkflow {
-						match flowty_types::openworkflow_from_binary(w) {
-							Ok(w) => {
-								if self.workflow_bundle.contains_key(workflow_id) {
-									trace!("Old version of workflow already known. Replacing");
-									if let Some(workflow) = self.workflow_bundle.get_mut(workflow_id) {
-										workflow.update_workflow(w, false);
-									}
-								} else {
-									trace!("Brand new workflow received");
-									self.workflow_bundle.insert(
-										workflow_id.to_string(),
-										workflow::Workflow::new(w)
-									);
-								}
-							},
-							Err(e) => {
-								error!("Failed to parse workflow '{}':\n{}", workflow_id, e);
-							}
+	/// Tells the retention job which workflows to clean up. None while standing by.
+	fn publish_owned_workflows(&self, active: bool) {
+		let mut owned: Vec<String> = if active { self.workflow_bundle.keys().cloned().collect() } else { Vec::new() };
+		owned.sort();
+		if *self.owned_workflows_rx.borrow() != owned {
+			let _ = self.owned_workflows.broadcast(owned);
+		}
+	}
+
+	/// Time until the next tick: the next schedule occurrence of any workflow, at most the loop interval.
+	fn calc_pause(&self, loop_start: Instant) -> Duration {
+		let pause = Duration::from_secs(calc_loop_pause(loop_start, self.config.loop_interval_sec));
+		let now = Utc::now();
+		match self.workflow_bundle.values().filter_map(|w| w.next_run_date(now)).min() {
+			Some(next_run_date) => {
+				trace!("Next schedule occurrence at {}", next_run_date);
+				pause.min((next_run_date - now).to_std().unwrap_or_else(|_| Duration::from_secs(0)))
+			},
+			None => pause,
+		}
+	}
+
+	/// Stops scheduling and persists the in-memory state, so another scheduler can take over.
+	async fn shutdown(&mut self, client: &Db) {
+		info!("Shutting down scheduler. No longer creating instances");
+		for workflow in self.workflow_bundle.values_mut() {
+			workflow.shutdown(client).await;
+		}
+		self.workflow_bundle.clear();
+		if !client.flush().await {
+			error!("Database unavailable. Buffered state writes are lost, the next scheduler runs the affected tasks again");
+		}
+		match &mut self.membership {
+			// The leader lock is released together with the DB connection.
+			Membership::Leader { .. } => (),
+			Membership::Sharded(member) => {
+				if let Err(e) = member.leave(client).await {
+					error!("Failed to leave the scheduler cluster:\n{}", e);
+				}
+			},
+		}
+		info!("Scheduler stopped");
+	}
+
+	/// Coordinates with the other schedulers. Returns false if this scheduler should stand by.
+	async fn coordinate(&mut self, client: &Db) -> bool {
+		match &mut self.membership {
+			Membership::Leader { is_leader } => {
+				let was_leader = *is_leader;
+				*is_leader = ensure_leadership(client, was_leader).await;
+				if *is_leader != was_leader {
+					// Whenever leadership changes, the in-memory state is rebuilt from the DB on the next ticks.
+					self.workflow_bundle.clear();
+					self.last_full_harvest = None;
+				}
+				*is_leader
+			},
+			Membership::Sharded(member) => {
+				let owned = member.owned();
+				if let Err(e) = member.rebalance(client).await {
+					error!("Failed to rebalance shards:\n{}", e);
+				}
+				// Workflows of newly acquired shards did not necessarily change since the last harvest
+				if member.owned() != owned {
+					self.last_full_harvest = None;
+				}
+				// Another member may own the shards we lost already, so their workflows are dropped right away.
+				// This hangs up on their in-flight tasks, the new owner runs them again.
+				let workflow_shards = &self.workflow_shards;
+				self.workflow_bundle.retain(|workflow_id, _| {
+					workflow_shards
+						.get(workflow_id)
+						.map_or(false, |shard| member.owns(*shard) || member.is_draining(*shard))
+				});
+
+				// Draining shards are handed back once their workflows have no tasks in flight anymore.
+				let mut drained = Vec::new();
+				for shard in member.draining() {
+					let mut workflows: Vec<&mut workflow::Workflow> = self.workflow_bundle
+						.iter_mut()
+						.filter(|(workflow_id, _)| workflow_shards.get(*workflow_id) == Some(&shard))
+						.map(|(_, workflow)| workflow)
+						.collect();
+					for workflow in workflows.iter_mut() {
+						workflow.drain();
+					}
+					if workflows.iter().all(|workflow| workflow.running_tasks() == 0) {
+						for workflow in workflows {
+							workflow.shutdown(client).await;
 						}
-					} else {
-						warn!("No binary data received for workflow_id '{}' from db", workflow_id);
+						drained.push(shard);
+					}
+				}
+				// The new owner must find the final task states in the DB
+				if !drained.is_empty() && client.flush().await {
+					self.workflow_bundle.retain(|workflow_id, _| {
+						workflow_shards.get(workflow_id).map_or(false, |shard| !drained.contains(shard))
+					});
+					if let Err(e) = member.release(client, &drained).await {
+						error!("Failed to release shards:\n{}", e);
 					}
 				}
+				true
 			},
+		}
+	}
+
+	fn owns_shard(&self, shard: i32) -> bool {
+		match &self.membership {
+			Membership::Leader { .. } => true,
+			Membership::Sharded(member) => member.owns(shard),
+		}
+	}
+
+	fn is_draining_shard(&self, shard: i32) -> bool {
+		match &self.membership {
+			Membership::Leader { .. } => false,
+			Membership::Sharded(member) => member.is_draining(shard),
+		}
+	}
+
+	fn shard_count(&self) -> i32 {
+		match &self.membership {
+			Membership::Leader { .. } => 1,
+			Membership::Sharded(member) => member.get_shard_count(),
+		}
+	}
+
+	/// Retrieves the dependencies of the workflows `workflow_ids`, grouped by workflow_id.
+	async fn harvest_dependencies(
+		&self,
+		client: &Db,
+		workflow_ids: &[String]
+	) -> Result<HashMap<String, Vec<dependency::Dependency>>, DbError> {
+		let mut dependencies: HashMap<String, Vec<dependency::Dependency>> = HashMap::new();
+		for row in client.query(include_str!("harvest_dependencies.sql"), &[&workflow_ids]).await? {
+			dependencies
+				.entry(row.get("workflow_id"))
+				.or_insert_with(Vec::new)
+				.push(dependency::Dependency::from_row(&row));
+		}
+		Ok(dependencies)
+	}
+
+	/// Retrieves the sensors of the workflows `workflow_ids`, grouped by workflow_id.
+	async fn harvest_sensors(
+		&self,
+		client: &Db,
+		workflow_ids: &[String]
+	) -> Result<HashMap<String, Vec<sensor::Sensor>>, DbError> {
+		let mut sensors: HashMap<String, Vec<sensor::Sensor>> = HashMap::new();
+		for row in client.query(include_str!("harvest_sensors.sql"), &[&workflow_ids]).await? {
+			if let Some(s) = sensor::Sensor::from_row(&row) {
+				sensors.entry(row.get("workflow_id")).or_insert_with(Vec::new).push(s);
+			}
+		}
+		Ok(sensors)
+	}
+
+	/// Retrieves the notification hooks of the workflows `workflow_ids`, grouped by workflow_id.
+	async fn harvest_notification_hooks(
+		&self,
+		client: &Db,
+		workflow_ids: &[String]
+	) -> Result<HashMap<String, Vec<notification::NotificationHook>>, DbError> {
+		let mut hooks: HashMap<String, Vec<notification::NotificationHook>> = HashMap::new();
+		for row in client.query(include_str!("harvest_notification_hooks.sql"), &[&workflow_ids]).await? {
+			if let Some(hook) = notification::NotificationHook::from_row(&row) {
+				hooks.entry(row.get("workflow_id")).or_insert_with(Vec::new).push(hook);
+			}
+		}
+		Ok(hooks)
+	}
+
+	/// Retrieves the SLAs of the workflows `workflow_ids`, grouped by workflow_id.
+	async fn harvest_slas(&self, client: &Db, workflow_ids: &[String]) -> Result<HashMap<String, Vec<sla::Sla>>, DbError> {
+		let mut slas: HashMap<String, Vec<sla::Sla>> = HashMap::new();
+		for row in client.query(include_str!("harvest_slas.sql"), &[&workflow_ids]).await? {
+			if let Some(s) = sla::Sla::from_row(&row) {
+				slas.entry(row.get("workflow_id")).or_insert_with(Vec::new).push(s);
+			}
+		}
+		Ok(slas)
+	}
+
+	/// Brings the workflow bundle in line with the DB.
+	/// Only workflows which changed since the last harvest are fetched, see `HarvestWatermark`. Deletions leave no
+	/// trace to compare a watermark with, so every `full_harvest_interval_sec` all workflows are fetched.
+	/// Only definitions the scheduler does not hold yet are transferred and decoded, identified by their wid.
+	/// Deleted or disabled workflows are retired.
+	/// Returns false if the workflows could not be retrieved. The bundle is left untouched then.
+	async fn harvest_workflows(&mut self, client: &Db) -> bool {
+		let full_harvest_interval = Duration::from_secs(self.config.full_harvest_interval_sec);
+		let watermark = match self.last_full_harvest {
+			Some(last_full_harvest) if last_full_harvest.elapsed() < full_harvest_interval => self.harvest_watermark,
+			_ => None,
+		};
+		let next_watermark = match client.query_one(include_str!("harvest_watermark.sql"), &[]).await {
+			Ok(row) => {
+				let now: DateTime<Utc> = row.get("now");
+				HarvestWatermark {
+					modified_at: now - chrono::Duration::seconds(HARVEST_OVERLAP_SEC),
+					wid: wid_watermark(&mut self.wid_observations, now, row.get("max_wid")),
+				}
+			},
+			Err(e) => {
+				error!("Failed to retrieve the harvest watermark from postgres:\n{}", e);
+				return false;
+			}
+		};
+		let known_wids: Vec<i32> = self.workflow_bundle
+			.values()
+			.map(|w| w.get_wid())
+			.chain(self.rejected_wids.iter().cloned())
+			.collect();
+		let modified_since = watermark.map(|w| w.modified_at);
+		let after_wid = watermark.map_or(0, |w| w.wid);
+		let result = client.query(
+			include_str!("harvest_workflows.sql"),
+			&[&self.shard_count(), &known_wids, &modified_since, &after_wid]
+		).await;
+
+		let rows = match result {
+			Ok(rows) => rows,
 			Err(e) => {
 				error!("Failed to retrieve workflows from postgres:\n{}", e);
-				panic!("Failed to retrieve workflows from postgres:\n{}", e);
+				return false;
+			}
+		};
+		match watermark {
+			Some(_) => trace!("Harvested {} changed workflows", rows.len()),
+			None => trace!("Harvested all {} workflows", rows.len()),
+		};
+		let workflow_ids: Vec<String> = rows.iter().map(|row| row.get("workflow_id")).collect();
+		let (mut dependencies, mut sensors, mut hooks, mut slas) = match (
+			self.harvest_dependencies(client, &workflow_ids).await,
+			self.harvest_sensors(client, &workflow_ids).await,
+			self.harvest_notification_hooks(client, &workflow_ids).await,
+			self.harvest_slas(client, &workflow_ids).await,
+		) {
+			(Ok(dependencies), Ok(sensors), Ok(hooks), Ok(slas)) => (dependencies, sensors, hooks, slas),
+			(Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+				error!("Failed to retrieve workflow dependencies, sensors, notification hooks and SLAs from postgres:\n{}", e);
+				return false;
+			}
+		};
+		let mut harvested: HashSet<String> = HashSet::with_capacity(rows.len());
+		for row in rows {
+			let workflow_id: &str = row.get("workflow_id");
+			let wid: i32 = row.get("wid");
+			let openworkflow: Option<&[u8]> = row.get("openworkflow_message");
+			let shard: i32 = row.get("shard");
+			self.workflow_shards.insert(workflow_id.to_string(), shard);
+			if self.is_draining_shard(shard) {
+				// Keeps its workflows from being retired while they drain
+				harvested.insert(workflow_id.to_string());
+				continue;
+			}
+			if !self.owns_shard(shard) {
+				trace!("Workflow '{}' belongs to shard {}, which is owned by another scheduler", workflow_id, shard);
+				continue;
+			}
+			let is_disabled: bool = row.get("is_disabled");
+			if is_disabled {
+				trace!("Workflow '{}' is disabled", workflow_id);
+				if let Some(workflow) = self.workflow_bundle.get_mut(workflow_id) {
+					workflow.retire();
+				}
+				continue;
+			}
+			harvested.insert(workflow_id.to_string());
+			let mut settings = settings::WorkflowSettings::from_row(&row);
+			settings.dependencies = dependencies.remove(workflow_id).unwrap_or_default();
+			settings.sensors = sensors.remove(workflow_id).unwrap_or_default();
+			settings.notification_hooks = hooks.remove(workflow_id).unwrap_or_default();
+			settings.slas = slas.remove(workflow_id).unwrap_or_default();
+			let has_setting: bool = row.get("has_setting");
+			if !has_setting && self.config.pause_new_workflows {
+				match admin::pause_new_workflow(client, workflow_id).await {
+					Ok(_) => settings.is_paused = true,
+					Err(e) => error!("Failed to pause new workflow '{}':\n{}", workflow_id, e),
+				}
+			}
+
+			let openworkflow = match openworkflow {
+				Some(w) => {
+					info!("Parsing workflow with workflow_id '{}' from db", workflow_id);
+					match flowty_types::openworkflow_from_binary(w) {
+						Ok(w) => Some(w),
+						Err(e) => {
+							error!("Failed to parse workflow '{}':\n{}", workflow_id, e);
+							continue;
+						}
+					}
+				},
+				None => None,
+			};
+			match (self.workflow_bundle.get_mut(workflow_id), openworkflow) {
+				(Some(workflow), Some(w)) => {
+					trace!("Old version of workflow already known. Replacing");
+					if let Err(e) = workflow.update_workflow(client, wid, w, false).await {
+						error!("Rejected version {} of workflow '{}':\n{}", wid, workflow_id, e);
+						self.rejected_wids.insert(wid);
+					}
+					workflow.reinstate();
+					workflow.update_settings(settings);
+				},
+				(Some(workflow), None) => {
+					workflow.reinstate();
+					workflow.update_settings(settings);
+				},
+				(None, Some(w)) => {
+					trace!("Brand new workflow received");
+					match workflow::Workflow::new(wid, w, settings.clone()) {
+						Ok(workflow) => {
+							self.workflow_bundle.insert(workflow_id.to_string(), workflow);
+						},
+						Err(e) => {
+							error!("Rejected version {} of workflow '{}':\n{}", wid, workflow_id, e);
+							self.rejected_wids.insert(wid);
+							if let Some(workflow) = self.load_valid_version(client, workflow_id, wid, settings).await {
+								self.workflow_bundle.insert(workflow_id.to_string(), workflow);
+							}
+						}
+					}
+				},
+				(None, None) if self.rejected_wids.contains(&wid) => {
+					trace!("Workflow '{}' has no valid version", workflow_id);
+				},
+				(None, None) => warn!("No binary data received for workflow_id '{}' from db", workflow_id),
+			}
+		}
+
+		// Only a full harvest tells which workflows were deleted
+		if watermark.is_none() {
+			for (workflow_id, workflow) in self.workflow_bundle.iter_mut() {
+				if !harvested.contains(workflow_id) {
+					workflow.retire();
+				}
 			}
+			self.last_full_harvest = Some(Instant::now());
 		}
+		self.harvest_watermark = Some(next_watermark);
+		true
 	}
 
-	async fn process_workflows(&mut self, client: &tokio_postgres::Client) {
+	/// Creates the workflow from the newest version before the rejected version `wid` which is valid, e.g. when a
+	/// scheduler starts after an invalid version was deployed. Runs pinned to that version can finish and new ones
+	/// are created from it, as they were before the rejected version was deployed.
+	async fn load_valid_version(
+		&mut self,
+		client: &Db,
+		workflow_id: &str,
+		wid: i32,
+		settings: settings::WorkflowSettings
+	) -> Option<workflow::Workflow> {
+		let mut before = wid;
+		loop {
+			let row = match client.query_opt(include_str!("previous_workflow_version.sql"), &[&workflow_id, &before]).await {
+				Ok(Some(row)) => row,
+				Ok(None) => return None,
+				Err(e) => {
+					error!("Failed to fetch previous versions of workflow '{}':\n{}", workflow_id, e);
+					return None;
+				}
+			};
+			before = row.get("wid");
+			let result = flowty_types::openworkflow_from_binary(row.get("openworkflow_message"))
+				.map_err(|e| e.to_string())
+				.and_then(|w| workflow::Workflow::new(before, w, settings.clone()).map_err(|e| e.to_string()));
+			match result {
+				Ok(workflow) => {
+					warn!("Scheduling version {} of workflow '{}' until a valid newer version is deployed", before, workflow_id);
+					return Some(workflow);
+				},
+				Err(e) => {
+					trace!("Version {} of workflow '{}' is invalid as well: {}", before, workflow_id, e);
+					self.rejected_wids.insert(before);
+				}
+			}
+		}
+	}
+
+	/// Ticks all workflows concurrently, at most `tick_parallelism` at a time.
+	/// A workflow is ticked once per loop, so its own ticks stay ordered.
+	async fn process_workflows(&mut self, client: &Db) {
 		let now = Utc::now();
-		for (workflow_id, workflow) in self.workflow_bundle.iter_mut() {
-			info!("Processing workflow: '{}'", workflow_id);
-			workflow.tick(client, now).await;
+		let dispatcher = &self.dispatcher;
+		let notifier = &self.notifier;
+		let loop_interval = Duration::from_secs(self.config.loop_interval_sec);
+		stream::iter(self.workflow_bundle.iter_mut())
+			.for_each_concurrent(self.config.tick_parallelism as usize, |(workflow_id, workflow)| async move {
+				info!("Processing workflow: '{}'", workflow_id);
+				let tick_start = Instant::now();
+				workflow.tick(client, dispatcher, notifier, now).await;
+				let elapsed = tick_start.elapsed();
+				if elapsed > loop_interval {
+					warn!(
+						"Tick of workflow '{}' took {:?}, longer than the loop interval of {:?}",
+						workflow_id,
+						elapsed,
+						loop_interval
+					);
+					event::record(
+						client,
+						workflow_id,
+						"tick_overrun",
+						&format!("Tick took {}ms, loop interval is {}ms", elapsed.as_millis(), loop_interval.as_millis())
+					).await;
+				} else {
+					trace!("Tick of workflow '{}' took {:?}", workflow_id, elapsed);
+				}
+			})
+			.await;
+		self.workflow_bundle.retain(|workflow_id, workflow| {
+			if workflow.is_retired() && !workflow.has_active_instances() {
+				info!("Dropping retired workflow '{}'", workflow_id);
+				return false;
+			}
+			true
+		});
+	}
+}
+
+/// Resolves once a shutdown was requested.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+	while let Some(requested) = shutdown.recv().await {
+		if requested {
+			return;
+		}
+	}
+	// The sender is gone, nobody can request a shutdown anymore.
+	futures::future::pending::<()>().await;
+}
+
+/// Only the leader drives the scheduler loop. Standbys try to take over the leader lock.
+/// Returns whether this scheduler is the leader.
+async fn ensure_leadership(client: &Db, is_leader: bool) -> bool {
+	if is_leader {
+		if leader::holds_lock(client).await {
+			return true;
 		}
+		warn!("Lost leadership. Going back to standby");
+		return false;
+	}
+
+	if leader::try_acquire(client).await {
+		info!("Became leader. Rebuilding state from the DB");
+		return true;
+	}
+	trace!("Another scheduler is leading. Standing by");
+	false
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn wid_watermark_lags_by_the_overlap() {
+		let start = Utc.ymd(2020, 1, 1).and_hms(0, 0, 0);
+		let at = |sec: i64| start + chrono::Duration::seconds(sec);
+		let mut observations = VecDeque::new();
+
+		// Nothing was seen long enough ago yet
+		assert_eq!(wid_watermark(&mut observations, at(0), 10), 0);
+		assert_eq!(wid_watermark(&mut observations, at(20), 12), 0);
+		// Versions up to 10 were seen 30 seconds ago, a lower one than 12 may still be committed
+		assert_eq!(wid_watermark(&mut observations, at(30), 15), 10);
+		assert_eq!(wid_watermark(&mut observations, at(55), 15), 12);
+		assert_eq!(wid_watermark(&mut observations, at(100), 20), 15);
+		// Observations older than the one in use are dropped
+		assert_eq!(observations.len(), 2);
 	}
 }
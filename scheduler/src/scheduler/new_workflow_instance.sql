@@ -1 +1,5 @@
-INSERT INTO workflow_instance (workflow_id, run_date) VALUES ($1, $2) RETURNING wiid;
\ No newline at end of file
+INSERT INTO workflow_instance (workflow_id, wid, run_id, run_type, run_state, run_date, timezone, conf)
+VALUES ($1, $2, $3, $4, 'queued', $5, $6, $7)
+-- A retried insert, whose first attempt was committed before the connection broke, returns the existing row.
+ON CONFLICT (workflow_id, run_id) DO UPDATE SET run_id = EXCLUDED.run_id
+RETURNING wiid, run_state::TEXT AS run_state;
\ No newline at end of file
@@ -0,0 +1,638 @@
+//! Notifications about runs and tasks, sent to the hooks configured in the `notification_hook` table.
+//!
+//! Hooks without a task_id are notified about the events of the workflow's runs, hooks with a task_id about the
+//! events of that task.
+//! Sending never blocks the scheduler loop: notifications are handed to a background worker, which delivers each
+//! of them concurrently and retries failed deliveries with exponential backoff.
+//!
+//! The target, subject and template of a hook support these placeholders:
+//! - `{{ workflow_id }}`, `{{ run_id }}`, `{{ task_id }}`
+//! - `{{ event }}`: `failure`, `retry`, `success` or `sla_miss`
+//! - `{{ run_date }}`: RFC 3339 formatted, in the timezone of the workflow
+//! - `{{ message }}`: what happened, e.g. why a run failed
+//!
+//! Values rendered into a webhook body are JSON escaped, so the template only has to supply the quotes. Values
+//! rendered into a webhook URL are percent-encoded. Rendered email recipients must not contain line breaks or
+//! angle brackets.
+//! The command of a `command` hook is run as is. Values like the run_id are chosen by whoever triggers a run, so
+//! they are only passed as `FLOWTY_*` environment variables, never substituted into the shell command.
+
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use hyper::{Body, Client, Request};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::config::SchedulerConfig;
+use super::template;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+const DEFAULT_WEBHOOK_TEMPLATE: &str = r#"{"event": "{{ event }}", "workflow_id": "{{ workflow_id }}", "run_id": "{{ run_id }}", "task_id": "{{ task_id }}", "run_date": "{{ run_date }}", "message": "{{ message }}"}"#;
+const DEFAULT_EMAIL_SUBJECT: &str = "[flowty] {{ event }} of {{ workflow_id }} {{ run_id }} {{ task_id }}";
+const DEFAULT_EMAIL_TEMPLATE: &str = "Workflow: {{ workflow_id }}\nRun: {{ run_id }}\nTask: {{ task_id }}\nRun date: {{ run_date }}\n\n{{ message }}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationEvent {
+	Failure,
+	Retry,
+	Success,
+	SlaMiss,
+}
+
+impl NotificationEvent {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			NotificationEvent::Failure => "failure",
+			NotificationEvent::Retry => "retry",
+			NotificationEvent::Success => "success",
+			NotificationEvent::SlaMiss => "sla_miss",
+		}
+	}
+
+	pub fn from_str(event: &str) -> Option<NotificationEvent> {
+		match event {
+			"failure" => Some(NotificationEvent::Failure),
+			"retry" => Some(NotificationEvent::Retry),
+			"success" => Some(NotificationEvent::Success),
+			"sla_miss" => Some(NotificationEvent::SlaMiss),
+			_ => None,
+		}
+	}
+}
+
+/// Where a notification is sent to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+	/// POSTs the rendered template as JSON. Only plain http is supported.
+	Webhook { url: String, template: String },
+	/// Sends a plain text email to comma separated recipients through the configured SMTP server.
+	Email { to: String, subject: String, template: String },
+	/// Runs a shell command on the scheduler host. The notification is passed as `FLOWTY_*` environment variables,
+	/// the command itself is not rendered.
+	Command { command: String },
+}
+
+/// Stored in the `notification_hook` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotificationHook {
+	pub task_id: Option<String>,
+	pub events: Vec<NotificationEvent>,
+	pub sink: Sink,
+}
+
+impl NotificationHook {
+	pub fn from_row(row: &tokio_postgres::Row) -> Option<NotificationHook> {
+		let events: Vec<String> = row.get("events");
+		let events = events
+			.iter()
+			.filter_map(|e| {
+				let event = NotificationEvent::from_str(e);
+				if event.is_none() {
+					warn!("Unknown notification event '{}'", e);
+				}
+				event
+			})
+			.collect();
+		let target: String = row.get("target");
+		let subject: Option<String> = row.get("subject");
+		let template: Option<String> = row.get("template");
+		let sink: &str = row.get("sink");
+		let sink = match sink {
+			"webhook" => Sink::Webhook {
+				url: target,
+				template: template.unwrap_or_else(|| DEFAULT_WEBHOOK_TEMPLATE.to_string()),
+			},
+			"email" => Sink::Email {
+				to: target,
+				subject: subject.unwrap_or_else(|| DEFAULT_EMAIL_SUBJECT.to_string()),
+				template: template.unwrap_or_else(|| DEFAULT_EMAIL_TEMPLATE.to_string()),
+			},
+			"command" => Sink::Command { command: target },
+			s => {
+				warn!("Unknown notification sink '{}'", s);
+				return None;
+			}
+		};
+		Some(NotificationHook { task_id: row.get("task_id"), events, sink })
+	}
+
+	fn matches(&self, notification: &Notification) -> bool {
+		self.task_id == notification.task_id && self.events.contains(&notification.event)
+	}
+}
+
+/// Something that happened to a run or to one of its tasks.
+#[derive(Debug, Clone)]
+pub struct Notification {
+	pub event: NotificationEvent,
+	pub workflow_id: String,
+	pub run_id: String,
+	/// Local to the timezone of the workflow, so people reading the notification see the date they scheduled
+	pub run_date: DateTime<Tz>,
+	/// Set if the notification is about a single task
+	pub task_id: Option<String>,
+	pub message: String,
+}
+
+impl Notification {
+	fn lookup(&self, key: &str) -> Option<String> {
+		match key {
+			"event" => Some(self.event.as_str().to_string()),
+			"workflow_id" => Some(self.workflow_id.clone()),
+			"run_id" => Some(self.run_id.clone()),
+			"task_id" => Some(self.task_id.clone().unwrap_or_default()),
+			"run_date" => Some(self.run_date.to_rfc3339()),
+			"message" => Some(self.message.clone()),
+			_ => None,
+		}
+	}
+
+	fn render(&self, template: &str) -> String {
+		template::render(template, |key| self.lookup(key))
+	}
+
+	/// Renders a URL template. Values are percent-encoded, so they cannot leave the URL component they are in.
+	fn render_url(&self, template: &str) -> String {
+		template::render(template, |key| self.lookup(key).map(|value| percent_encode(&value)))
+	}
+
+	/// Renders a JSON template. Values are escaped as JSON string contents.
+	fn render_json(&self, template: &str) -> String {
+		template::render(template, |key| self.lookup(key).map(|value| {
+			let quoted = serde_json::Value::String(value).to_string();
+			quoted[1..quoted.len() - 1].to_string()
+		}))
+	}
+}
+
+struct Delivery {
+	sink: Sink,
+	notification: Notification,
+}
+
+struct NotifierSettings {
+	max_retries: u64,
+	/// Delay before the first retry. It doubles with every further one.
+	initial_backoff: Duration,
+	smtp_addr: String,
+	smtp_from: String,
+}
+
+/// Hands notifications to the background worker which delivers them.
+pub struct Notifier {
+	deliveries: mpsc::UnboundedSender<Delivery>,
+}
+
+impl Notifier {
+	/// Spawns the delivery worker. Has to be called within the runtime.
+	pub fn new(config: &SchedulerConfig) -> Notifier {
+		let settings = Arc::new(NotifierSettings {
+			max_retries: config.notify_max_retries,
+			initial_backoff: INITIAL_BACKOFF,
+			smtp_addr: config.smtp_addr.clone(),
+			smtp_from: config.smtp_from.clone(),
+		});
+		let (deliveries, mut rx) = mpsc::unbounded_channel::<Delivery>();
+		tokio::spawn(async move {
+			while let Some(delivery) = rx.recv().await {
+				tokio::spawn(deliver(delivery, Arc::clone(&settings)));
+			}
+		});
+		Notifier { deliveries }
+	}
+
+	/// Sends the notification to every hook subscribed to it. Returns right away.
+	pub fn notify(&self, hooks: &[NotificationHook], notification: &Notification) {
+		for hook in hooks.iter().filter(|h| h.matches(notification)) {
+			let delivery = Delivery { sink: hook.sink.clone(), notification: notification.clone() };
+			if self.deliveries.send(delivery).is_err() {
+				error!("Notification worker is gone. Dropping {:?}", notification);
+			}
+		}
+	}
+}
+
+async fn deliver(delivery: Delivery, settings: Arc<NotifierSettings>) {
+	let notification = &delivery.notification;
+	let mut backoff = settings.initial_backoff;
+	for attempt in 0..=settings.max_retries {
+		let result = match time::timeout(SEND_TIMEOUT, send(&delivery, &settings)).await {
+			Ok(result) => result,
+			Err(_) => Err(format!("Timed out after {:?}", SEND_TIMEOUT)),
+		};
+		match result {
+			Ok(()) => {
+				trace!("Sent {} notification of '{}' to {:?}", notification.event.as_str(), notification.run_id, delivery.sink);
+				return;
+			},
+			Err(e) if attempt < settings.max_retries => {
+				warn!("Failed to send notification to {:?}: {}. Retrying in {:?}", delivery.sink, e, backoff);
+				time::delay_for(backoff).await;
+				backoff *= 2;
+			},
+			Err(e) => error!(
+				"Giving up on {} notification of '{}' to {:?}: {}",
+				notification.event.as_str(),
+				notification.run_id,
+				delivery.sink,
+				e
+			),
+		}
+	}
+}
+
+async fn send(delivery: &Delivery, settings: &NotifierSettings) -> Result<(), String> {
+	let notification = &delivery.notification;
+	match &delivery.sink {
+		Sink::Webhook { url, template } => {
+			let request = Request::post(notification.render_url(url))
+				.header("content-type", "application/json")
+				.body(Body::from(notification.render_json(template)))
+				.map_err(|e| e.to_string())?;
+			let response = Client::new().request(request).await.map_err(|e| e.to_string())?;
+			if response.status().is_success() {
+				Ok(())
+			} else {
+				Err(format!("Webhook responded with {}", response.status()))
+			}
+		},
+		Sink::Email { to, subject, template } => {
+			send_email(
+				settings,
+				&notification.render(to),
+				&notification.render(subject),
+				&notification.render(template)
+			).await
+		},
+		Sink::Command { command } => {
+			let status = Command::new("sh")
+				.arg("-c")
+				.arg(command)
+				.env("FLOWTY_EVENT", notification.event.as_str())
+				.env("FLOWTY_WORKFLOW_ID", &notification.workflow_id)
+				.env("FLOWTY_RUN_ID", &notification.run_id)
+				.env("FLOWTY_TASK_ID", notification.task_id.as_deref().unwrap_or(""))
+				.env("FLOWTY_RUN_DATE", notification.run_date.to_rfc3339())
+				.env("FLOWTY_MESSAGE", &notification.message)
+				.stdin(Stdio::null())
+				.kill_on_drop(true)
+				.status()
+				.await
+				.map_err(|e| e.to_string())?;
+			if status.success() {
+				Ok(())
+			} else {
+				Err(format!("Command exited with {}", status))
+			}
+		},
+	}
+}
+
+/// Sends a plain text email with a minimal SMTP dialogue. Neither TLS nor authentication are supported,
+/// so `smtp_addr` is expected to be a local relay.
+async fn send_email(settings: &NotifierSettings, to: &str, subject: &str, body: &str) -> Result<(), String> {
+	let recipients: Vec<&str> = to.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()).collect();
+	if recipients.is_empty() {
+		return Err("No recipients".into());
+	}
+	// They end up in SMTP commands and headers, where they could inject further ones
+	if let Some(recipient) = recipients.iter().find(|r| r.contains(|c| matches!(c, '\r' | '\n' | '<' | '>'))) {
+		return Err(format!("Invalid recipient '{}'", recipient.escape_debug()));
+	}
+	let stream = TcpStream::connect(&settings.smtp_addr).await.map_err(|e| e.to_string())?;
+	let (reader, mut writer) = tokio::io::split(stream);
+	let mut reader = BufReader::new(reader);
+
+	smtp_reply(&mut reader, "220").await?;
+	smtp_command(&mut writer, &mut reader, "HELO flowty\r\n", "250").await?;
+	smtp_command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>\r\n", settings.smtp_from), "250").await?;
+	for recipient in &recipients {
+		smtp_command(&mut writer, &mut reader, &format!("RCPT TO:<{}>\r\n", recipient), "250").await?;
+	}
+	smtp_command(&mut writer, &mut reader, "DATA\r\n", "354").await?;
+
+	let mut message = format!(
+		"From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+		settings.smtp_from,
+		recipients.join(", "),
+		subject.replace(|c| c == '\r' || c == '\n', " "),
+		Utc::now().to_rfc2822()
+	);
+	for line in body.lines() {
+		// Dot stuffing, so a line consisting of a single dot does not end the message early
+		if line.starts_with('.') {
+			message.push('.');
+		}
+		message.push_str(line);
+		message.push_str("\r\n");
+	}
+	message.push_str(".\r\n");
+	smtp_command(&mut writer, &mut reader, &message, "250").await?;
+	let _ = smtp_command(&mut writer, &mut reader, "QUIT\r\n", "221").await;
+	Ok(())
+}
+
+/// Encodes all but the unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+	let mut encoded = String::with_capacity(value.len());
+	for byte in value.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+			_ => encoded.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+	encoded
+}
+
+async fn smtp_command<W, R>(writer: &mut W, reader: &mut R, command: &str, expected: &str) -> Result<(), String>
+where
+	W: AsyncWrite + Unpin,
+	R: AsyncBufRead + Unpin,
+{
+	writer.write_all(command.as_bytes()).await.map_err(|e| e.to_string())?;
+	smtp_reply(reader, expected).await
+}
+
+/// Reads a possibly multi-line reply and checks its code.
+async fn smtp_reply<R: AsyncBufRead + Unpin>(reader: &mut R, expected: &str) -> Result<(), String> {
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+			return Err("SMTP server closed the connection".into());
+		}
+		if !line.starts_with(expected) {
+			return Err(format!("Unexpected SMTP reply: {}", line.trim_end()));
+		}
+		// `250-` continues a multi-line reply, `250 ` ends it
+		if line.as_bytes().get(3) != Some(&b'-') {
+			return Ok(());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::Instant;
+
+	use hyper::service::{make_service_fn, service_fn};
+	use hyper::{Response, Server, StatusCode};
+	use tokio::net::TcpListener;
+
+	fn notification(task_id: Option<&str>, event: NotificationEvent) -> Notification {
+		Notification {
+			event,
+			workflow_id: "wf".to_string(),
+			run_id: "manual__1".to_string(),
+			run_date: Tz::UTC.ymd(2020, 6, 1).and_hms(0, 0, 0),
+			task_id: task_id.map(String::from),
+			message: "Task failed:\n\"exit 1\"".to_string(),
+		}
+	}
+
+	fn settings(max_retries: u64, smtp_addr: String) -> Arc<NotifierSettings> {
+		Arc::new(NotifierSettings {
+			max_retries,
+			initial_backoff: Duration::from_millis(20),
+			smtp_addr,
+			smtp_from: "flowty@localhost".to_string(),
+		})
+	}
+
+	/// Serves HTTP on a local port. Answers with the statuses in `statuses` in turn and 200 once they ran out.
+	/// Returns the URL, the number of requests received and the bodies received.
+	fn webhook_server(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>, mpsc::UnboundedReceiver<String>) {
+		let requests = Arc::new(AtomicUsize::new(0));
+		let (body_tx, body_rx) = mpsc::unbounded_channel();
+		let statuses = Arc::new(statuses);
+		let counter = Arc::clone(&requests);
+		let make_service = make_service_fn(move |_| {
+			let counter = Arc::clone(&counter);
+			let statuses = Arc::clone(&statuses);
+			let body_tx = body_tx.clone();
+			async move {
+				Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+					let attempt = counter.fetch_add(1, Ordering::SeqCst);
+					let status = statuses.get(attempt).copied().unwrap_or(200);
+					let body_tx = body_tx.clone();
+					async move {
+						let body = hyper::body::to_bytes(request.into_body()).await?;
+						let _ = body_tx.send(String::from_utf8_lossy(&body).to_string());
+						let response = Response::builder()
+							.status(StatusCode::from_u16(status).unwrap())
+							.body(Body::empty())
+							.unwrap();
+						Ok::<_, hyper::Error>(response)
+					}
+				}))
+			}
+		});
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+		let url = format!("http://{}/hook", server.local_addr());
+		tokio::spawn(server);
+		(url, requests, body_rx)
+	}
+
+	fn webhook(url: &str, template: &str) -> Delivery {
+		Delivery {
+			sink: Sink::Webhook { url: url.to_string(), template: template.to_string() },
+			notification: notification(Some("load"), NotificationEvent::Failure),
+		}
+	}
+
+	#[test]
+	fn hooks_match_event_and_task() {
+		let run_hook = NotificationHook {
+			task_id: None,
+			events: vec![NotificationEvent::Failure, NotificationEvent::SlaMiss],
+			sink: Sink::Command { command: "true".to_string() },
+		};
+		let task_hook = NotificationHook { task_id: Some("load".to_string()), ..run_hook.clone() };
+
+		assert!(run_hook.matches(&notification(None, NotificationEvent::Failure)));
+		assert!(run_hook.matches(&notification(None, NotificationEvent::SlaMiss)));
+		assert!(!run_hook.matches(&notification(None, NotificationEvent::Success)));
+		assert!(!run_hook.matches(&notification(Some("load"), NotificationEvent::Failure)));
+
+		assert!(task_hook.matches(&notification(Some("load"), NotificationEvent::Failure)));
+		assert!(!task_hook.matches(&notification(Some("extract"), NotificationEvent::Failure)));
+		assert!(!task_hook.matches(&notification(None, NotificationEvent::Failure)));
+		assert!(!task_hook.matches(&notification(Some("load"), NotificationEvent::Retry)));
+	}
+
+	#[tokio::test]
+	async fn webhook_body_is_rendered_and_json_escaped() {
+		let (url, _, mut bodies) = webhook_server(vec![]);
+
+		deliver(webhook(&url, DEFAULT_WEBHOOK_TEMPLATE), settings(0, String::new())).await;
+
+		let body: serde_json::Value = serde_json::from_str(&bodies.recv().await.unwrap()).unwrap();
+		assert_eq!(body["event"], "failure");
+		assert_eq!(body["workflow_id"], "wf");
+		assert_eq!(body["run_id"], "manual__1");
+		assert_eq!(body["task_id"], "load");
+		assert_eq!(body["run_date"], "2020-06-01T00:00:00+00:00");
+		assert_eq!(body["message"], "Task failed:\n\"exit 1\"");
+	}
+
+	#[test]
+	fn run_dates_are_rendered_in_the_workflow_timezone() {
+		let failure = Notification {
+			run_date: Utc.ymd(2020, 6, 1).and_hms(0, 0, 0).with_timezone(&chrono_tz::Europe::Berlin),
+			..notification(None, NotificationEvent::Failure)
+		};
+
+		assert_eq!(failure.render("{{ run_date }}"), "2020-06-01T02:00:00+02:00");
+	}
+
+	#[tokio::test]
+	async fn webhook_url_is_rendered() {
+		let (url, requests, mut bodies) = webhook_server(vec![]);
+		let template = r#"{"text": "{{ workflow_id }} {{ unknown }}"}"#;
+
+		deliver(webhook(&format!("{}?run={{{{ workflow_id }}}}", url), template), settings(0, String::new())).await;
+
+		assert_eq!(requests.load(Ordering::SeqCst), 1);
+		assert_eq!(bodies.recv().await.unwrap(), r#"{"text": "wf {{ unknown }}"}"#);
+	}
+
+	#[test]
+	fn webhook_url_values_are_percent_encoded() {
+		let failure = Notification {
+			run_id: "manual__a&b=c/ü".to_string(),
+			..notification(None, NotificationEvent::Failure)
+		};
+
+		assert_eq!(
+			failure.render_url("http://localhost/hook?run={{ run_id }}&msg={{ message }}"),
+			"http://localhost/hook?run=manual__a%26b%3Dc%2F%C3%BC&msg=Task%20failed%3A%0A%22exit%201%22"
+		);
+	}
+
+	#[tokio::test]
+	async fn failed_deliveries_are_retried_with_backoff() {
+		let (url, requests, _bodies) = webhook_server(vec![500, 503]);
+
+		let start = Instant::now();
+		deliver(webhook(&url, DEFAULT_WEBHOOK_TEMPLATE), settings(3, String::new())).await;
+
+		assert_eq!(requests.load(Ordering::SeqCst), 3);
+		// 20ms before the first retry, 40ms before the second
+		assert!(start.elapsed() >= Duration::from_millis(60));
+	}
+
+	#[tokio::test]
+	async fn deliveries_are_given_up_after_max_retries() {
+		let (url, requests, _bodies) = webhook_server(vec![500; 10]);
+
+		deliver(webhook(&url, DEFAULT_WEBHOOK_TEMPLATE), settings(2, String::new())).await;
+
+		assert_eq!(requests.load(Ordering::SeqCst), 3);
+	}
+
+	/// Plays an SMTP server for a single session. Returns the commands it received and the message data.
+	async fn smtp_server(mut listener: TcpListener) -> (Vec<String>, Vec<String>) {
+		let (stream, _) = listener.accept().await.unwrap();
+		let (reader, mut writer) = tokio::io::split(stream);
+		let mut reader = BufReader::new(reader);
+		let mut commands = Vec::new();
+		let mut data = Vec::new();
+		writer.write_all(b"220 stand-in ready\r\n").await.unwrap();
+		loop {
+			let mut line = String::new();
+			if reader.read_line(&mut line).await.unwrap() == 0 {
+				break;
+			}
+			let line = line.trim_end_matches("\r\n").to_string();
+			let reply: &[u8] = match line.as_str() {
+				"HELO flowty" => b"250-stand-in greets flowty\r\n250-8BITMIME\r\n250 OK\r\n",
+				"DATA" => {
+					writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await.unwrap();
+					loop {
+						let mut line = String::new();
+						reader.read_line(&mut line).await.unwrap();
+						if line == ".\r\n" {
+							break;
+						}
+						data.push(line.trim_end_matches("\r\n").to_string());
+					}
+					b"250 queued\r\n"
+				},
+				"QUIT" => b"221 bye\r\n",
+				_ => b"250 OK\r\n",
+			};
+			commands.push(line.clone());
+			writer.write_all(reply).await.unwrap();
+			if line == "QUIT" {
+				break;
+			}
+		}
+		(commands, data)
+	}
+
+	#[tokio::test]
+	async fn emails_are_sent_through_smtp() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let smtp_addr = listener.local_addr().unwrap().to_string();
+		let server = tokio::spawn(smtp_server(listener));
+
+		let body = "first\n.\n..two\nlast";
+		send_email(&settings(0, smtp_addr), "ops@example.com, dev@example.com", "Failed\nrun", body).await.unwrap();
+
+		let (commands, data) = server.await.unwrap();
+		assert_eq!(commands, vec![
+			"HELO flowty",
+			"MAIL FROM:<flowty@localhost>",
+			"RCPT TO:<ops@example.com>",
+			"RCPT TO:<dev@example.com>",
+			"DATA",
+			"QUIT",
+		]);
+		assert!(data.contains(&"To: ops@example.com, dev@example.com".to_string()));
+		assert!(data.contains(&"Subject: Failed run".to_string()));
+		let body_start = data.iter().position(|line| line.is_empty()).unwrap() + 1;
+		assert_eq!(&data[body_start..], &["first", "..", "...two", "last"]);
+	}
+
+	#[tokio::test]
+	async fn unexpected_smtp_replies_fail_the_delivery() {
+		let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let smtp_addr = listener.local_addr().unwrap().to_string();
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			stream.write_all(b"554 no service\r\n").await.unwrap();
+		});
+
+		let result = send_email(&settings(0, smtp_addr), "ops@example.com", "subject", "body").await;
+
+		assert_eq!(result, Err("Unexpected SMTP reply: 554 no service".to_string()));
+	}
+
+	#[tokio::test]
+	async fn recipients_cannot_inject_smtp_commands() {
+		let settings = settings(0, "127.0.0.1:1".to_string());
+		for to in &["ops@example.com>\r\nRCPT TO:<evil@example.com", "ops@example.com\nBcc: evil@example.com"] {
+			let result = send_email(&settings, to, "subject", "body").await;
+
+			assert!(matches!(&result, Err(e) if e.starts_with("Invalid recipient")), "{:?}", result);
+		}
+	}
+
+	#[tokio::test]
+	async fn emails_need_recipients() {
+		let result = send_email(&settings(0, "127.0.0.1:1".to_string()), " , ", "subject", "body").await;
+
+		assert_eq!(result, Err("No recipients".to_string()));
+	}
+}
@@ -0,0 +1,2 @@
+INSERT INTO workflow_setting (workflow_id, is_paused) VALUES ($1, TRUE)
+ON CONFLICT (workflow_id) DO UPDATE SET is_paused = TRUE;
\ No newline at end of file
@@ -0,0 +1 @@
+UPDATE workflow_instance SET wid = $2 WHERE wiid = $1 AND wid IS NULL;
\ No newline at end of file
@@ -0,0 +1 @@
+INSERT INTO workflow_event (workflow_id, event, message) VALUES ($1, $2, $3);
\ No newline at end of file
@@ -0,0 +1,4 @@
+INSERT INTO sla_miss (workflow_id, run_id, run_date, task_id, kind, due_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (workflow_id, run_id, (COALESCE(task_id, '')), kind) DO NOTHING
+RETURNING smid;
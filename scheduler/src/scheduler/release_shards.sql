@@ -0,0 +1,2 @@
+UPDATE scheduler_shard SET owner = NULL, lease_until = NULL
+WHERE owner = $1 AND shard = ANY($2);
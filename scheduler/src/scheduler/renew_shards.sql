@@ -0,0 +1,3 @@
+UPDATE scheduler_shard SET lease_until = NOW() + make_interval(secs => $2)
+WHERE owner = $1 AND shard < $3
+RETURNING shard;
@@ -0,0 +1,274 @@
+//! Removes finished runs which exceed the retention policy of their workflow, together with their task rows.
+//!
+//! The policy is "keep the newest N runs" and/or "keep runs created in the last X days". The defaults come from
+//! the scheduler config and are overridden per workflow in `workflow_setting`. A run expires as soon as it
+//! exceeds either limit. Active runs and the newest scheduled run of a workflow, after which scheduling
+//! resumes, are always kept.
+//!
+//! The cleanup runs next to the scheduler loop, so it never delays a tick. It deletes in small batches, each in
+//! its own statement, so rows are only locked briefly. A scheduler only cleans up the workflows it schedules,
+//! which keeps the members of a cluster from racing each other.
+//!
+//! With an `archive_dir`, every batch is exported to a JSONL file before it is deleted, one run with its tasks
+//! per line, and gzipped if `archive_compress` is set. A batch whose export failed is not deleted.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::config::SchedulerConfig;
+use super::db::Db;
+use super::workflow_instance::{RunState, RunType};
+
+/// Pause between two batches, so the cleanup does not compete with the scheduler for the DB.
+const BATCH_PAUSE: Duration = Duration::from_millis(200);
+
+/// A run selected by `expired_runs.sql`, with what the retention policy of its workflow decided on.
+#[derive(Debug, Clone)]
+struct ExpiredRun {
+	wiid: i32,
+	run_type: RunType,
+	run_state: RunState,
+	created_at: DateTime<Utc>,
+	/// 1 for the newest run of the workflow by run_date, 2 for the one before, ...
+	position: i64,
+	/// Like position, among the runs of the same run_type
+	type_position: i64,
+	keep_runs: i64,
+	max_age_days: i64,
+}
+
+impl ExpiredRun {
+	fn from_row(row: &tokio_postgres::Row) -> ExpiredRun {
+		let run_type: &str = row.get("run_type");
+		let run_state: &str = row.get("run_state");
+		ExpiredRun {
+			wiid: row.get("wiid"),
+			run_type: RunType::from_str(run_type),
+			run_state: RunState::from_str(run_state),
+			created_at: row.get("created_at"),
+			position: row.get("position"),
+			type_position: row.get("type_position"),
+			keep_runs: row.get("keep_runs"),
+			max_age_days: row.get("max_age_days"),
+		}
+	}
+
+	/// Whether the run exceeds the retention policy at `now`. Runs are only removed if this agrees with the query.
+	fn is_expired(&self, now: DateTime<Utc>) -> bool {
+		let newest_scheduled = self.run_type == RunType::Scheduled && self.type_position == 1;
+		let too_many = self.keep_runs > 0 && self.position > self.keep_runs;
+		let too_old = self.max_age_days > 0 && self.created_at < now - chrono::Duration::days(self.max_age_days);
+		self.run_state.is_finished() && !newest_scheduled && (too_many || too_old)
+	}
+}
+
+/// Whether more expired runs may be left after a batch which found `found` of them.
+fn has_more(found: usize, batch_size: i64) -> bool {
+	found as i64 >= batch_size
+}
+
+pub struct Retention {
+	interval: Duration,
+	keep_runs: i64,
+	max_age_days: i64,
+	batch_size: i64,
+	archive_dir: Option<PathBuf>,
+	archive_compress: bool,
+	/// The workflows the scheduler currently schedules. Empty while it stands by.
+	owned_workflows: watch::Receiver<Vec<String>>,
+}
+
+impl Retention {
+	pub fn new(config: &SchedulerConfig, owned_workflows: watch::Receiver<Vec<String>>) -> Retention {
+		Retention {
+			interval: Duration::from_secs(config.retention_interval_sec),
+			keep_runs: config.retention_keep_runs as i64,
+			max_age_days: config.retention_max_age_days as i64,
+			batch_size: config.retention_batch_size as i64,
+			archive_dir: if config.archive_dir.is_empty() { None } else { Some(PathBuf::from(&config.archive_dir)) },
+			archive_compress: config.archive_compress,
+			owned_workflows,
+		}
+	}
+
+	/// Cleans up every `retention_interval_sec` until a shutdown is requested. A retention_interval_sec of 0
+	/// disables the cleanup.
+	pub async fn run(&self, sql_client: &Db, mut shutdown: watch::Receiver<bool>) {
+		if self.interval.as_secs() == 0 {
+			info!("Retention of runs is disabled");
+			return;
+		}
+		loop {
+			tokio::select! {
+				_ = time::delay_for(self.interval) => (),
+				_ = super::shutdown_requested(&mut shutdown) => return,
+			}
+			self.clean_up(sql_client, &shutdown).await;
+		}
+	}
+
+	/// Removes expired runs batch by batch, until none are left or a shutdown is requested.
+	/// Errors end the pass, the next one retries.
+	async fn clean_up(&self, sql_client: &Db, shutdown: &watch::Receiver<bool>) {
+		let mut removed = 0;
+		while !*shutdown.borrow() {
+			// Re-read on every batch, so workflows handed over to another scheduler are left alone
+			let workflow_ids = self.owned_workflows.borrow().clone();
+			if workflow_ids.is_empty() {
+				break;
+			}
+			let rows = match sql_client.query(
+				include_str!("expired_runs.sql"),
+				&[&workflow_ids, &self.keep_runs, &self.max_age_days, &self.batch_size]
+			).await {
+				Ok(rows) => rows,
+				Err(e) => {
+					error!("Failed to retrieve expired runs:\n{}", e);
+					break;
+				}
+			};
+			let runs: Vec<ExpiredRun> = rows.iter().map(ExpiredRun::from_row).collect();
+			if runs.is_empty() {
+				break;
+			}
+			// The query would return the same runs again, so a disagreement ends the pass
+			let now: DateTime<Utc> = rows[0].get("now");
+			if let Some(run) = runs.iter().find(|run| !run.is_expired(now)) {
+				error!("Run {} was selected for removal, but does not exceed its retention policy: {:?}", run.wiid, run);
+				break;
+			}
+			let wiids: Vec<i32> = runs.iter().map(|run| run.wiid).collect();
+			if let Some(archive_dir) = &self.archive_dir {
+				if let Err(e) = self.archive(sql_client, archive_dir, &wiids).await {
+					error!("Failed to archive expired runs to {}. Not removing them:\n{}", archive_dir.display(), e);
+					break;
+				}
+			}
+			match sql_client.query(include_str!("delete_runs.sql"), &[&wiids]).await {
+				Ok(rows) => removed += rows.len(),
+				Err(e) => {
+					error!("Failed to remove expired runs:\n{}", e);
+					break;
+				}
+			};
+			if !has_more(wiids.len(), self.batch_size) {
+				break;
+			}
+			time::delay_for(BATCH_PAUSE).await;
+		}
+		if removed > 0 {
+			info!("Removed {} expired runs", removed);
+		}
+	}
+
+	/// Exports the runs `wiids` with their tasks to a new JSONL file in `archive_dir`.
+	async fn archive(&self, sql_client: &Db, archive_dir: &Path, wiids: &[i32]) -> Result<(), String> {
+		let rows = sql_client
+			.query(include_str!("archive_runs.sql"), &[&wiids])
+			.await
+			.map_err(|e| e.to_string())?;
+		let lines: Vec<String> = rows.iter().map(|row| row.get("line")).collect();
+		let path = archive_dir.join(format!("runs-{}-{}.jsonl", Utc::now().format("%Y%m%dT%H%M%S"), wiids[0]));
+
+		let file = path.clone();
+		tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+			let mut writer = std::io::BufWriter::new(std::fs::File::create(&file)?);
+			for line in lines {
+				writeln!(writer, "{}", line)?;
+			}
+			writer.flush()
+		})
+			.await
+			.map_err(|e| e.to_string())?
+			.map_err(|e| format!("{}: {}", path.display(), e))?;
+
+		if self.archive_compress {
+			let status = Command::new("gzip").arg(&path).status().await.map_err(|e| format!("gzip: {}", e))?;
+			if !status.success() {
+				return Err(format!("gzip {} exited with {}", path.display(), status));
+			}
+		}
+		trace!("Archived {} runs to {}", wiids.len(), path.display());
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn utc(date: &str) -> DateTime<Utc> {
+		date.parse().unwrap()
+	}
+
+	/// A successful manual run created at 2020-06-01, the third newest of its workflow, under a policy keeping
+	/// 5 runs for 30 days.
+	fn run() -> ExpiredRun {
+		ExpiredRun {
+			wiid: 1,
+			run_type: RunType::Manual,
+			run_state: RunState::Success,
+			created_at: utc("2020-06-01T00:00:00Z"),
+			position: 3,
+			type_position: 3,
+			keep_runs: 5,
+			max_age_days: 30,
+		}
+	}
+
+	#[test]
+	fn runs_expire_by_count_or_age() {
+		let now = utc("2020-06-15T00:00:00Z");
+		assert!(!run().is_expired(now));
+		assert!(ExpiredRun { position: 6, ..run() }.is_expired(now));
+		assert!(!ExpiredRun { position: 5, ..run() }.is_expired(now));
+		assert!(run().is_expired(utc("2020-07-01T00:00:01Z")));
+		assert!(!run().is_expired(utc("2020-07-01T00:00:00Z")));
+	}
+
+	#[test]
+	fn zero_disables_a_limit() {
+		let now = utc("2021-06-01T00:00:00Z");
+		assert!(!ExpiredRun { position: 100, keep_runs: 0, max_age_days: 0, ..run() }.is_expired(now));
+		assert!(ExpiredRun { position: 100, max_age_days: 0, ..run() }.is_expired(now));
+		assert!(ExpiredRun { keep_runs: 0, ..run() }.is_expired(now));
+	}
+
+	#[test]
+	fn active_runs_are_kept() {
+		let now = utc("2021-06-01T00:00:00Z");
+		let expired = ExpiredRun { position: 100, ..run() };
+		assert!(expired.is_expired(now));
+		let active = [RunState::Nothing, RunState::Queued, RunState::Running, RunState::UpForRetry, RunState::Cancelling];
+		for run_state in &active {
+			assert!(!ExpiredRun { run_state: *run_state, ..expired.clone() }.is_expired(now), "{:?}", run_state);
+		}
+		let finished = [RunState::Failed, RunState::Cancelled, RunState::Skipped, RunState::TimedOut];
+		for run_state in &finished {
+			assert!(ExpiredRun { run_state: *run_state, ..expired.clone() }.is_expired(now), "{:?}", run_state);
+		}
+	}
+
+	#[test]
+	fn newest_scheduled_run_is_kept() {
+		let now = utc("2021-06-01T00:00:00Z");
+		let scheduled = ExpiredRun { run_type: RunType::Scheduled, position: 100, ..run() };
+		assert!(!ExpiredRun { type_position: 1, ..scheduled.clone() }.is_expired(now));
+		assert!(ExpiredRun { type_position: 2, ..scheduled }.is_expired(now));
+		// Only scheduling resumes after a run, triggered ones are not kept
+		assert!(ExpiredRun { type_position: 1, ..run() }.is_expired(now));
+	}
+
+	#[test]
+	fn passes_end_with_a_partial_batch() {
+		assert!(has_more(500, 500));
+		assert!(!has_more(499, 500));
+		assert!(!has_more(0, 500));
+	}
+}
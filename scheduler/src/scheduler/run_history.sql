@@ -0,0 +1,5 @@
+SELECT wiid, wid, run_id, run_type, run_state::TEXT AS run_state, run_date, timezone, reason
+FROM workflow_instance
+WHERE workflow_id = $1
+ORDER BY run_date DESC, wiid DESC
+LIMIT $2;
\ No newline at end of file
@@ -0,0 +1,387 @@
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult};
+use chrono_tz::Tz;
+
+use flowty_types::FlowtyError;
+use super::settings::WorkflowSettings;
+
+/// Longest DST gap we search through when a local time does not exist.
+const MAX_DST_GAP_MINUTES: i64 = 180;
+
+/// The schedule of a workflow, parsed from `openworkflow::Workflow::schedule`.
+///
+/// Accepted expressions:
+/// - Standard 5-field cron (`*/5 * * * *`), with day-of-week 0-7 where 0 and 7 are Sunday
+/// - 6 or 7-field cron with seconds, as understood by the `cron` crate
+/// - `@hourly`, `@daily`/`@midnight`, `@weekly`, `@monthly`, `@yearly`/`@annually`
+/// - Fixed intervals like `every 30s`, `every 15m`, `every 2h` or `every 1d`
+/// - `none` or an empty string for workflows which are only triggered manually
+#[derive(Debug, Clone)]
+pub enum Schedule {
+	Cron(cron::Schedule),
+	/// Fixed interval, anchored at the workflow's start_date or the unix epoch.
+	Interval(Duration),
+	Manual,
+}
+
+impl FromStr for Schedule {
+	type Err = FlowtyError;
+
+	fn from_str(expression: &str) -> Result<Schedule, Self::Err> {
+		let expression = expression.trim();
+		let invalid = |message: String| FlowtyError::InvalidSchedule {
+			schedule: expression.to_string(),
+			message,
+		};
+
+		if expression.is_empty() || expression.eq_ignore_ascii_case("none") {
+			return Ok(Schedule::Manual);
+		}
+		if expression.starts_with("every ") {
+			return parse_interval(&expression["every ".len()..])
+				.map(Schedule::Interval)
+				.map_err(invalid);
+		}
+
+		let cron_expression = match expression {
+			"@hourly" => "0 0 * * * *".to_string(),
+			"@daily" | "@midnight" => "0 0 0 * * *".to_string(),
+			"@weekly" => "0 0 0 * * Sun".to_string(),
+			"@monthly" => "0 0 0 1 * *".to_string(),
+			"@yearly" | "@annually" => "0 0 0 1 1 *".to_string(),
+			e if e.starts_with('@') => return Err(invalid("Unknown macro".into())),
+			e => {
+				let fields: Vec<&str> = e.split_whitespace().collect();
+				match fields.len() {
+					5 => format!(
+						"0 {} {} {} {} {}",
+						fields[0], fields[1], fields[2], fields[3], translate_day_of_week(fields[4])
+					),
+					6 | 7 => e.to_string(),
+					n => return Err(invalid(format!("Expected 5, 6 or 7 cron fields, found {}", n))),
+				}
+			}
+		};
+		cron::Schedule::from_str(&cron_expression)
+			.map(Schedule::Cron)
+			.map_err(|e| invalid(e.to_string()))
+	}
+}
+
+impl Schedule {
+	/// Returns the occurrences after `after`, honoring the timezone, start_date and exclusion calendars of `settings`.
+	pub fn after<'a>(
+		&'a self,
+		settings: &'a WorkflowSettings,
+		after: &DateTime<Utc>
+	) -> Box<dyn Iterator<Item = DateTime<Utc>> + 'a> {
+		let timezone = settings.timezone;
+		let occurrences: Box<dyn Iterator<Item = DateTime<Utc>> + 'a> = match self {
+			Schedule::Cron(schedule) => Box::new(occurrences_after(schedule, timezone, after)),
+			Schedule::Interval(interval) => {
+				let anchor = settings.start_date.unwrap_or_else(|| Utc.timestamp(0, 0));
+				Box::new(interval_after(*interval, anchor, after))
+			},
+			Schedule::Manual => Box::new(std::iter::empty()),
+		};
+		Box::new(occurrences.filter(move |run_date| {
+			let local_date = run_date.with_timezone(&timezone).naive_local().date();
+			let excluded = settings.excluded_dates.contains(&local_date);
+			if excluded {
+				trace!("Skipping {} as {} is excluded", run_date, local_date);
+			}
+			!excluded
+		}))
+	}
+}
+
+/// Parses the duration part of `every <n><unit>`, with unit one of s, m, h or d.
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+	let interval = interval.trim();
+	let split = interval.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| interval.len());
+	let (amount, unit) = interval.split_at(split);
+	let amount: i64 = amount.parse().map_err(|_| format!("Invalid interval amount '{}'", amount))?;
+	if amount <= 0 {
+		return Err("Interval must be positive".into());
+	}
+	match unit.trim() {
+		"s" => Ok(Duration::seconds(amount)),
+		"m" => Ok(Duration::minutes(amount)),
+		"h" => Ok(Duration::hours(amount)),
+		"d" => Ok(Duration::days(amount)),
+		u => Err(format!("Unknown interval unit '{}'", u)),
+	}
+}
+
+const DAYS_OF_WEEK: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// Standard cron counts days of the week from 0 (Sunday), the `cron` crate from 1 (Sunday).
+/// Numeric days are translated into names, which both understand.
+fn translate_day_of_week(field: &str) -> String {
+	field
+		.split(',')
+		.map(|part| {
+			let mut split = part.splitn(2, '/');
+			let range = split.next().unwrap_or_default();
+			let step = split.next();
+			if let Some(days) = expand_range_to_sunday(range, step) {
+				return days;
+			}
+			let range = range
+				.split('-')
+				.map(|day| match day.parse::<usize>() {
+					Ok(day) if day < DAYS_OF_WEEK.len() => DAYS_OF_WEEK[day].to_string(),
+					_ => day.to_string(),
+				})
+				.collect::<Vec<String>>()
+				.join("-");
+			match step {
+				Some(step) => format!("{}/{}", range, step),
+				None => range,
+			}
+		})
+		.collect::<Vec<String>>()
+		.join(",")
+}
+
+/// Lists the days of a numeric range ending on Sunday, e.g. `5-7` or `5-0` as `Fri,Sat,Sun`.
+/// As names such a range would end before it starts, which the `cron` crate rejects.
+fn expand_range_to_sunday(range: &str, step: Option<&str>) -> Option<String> {
+	let mut days = range.splitn(2, '-').map(|day| day.parse::<usize>().ok());
+	let (start, end) = match (days.next().flatten(), days.next().flatten()) {
+		(Some(start), Some(end)) => (start, end),
+		_ => return None,
+	};
+	let step = match step {
+		Some(step) => step.parse::<usize>().ok().filter(|step| *step > 0)?,
+		None => 1,
+	};
+	let ends_on_sunday = end == 7 || (end == 0 && start > 0);
+	if start > 7 || !ends_on_sunday {
+		return None;
+	}
+	let days: Vec<&str> = (start..=7).step_by(step).map(|day| DAYS_OF_WEEK[day]).collect();
+	Some(days.join(","))
+}
+
+fn interval_after(
+	interval: Duration,
+	anchor: DateTime<Utc>,
+	after: &DateTime<Utc>
+) -> impl Iterator<Item = DateTime<Utc>> {
+	let first = if *after < anchor {
+		anchor
+	} else {
+		let elapsed = (*after - anchor).num_seconds() / interval.num_seconds();
+		anchor + Duration::seconds(interval.num_seconds() * (elapsed + 1))
+	};
+	std::iter::successors(Some(first), move |run_date| Some(*run_date + interval))
+}
+
+/// Returns the occurrences of `schedule` after `after`, evaluated on the wall clock of `timezone`.
+///
+/// DST transitions are handled as follows:
+/// - A local time skipped by a transition (e.g. 02:30 when clocks jump from 02:00 to 03:00) is moved to the first
+///   valid local time after the gap.
+/// - A local time repeated by a transition (e.g. 02:30 when clocks fall back from 03:00 to 02:00) runs once, at
+///   its earliest instant.
+fn occurrences_after<'a>(
+	schedule: &'a cron::Schedule,
+	timezone: Tz,
+	after: &DateTime<Utc>
+) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+	let mut last = *after;
+	// The cron schedule is evaluated on naive wall clock times, which are mapped into the timezone afterwards.
+	let local_after = Utc.from_utc_datetime(&last.with_timezone(&timezone).naive_local());
+	schedule
+		.after(&local_after)
+		.filter_map(move |local| resolve_local(timezone, &local.naive_utc()))
+		.filter(move |run_date| {
+			// Drops local times which collapsed onto an already emitted instant at a DST transition.
+			if *run_date > last {
+				last = *run_date;
+				return true;
+			}
+			false
+		})
+}
+
+/// Maps a wall clock time in `timezone` onto an instant, following the DST rules of `occurrences_after`.
+fn resolve_local(timezone: Tz, local: &NaiveDateTime) -> Option<DateTime<Utc>> {
+	match timezone.from_local_datetime(local) {
+		LocalResult::Single(dt) => Some(dt.with_timezone(&Utc)),
+		LocalResult::Ambiguous(earliest, _) => Some(earliest.with_timezone(&Utc)),
+		LocalResult::None => {
+			for minutes in 1..=MAX_DST_GAP_MINUTES {
+				if let LocalResult::Single(dt) = timezone.from_local_datetime(&(*local + Duration::minutes(minutes))) {
+					trace!("Local time {} does not exist in {}. Moving it to {}", local, timezone.name(), dt);
+					return Some(dt.with_timezone(&Utc));
+				}
+			}
+			warn!("Local time {} does not exist in {}. Skipping it", local, timezone.name());
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn utc(date: &str) -> DateTime<Utc> {
+		date.parse().unwrap()
+	}
+
+	/// The next `n` occurrences of `expression` after `after`, in UTC.
+	fn next(expression: &str, after: &str, n: usize) -> Vec<DateTime<Utc>> {
+		let schedule: Schedule = expression.parse().unwrap();
+		let settings = WorkflowSettings::default();
+		schedule.after(&settings, &utc(after)).take(n).collect()
+	}
+
+	#[test]
+	fn days_of_week_are_translated() {
+		assert_eq!(translate_day_of_week("0"), "Sun");
+		assert_eq!(translate_day_of_week("7"), "Sun");
+		assert_eq!(translate_day_of_week("0,6"), "Sun,Sat");
+		assert_eq!(translate_day_of_week("1-5"), "Mon-Fri");
+		assert_eq!(translate_day_of_week("1-5/2"), "Mon-Fri/2");
+		assert_eq!(translate_day_of_week("*"), "*");
+		assert_eq!(translate_day_of_week("*/2"), "*/2");
+		assert_eq!(translate_day_of_week("Mon-Fri"), "Mon-Fri");
+	}
+
+	#[test]
+	fn ranges_ending_on_sunday_are_expanded() {
+		assert_eq!(translate_day_of_week("1-7"), "Mon,Tue,Wed,Thu,Fri,Sat,Sun");
+		assert_eq!(translate_day_of_week("5-7"), "Fri,Sat,Sun");
+		assert_eq!(translate_day_of_week("5-0"), "Fri,Sat,Sun");
+		assert_eq!(translate_day_of_week("1-7/2"), "Mon,Wed,Fri,Sun");
+		assert_eq!(translate_day_of_week("0-7/2"), "Sun,Tue,Thu,Sat");
+		assert_eq!(translate_day_of_week("0-0"), "Sun-Sun");
+	}
+
+	#[test]
+	fn five_fields_are_scheduled() {
+		// 2020-06-01 is a Monday
+		assert_eq!(next("*/15 * * * *", "2020-06-01T00:00:00Z", 2), vec![
+			utc("2020-06-01T00:15:00Z"),
+			utc("2020-06-01T00:30:00Z"),
+		]);
+		let days = |expression| -> Vec<Weekday> {
+			next(expression, "2020-06-01T00:00:00Z", 4).iter().map(|run_date| run_date.weekday()).collect()
+		};
+		assert_eq!(days("0 9 * * 1-7"), vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu]);
+		assert_eq!(days("0 9 * * 5-7"), vec![Weekday::Fri, Weekday::Sat, Weekday::Sun, Weekday::Fri]);
+		assert_eq!(days("0 9 * * 0"), vec![Weekday::Sun; 4]);
+		assert_eq!(days("0 9 * * 7"), vec![Weekday::Sun; 4]);
+		assert_eq!(next("0 9 * * 5-7", "2020-06-01T00:00:00Z", 1), vec![utc("2020-06-05T09:00:00Z")]);
+	}
+
+	#[test]
+	fn macros_are_scheduled() {
+		let after = "2020-06-01T00:30:00Z";
+		assert_eq!(next("@hourly", after, 1), vec![utc("2020-06-01T01:00:00Z")]);
+		assert_eq!(next("@daily", after, 1), vec![utc("2020-06-02T00:00:00Z")]);
+		assert_eq!(next("@midnight", after, 1), vec![utc("2020-06-02T00:00:00Z")]);
+		assert_eq!(next("@weekly", after, 1), vec![utc("2020-06-07T00:00:00Z")]);
+		assert_eq!(next("@monthly", after, 1), vec![utc("2020-07-01T00:00:00Z")]);
+		assert_eq!(next("@yearly", after, 1), vec![utc("2021-01-01T00:00:00Z")]);
+		assert_eq!(next("@annually", after, 1), vec![utc("2021-01-01T00:00:00Z")]);
+	}
+
+	#[test]
+	fn intervals_are_anchored() {
+		assert!(matches!("every 15m".parse::<Schedule>(), Ok(Schedule::Interval(i)) if i == Duration::minutes(15)));
+		assert!(matches!("every 30s".parse::<Schedule>(), Ok(Schedule::Interval(i)) if i == Duration::seconds(30)));
+		assert!(matches!("every 2h".parse::<Schedule>(), Ok(Schedule::Interval(i)) if i == Duration::hours(2)));
+		assert!(matches!("every 1d".parse::<Schedule>(), Ok(Schedule::Interval(i)) if i == Duration::days(1)));
+		// Without a start_date, intervals are anchored at the unix epoch
+		assert_eq!(next("every 15m", "2020-06-01T00:07:00Z", 2), vec![
+			utc("2020-06-01T00:15:00Z"),
+			utc("2020-06-01T00:30:00Z"),
+		]);
+
+		let schedule: Schedule = "every 15m".parse().unwrap();
+		let settings = WorkflowSettings {
+			start_date: Some(utc("2020-06-01T00:05:00Z")),
+			..WorkflowSettings::default()
+		};
+		let run_dates: Vec<DateTime<Utc>> = schedule.after(&settings, &utc("2020-06-01T00:07:00Z")).take(1).collect();
+		assert_eq!(run_dates, vec![utc("2020-06-01T00:20:00Z")]);
+	}
+
+	/// The next `n` occurrences of `expression` after `after`, evaluated in Europe/Berlin.
+	fn next_in_berlin(expression: &str, after: &str, n: usize) -> Vec<DateTime<Utc>> {
+		let schedule: Schedule = expression.parse().unwrap();
+		let settings = WorkflowSettings {
+			timezone: chrono_tz::Europe::Berlin,
+			..WorkflowSettings::default()
+		};
+		schedule.after(&settings, &utc(after)).take(n).collect()
+	}
+
+	#[test]
+	fn skipped_local_times_move_forward() {
+		// On 2020-03-29 clocks jump from 02:00 CET to 03:00 CEST, so 02:30 does not exist
+		assert_eq!(next_in_berlin("30 2 * * *", "2020-03-28T00:00:00Z", 3), vec![
+			utc("2020-03-28T01:30:00Z"),
+			// Moved to 03:00 CEST
+			utc("2020-03-29T01:00:00Z"),
+			utc("2020-03-30T00:30:00Z"),
+		]);
+		// 02:00 moves to 03:00 CEST, which runs only once
+		assert_eq!(next_in_berlin("0 * * * *", "2020-03-28T23:30:00Z", 3), vec![
+			utc("2020-03-29T00:00:00Z"),
+			utc("2020-03-29T01:00:00Z"),
+			utc("2020-03-29T02:00:00Z"),
+		]);
+	}
+
+	#[test]
+	fn repeated_local_times_run_once() {
+		// On 2020-10-25 clocks fall back from 03:00 CEST to 02:00 CET, so 02:30 happens twice
+		assert_eq!(next_in_berlin("30 2 * * *", "2020-10-24T00:00:00Z", 3), vec![
+			utc("2020-10-24T00:30:00Z"),
+			// The earlier 02:30 CEST
+			utc("2020-10-25T00:30:00Z"),
+			utc("2020-10-26T01:30:00Z"),
+		]);
+		// The hour from 02:00 to 03:00 runs once, at 02:00 CEST
+		assert_eq!(next_in_berlin("0 * * * *", "2020-10-24T22:30:00Z", 3), vec![
+			utc("2020-10-24T23:00:00Z"),
+			utc("2020-10-25T00:00:00Z"),
+			utc("2020-10-25T02:00:00Z"),
+		]);
+	}
+
+	#[test]
+	fn manual_schedules_never_occur() {
+		assert!(matches!("".parse::<Schedule>(), Ok(Schedule::Manual)));
+		assert!(matches!("none".parse::<Schedule>(), Ok(Schedule::Manual)));
+		assert!(next("none", "2020-06-01T00:00:00Z", 1).is_empty());
+	}
+
+	#[test]
+	fn invalid_expressions_are_rejected() {
+		for expression in &[
+			"every 0m",
+			"every -5m",
+			"every 5x",
+			"every m",
+			"@often",
+			"* * * *",
+			"* * * * * * * *",
+			"61 * * * *",
+			"* * * * 9",
+		] {
+			assert!(
+				matches!(expression.parse::<Schedule>(), Err(FlowtyError::InvalidSchedule { .. })),
+				"'{}' was accepted",
+				expression
+			);
+		}
+	}
+}
@@ -0,0 +1,262 @@
+use std::path::Path;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use tokio::sync::oneshot;
+
+use super::db::Db;
+use super::template::TemplateContext;
+
+/// HTTP pokes run in the background. An unresponsive endpoint must not keep the sensor from poking again.
+const HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorKind {
+	/// Succeeds once the target path exists.
+	File,
+	/// Succeeds once the target query returns rows. The query runs against the scheduler's database, so templates
+	/// are not rendered into it. Otherwise the payload of a triggered run could inject SQL.
+	Sql,
+	/// Succeeds once a GET on the target URL returns 200. Only plain http is supported.
+	Http,
+}
+
+/// Result of a single poke.
+#[derive(Debug, PartialEq)]
+pub enum SensorState {
+	Success,
+	Waiting,
+	TimedOut,
+}
+
+/// A task which waits for an external condition. The scheduler evaluates it itself at the poke interval,
+/// so it never holds an executor slot. Stored in the `task_sensor` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensor {
+	pub task_id: String,
+	pub kind: SensorKind,
+	pub target: String,
+	pub poke_interval: Duration,
+	/// Total time the sensor may wait. `None` waits forever.
+	pub timeout: Option<Duration>,
+	/// On timeout the task is skipped instead of failed.
+	pub soft_fail: bool,
+}
+
+impl Sensor {
+	pub fn from_row(row: &tokio_postgres::Row) -> Option<Sensor> {
+		let kind: &str = row.get("kind");
+		let kind = match kind {
+			"file" => SensorKind::File,
+			"sql" => SensorKind::Sql,
+			"http" => SensorKind::Http,
+			k => {
+				warn!("Unknown sensor kind '{}'", k);
+				return None;
+			}
+		};
+		let poke_interval_sec: Option<i64> = row.get("poke_interval_sec");
+		let timeout_sec: Option<i64> = row.get("timeout_sec");
+		Some(Sensor {
+			task_id: row.get("task_id"),
+			kind,
+			target: row.get("target"),
+			poke_interval: Duration::seconds(poke_interval_sec.unwrap_or(60)),
+			timeout: timeout_sec.map(Duration::seconds),
+			soft_fail: row.get::<_, Option<bool>>("soft_fail").unwrap_or(false),
+		})
+	}
+
+	/// The target with the templates of the run rendered, except for SQL targets.
+	pub fn render_target(&self, context: &TemplateContext) -> String {
+		match self.kind {
+			SensorKind::Sql => self.target.clone(),
+			SensorKind::File | SensorKind::Http => context.render(&self.target),
+		}
+	}
+
+	/// True if the poke interval elapsed since the poke at `last_poke`.
+	pub fn is_due(&self, last_poke: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+		now - last_poke >= self.poke_interval
+	}
+
+	/// Checks the condition once. `target` is the rendered target.
+	pub async fn poke(&self, sql_client: &Db, target: &str) -> bool {
+		trace!("Poking {:?} sensor '{}' for '{}'", self.kind, self.task_id, target);
+		match self.kind {
+			SensorKind::File => Path::new(target).exists(),
+			SensorKind::Sql => match sql_client.query(target, &[]).await {
+				Ok(rows) => !rows.is_empty(),
+				Err(e) => {
+					warn!("Sensor '{}' failed to run query: {}", self.task_id, e);
+					false
+				}
+			},
+			SensorKind::Http => poke_http(&self.task_id, target).await,
+		}
+	}
+
+	/// Pokes an HTTP sensor in the background, like tasks are dispatched. The result is sent on the returned
+	/// channel, so a slow endpoint does not hold up the tick.
+	pub fn spawn_http_poke(&self, target: String) -> oneshot::Receiver<bool> {
+		let (result_tx, result_rx) = oneshot::channel();
+		let task_id = self.task_id.clone();
+		tokio::spawn(async move {
+			trace!("Poking Http sensor '{}' for '{}'", task_id, target);
+			let _ = result_tx.send(poke_http(&task_id, &target).await);
+		});
+		result_rx
+	}
+
+	/// State of the sensor after a poke, which started waiting at `started_at`.
+	pub fn state(&self, success: bool, started_at: DateTime<Utc>, now: DateTime<Utc>) -> SensorState {
+		if success {
+			return SensorState::Success;
+		}
+		match self.timeout {
+			Some(timeout) if now - started_at > timeout => SensorState::TimedOut,
+			_ => SensorState::Waiting,
+		}
+	}
+}
+
+/// True if a GET on `target` returns 200.
+async fn poke_http(task_id: &str, target: &str) -> bool {
+	match target.parse() {
+		Ok(uri) => match tokio::time::timeout(HTTP_TIMEOUT, hyper::Client::new().get(uri)).await {
+			Ok(Ok(response)) => response.status() == hyper::StatusCode::OK,
+			Ok(Err(e)) => {
+				trace!("Sensor '{}' failed to reach '{}': {}", task_id, target, e);
+				false
+			},
+			Err(_) => {
+				trace!("Sensor '{}' timed out reaching '{}'", task_id, target);
+				false
+			}
+		},
+		Err(e) => {
+			warn!("Sensor '{}' has an invalid URL '{}': {}", task_id, target, e);
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use chrono_tz::Tz;
+	use hyper::service::{make_service_fn, service_fn};
+	use hyper::{Body, Request, Response, Server, StatusCode};
+
+	use crate::config::SchedulerConfig;
+
+	fn sensor(kind: SensorKind, target: &str, timeout: Option<Duration>) -> Sensor {
+		Sensor {
+			task_id: "wait".to_string(),
+			kind,
+			target: target.to_string(),
+			poke_interval: Duration::seconds(60),
+			timeout,
+			soft_fail: false,
+		}
+	}
+
+	/// A DB nothing listens on. Connections are only made by queries, which fail right away.
+	fn unreachable_db() -> Db {
+		let config = SchedulerConfig {
+			psql_url: "postgres://postgres@127.0.0.1:1".to_string(),
+			db_max_retries: 0,
+			..Default::default()
+		};
+		Db::new(&config).unwrap().0
+	}
+
+	/// Serves HTTP on a local port, answering `/ready` with 200 and everything else with 404.
+	fn http_server() -> String {
+		let make_service = make_service_fn(|_| async {
+			Ok::<_, hyper::Error>(service_fn(|request: Request<Body>| async move {
+				let status = if request.uri().path() == "/ready" { StatusCode::OK } else { StatusCode::NOT_FOUND };
+				Ok::<_, hyper::Error>(Response::builder().status(status).body(Body::empty()).unwrap())
+			}))
+		});
+		let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+		let url = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+		url
+	}
+
+	#[tokio::test]
+	async fn file_sensors_wait_for_the_path() {
+		let db = unreachable_db();
+		let dir = std::env::temp_dir();
+		let file = sensor(SensorKind::File, "", None);
+
+		assert!(file.poke(&db, dir.to_str().unwrap()).await);
+		assert!(!file.poke(&db, dir.join("flowty-sensor-missing").to_str().unwrap()).await);
+	}
+
+	#[tokio::test]
+	async fn sql_sensors_wait_while_the_query_fails() {
+		let sql = sensor(SensorKind::Sql, "SELECT 1", None);
+
+		assert!(!sql.poke(&unreachable_db(), &sql.target).await);
+	}
+
+	#[test]
+	fn only_file_and_http_targets_are_rendered() {
+		let conf = serde_json::json!({ "table": "users; DROP TABLE workflow" });
+		let context = TemplateContext {
+			run_id: "manual__1",
+			run_date: Tz::UTC.ymd(2020, 6, 1).and_hms(0, 0, 0),
+			conf: &conf,
+		};
+
+		let file = sensor(SensorKind::File, "/data/{{ ds }}/_SUCCESS", None);
+		assert_eq!(file.render_target(&context), "/data/2020-06-01/_SUCCESS");
+		let http = sensor(SensorKind::Http, "http://localhost/{{ run_id }}", None);
+		assert_eq!(http.render_target(&context), "http://localhost/manual__1");
+		let sql = sensor(SensorKind::Sql, "SELECT 1 FROM {{ conf.table }}", None);
+		assert_eq!(sql.render_target(&context), "SELECT 1 FROM {{ conf.table }}");
+	}
+
+	#[tokio::test]
+	async fn http_sensors_wait_for_200() {
+		let url = http_server();
+		let http = sensor(SensorKind::Http, "", None);
+
+		assert!(http.poke(&unreachable_db(), &format!("{}/ready", url)).await);
+		assert!(!http.poke(&unreachable_db(), &format!("{}/missing", url)).await);
+		assert!(!http.poke(&unreachable_db(), "not a url").await);
+	}
+
+	#[tokio::test]
+	async fn http_pokes_report_in_the_background() {
+		let url = http_server();
+		let http = sensor(SensorKind::Http, "", None);
+
+		assert_eq!(http.spawn_http_poke(format!("{}/ready", url)).await, Ok(true));
+		assert_eq!(http.spawn_http_poke(format!("{}/missing", url)).await, Ok(false));
+	}
+
+	#[test]
+	fn pokes_are_due_after_the_poke_interval() {
+		let last_poke = Utc.ymd(2020, 6, 1).and_hms(12, 0, 0);
+		let file = sensor(SensorKind::File, "", None);
+
+		assert!(!file.is_due(last_poke, last_poke + Duration::seconds(59)));
+		assert!(file.is_due(last_poke, last_poke + Duration::seconds(60)));
+	}
+
+	#[test]
+	fn sensors_time_out_after_waiting() {
+		let started_at = Utc.ymd(2020, 6, 1).and_hms(12, 0, 0);
+		let file = sensor(SensorKind::File, "", Some(Duration::minutes(10)));
+
+		assert_eq!(file.state(false, started_at, started_at + Duration::minutes(10)), SensorState::Waiting);
+		assert_eq!(file.state(false, started_at, started_at + Duration::minutes(11)), SensorState::TimedOut);
+		assert_eq!(file.state(true, started_at, started_at + Duration::minutes(11)), SensorState::Success);
+		let forever = sensor(SensorKind::File, "", None);
+		assert_eq!(forever.state(false, started_at, started_at + Duration::days(365)), SensorState::Waiting);
+	}
+}
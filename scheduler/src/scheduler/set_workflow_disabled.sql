@@ -0,0 +1,2 @@
+INSERT INTO workflow_setting (workflow_id, is_disabled) VALUES ($1, $2)
+ON CONFLICT (workflow_id) DO UPDATE SET is_disabled = $2;
\ No newline at end of file
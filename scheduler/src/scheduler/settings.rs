@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+
+use super::dependency::Dependency;
+use super::notification::NotificationHook;
+use super::sensor::Sensor;
+use super::sla::Sla;
+
+/// Decides which missed schedule occurrences become workflow instances.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchupPolicy {
+	/// Every missed occurrence is run.
+	All,
+	/// Only the most recent missed occurrence is run.
+	LatestOnly,
+	/// Missed occurrences are dropped.
+	None,
+}
+
+impl CatchupPolicy {
+	pub fn from_str(policy: &str) -> CatchupPolicy {
+		match policy {
+			"latest_only" => CatchupPolicy::LatestOnly,
+			"none" => CatchupPolicy::None,
+			"all" => CatchupPolicy::All,
+			_ => {
+				warn!("Unknown catchup policy '{}'. Falling back to 'all'", policy);
+				CatchupPolicy::All
+			}
+		}
+	}
+}
+
+/// Decides what happens to a schedule occurrence which is due while the previous scheduled run is still active.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverlapPolicy {
+	/// Runs overlap, as far as max_active_runs allows.
+	Allow,
+	/// The occurrence is recorded as skipped.
+	Skip,
+	/// The active runs are cancelled and the occurrence is run.
+	CancelPrevious,
+	/// The occurrence waits until the previous run finished.
+	Queue,
+}
+
+impl OverlapPolicy {
+	pub fn from_str(policy: &str) -> OverlapPolicy {
+		match policy {
+			"skip" => OverlapPolicy::Skip,
+			"cancel_previous" => OverlapPolicy::CancelPrevious,
+			"queue" => OverlapPolicy::Queue,
+			"allow" => OverlapPolicy::Allow,
+			_ => {
+				warn!("Unknown overlap policy '{}'. Falling back to 'allow'", policy);
+				OverlapPolicy::Allow
+			}
+		}
+	}
+}
+
+/// Scheduler specific settings of a workflow, which are not part of the OpenWorkflow definition.
+/// Stored in the `workflow_setting` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkflowSettings {
+	pub start_date: Option<DateTime<Utc>>,
+	pub end_date: Option<DateTime<Utc>>,
+	pub catchup: CatchupPolicy,
+	pub overlap: OverlapPolicy,
+	/// IANA timezone in which the schedule is evaluated.
+	pub timezone: Tz,
+	/// Local dates on which no scheduled occurrences are run, collected from the exclusion calendars.
+	pub excluded_dates: Vec<NaiveDate>,
+	/// A paused workflow does not create new instances, but lets running ones finish.
+	pub is_paused: bool,
+	/// Occurrences at or before this date are never scheduled. Set when unpausing without catchup.
+	pub skip_until: Option<DateTime<Utc>>,
+	/// Maximum number of tasks running at the same time across all instances. `None` means unlimited.
+	pub max_active_tasks: Option<usize>,
+	/// Priority per task_id. Among ready tasks of equally old instances, higher priorities are dispatched first.
+	/// Tasks without an entry have priority 0.
+	pub task_priorities: HashMap<String, i64>,
+	/// How long an instance may run before it is failed and its tasks are cancelled. `None` runs forever.
+	pub run_timeout: Option<Duration>,
+	/// Dependencies on other workflows. Harvested from `workflow_dependency` separately.
+	pub dependencies: Vec<Dependency>,
+	/// Tasks which are evaluated as sensors. Harvested from `task_sensor` separately.
+	pub sensors: Vec<Sensor>,
+	/// Harvested from `notification_hook` separately.
+	pub notification_hooks: Vec<NotificationHook>,
+	/// Harvested from `sla` separately.
+	pub slas: Vec<Sla>,
+}
+
+impl Default for WorkflowSettings {
+	fn default() -> Self {
+		WorkflowSettings {
+			start_date: None,
+			end_date: None,
+			catchup: CatchupPolicy::All,
+			overlap: OverlapPolicy::Allow,
+			timezone: Tz::UTC,
+			excluded_dates: Vec::new(),
+			is_paused: false,
+			skip_until: None,
+			max_active_tasks: None,
+			task_priorities: HashMap::new(),
+			run_timeout: None,
+			dependencies: Vec::new(),
+			sensors: Vec::new(),
+			notification_hooks: Vec::new(),
+			slas: Vec::new(),
+		}
+	}
+}
+
+impl WorkflowSettings {
+	/// Reads the settings from a harvested row.
+	/// Workflows without an entry in `workflow_setting` get the defaults.
+	pub fn from_row(row: &tokio_postgres::Row) -> WorkflowSettings {
+		let catchup: Option<&str> = row.get("catchup");
+		let overlap: Option<&str> = row.get("overlap_policy");
+		let timezone: Option<&str> = row.get("timezone");
+		let max_active_tasks: Option<i32> = row.get("max_active_tasks");
+		let task_priorities: Option<serde_json::Value> = row.get("task_priorities");
+		let run_timeout_sec: Option<i32> = row.get("run_timeout_sec");
+		WorkflowSettings {
+			start_date: row.get("start_date"),
+			end_date: row.get("end_date"),
+			catchup: catchup.map(CatchupPolicy::from_str).unwrap_or(CatchupPolicy::All),
+			overlap: overlap.map(OverlapPolicy::from_str).unwrap_or(OverlapPolicy::Allow),
+			timezone: timezone.map(parse_timezone).unwrap_or(Tz::UTC),
+			excluded_dates: row.get("excluded_dates"),
+			is_paused: row.get::<_, Option<bool>>("is_paused").unwrap_or(false),
+			skip_until: row.get("skip_until"),
+			max_active_tasks: max_active_tasks.filter(|m| *m > 0).map(|m| m as usize),
+			task_priorities: task_priorities.map(parse_task_priorities).unwrap_or_default(),
+			run_timeout: run_timeout_sec.filter(|t| *t > 0).map(|t| Duration::seconds(t as i64)),
+			dependencies: Vec::new(),
+			sensors: Vec::new(),
+			notification_hooks: Vec::new(),
+			slas: Vec::new(),
+		}
+	}
+
+	pub fn task_priority(&self, task_id: &str) -> i64 {
+		self.task_priorities.get(task_id).copied().unwrap_or(0)
+	}
+}
+
+/// Reads `{"task_id": priority, ...}`. Entries without an integer priority are ignored.
+fn parse_task_priorities(priorities: serde_json::Value) -> HashMap<String, i64> {
+	match priorities {
+		serde_json::Value::Object(priorities) => priorities
+			.into_iter()
+			.filter_map(|(task_id, priority)| match priority.as_i64() {
+				Some(priority) => Some((task_id, priority)),
+				None => {
+					warn!("Priority of task '{}' is not an integer: {}", task_id, priority);
+					None
+				}
+			})
+			.collect(),
+		priorities => {
+			warn!("task_priorities has to be an object, got {}", priorities);
+			HashMap::new()
+		}
+	}
+}
+
+fn parse_timezone(timezone: &str) -> Tz {
+	match timezone.parse() {
+		Ok(tz) => tz,
+		Err(e) => {
+			warn!("Unknown timezone '{}': {}. Falling back to UTC", timezone, e);
+			Tz::UTC
+		}
+	}
+}
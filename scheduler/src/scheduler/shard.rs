@@ -0,0 +1,129 @@
+//! Sharding of workflows across multiple active schedulers.
+//!
+//! Workflows are hashed into a fixed number of shards. Every live member leases its fair share of shards in the
+//! `scheduler_shard` table and only schedules the workflows of shards it holds a lease on.
+//! A member hands a shard back by draining it first: it keeps the lease, but stops creating runs and dispatching
+//! tasks for its workflows. Once their in-flight tasks finished and their states are persisted, the shard is
+//! released. So a shard is never scheduled by two members and no task runs twice.
+//! A member which lost a lease, e.g. because it could not reach the DB in time, stops scheduling the shard right
+//! away and hangs up on its in-flight tasks. The new owner runs them again.
+//! Runs missed while a shard changes owners are caught up by the new owner, which restores its state from the DB.
+
+use std::collections::HashSet;
+
+use super::db::{Db, DbError};
+
+pub struct ShardMember {
+	member_id: String,
+	shard_count: i32,
+	lease_sec: f64,
+	owned: HashSet<i32>,
+	/// Shards which are handed back once their workflows have no tasks in flight. Their leases are still renewed.
+	draining: HashSet<i32>,
+}
+
+impl ShardMember {
+	pub fn new(member_id: String, shard_count: i32, lease_sec: u64) -> ShardMember {
+		ShardMember {
+			member_id,
+			shard_count,
+			lease_sec: lease_sec as f64,
+			owned: HashSet::new(),
+			draining: HashSet::new(),
+		}
+	}
+
+	pub fn get_shard_count(&self) -> i32 {
+		self.shard_count
+	}
+
+	pub fn owns(&self, shard: i32) -> bool {
+		self.owned.contains(&shard)
+	}
+
+	pub fn is_draining(&self, shard: i32) -> bool {
+		self.draining.contains(&shard)
+	}
+
+	pub fn draining(&self) -> Vec<i32> {
+		let mut draining: Vec<i32> = self.draining.iter().cloned().collect();
+		draining.sort();
+		draining
+	}
+
+	pub fn owned(&self) -> Vec<i32> {
+		let mut owned: Vec<i32> = self.owned.iter().cloned().collect();
+		owned.sort();
+		owned
+	}
+
+	/// Sends a heartbeat, renews the leases of owned and draining shards and computes the fair share of this member.
+	/// Shards this member holds too many of start draining. The caller has to stop creating runs and dispatching
+	/// tasks for them and hand them back via `release`, once their workflows have no tasks in flight.
+	pub async fn rebalance(&mut self, sql_client: &Db) -> Result<(), DbError> {
+		sql_client.execute(include_str!("init_shards.sql"), &[&self.shard_count]).await?;
+		sql_client.execute(include_str!("member_heartbeat.sql"), &[&self.member_id]).await?;
+		sql_client.execute(include_str!("expire_members.sql"), &[&self.lease_sec]).await?;
+
+		let members: i64 = sql_client.query_one(include_str!("live_members.sql"), &[]).await?.get("members");
+		let fair_share = ((self.shard_count as i64 + members - 1) / members.max(1)) as usize;
+
+		let renewed = sql_client.query(
+			include_str!("renew_shards.sql"), &[&self.member_id, &self.lease_sec, &self.shard_count]
+		).await?;
+		let renewed: HashSet<i32> = renewed.iter().map(|row| row.get("shard")).collect();
+		for lost in self.owned.union(&self.draining).filter(|shard| !renewed.contains(*shard)) {
+			warn!("Lost the lease on shard {}", lost);
+		}
+		self.draining = self.draining.intersection(&renewed).cloned().collect();
+		self.owned = renewed.difference(&self.draining).cloned().collect();
+
+		if self.owned.len() > fair_share {
+			let mut owned: Vec<i32> = self.owned.iter().cloned().collect();
+			owned.sort();
+			for shard in owned.split_off(fair_share) {
+				info!("Draining shard {}", shard);
+				self.owned.remove(&shard);
+				self.draining.insert(shard);
+			}
+			return Ok(());
+		}
+
+		if self.owned.len() < fair_share {
+			let wanted = (fair_share - self.owned.len()) as i64;
+			let claimed = sql_client.query(
+				include_str!("claim_shards.sql"),
+				&[&self.member_id, &self.lease_sec, &self.shard_count, &wanted]
+			).await?;
+			for row in claimed {
+				let shard: i32 = row.get("shard");
+				info!("Claimed shard {}", shard);
+				self.owned.insert(shard);
+			}
+		}
+		trace!("Member '{}' owns {} of {} shards", self.member_id, self.owned.len(), self.shard_count);
+		Ok(())
+	}
+
+	/// Hands drained shards back, after their workflows were dropped from scheduling.
+	pub async fn release(&mut self, sql_client: &Db, shards: &[i32]) -> Result<(), DbError> {
+		if shards.is_empty() {
+			return Ok(());
+		}
+		info!("Releasing shards {:?}", shards);
+		sql_client.execute(include_str!("release_shards.sql"), &[&self.member_id, &shards]).await?;
+		for shard in shards {
+			self.draining.remove(shard);
+		}
+		Ok(())
+	}
+
+	/// Releases all owned shards and leaves the cluster, so the other members can take over right away.
+	pub async fn leave(&mut self, sql_client: &Db) -> Result<(), DbError> {
+		info!("Leaving the scheduler cluster as '{}'", self.member_id);
+		sql_client.execute(include_str!("leave_cluster.sql"), &[&self.member_id]).await?;
+		self.owned.clear();
+		self.draining.clear();
+		Ok(())
+	}
+}
@@ -0,0 +1,3 @@
+INSERT INTO workflow_instance (workflow_id, wid, run_id, run_type, run_state, run_date, timezone, reason)
+VALUES ($1, $2, $3, 'scheduled', 'skipped', $4, $5, $6)
+ON CONFLICT (workflow_id, run_id) DO NOTHING;
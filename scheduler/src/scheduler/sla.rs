@@ -0,0 +1,206 @@
+//! Service level agreements of runs and tasks, configured in the `sla` table.
+//!
+//! An SLA without a task_id applies to the runs of a workflow, one with a task_id to that task. It is missed if
+//! the run or task did not finish, in whatever state, by its deadline, which is
+//! - `deadline_offset` after the run_date, or
+//! - `max_duration` after it started running. Time spent waiting for retries counts.
+//!
+//! Deadlines relative to the run_date also apply to due occurrences and triggered runs which did not become
+//! instances yet, e.g. because max_active_runs is exhausted, so a growing backlog is noticed before the runs start.
+//! Only the oldest runs of a backlog are checked on each tick.
+//! SLAs are evaluated on every tick, so misses are detected at most one loop interval late.
+//! Every miss is recorded once in the `sla_miss` table and sent to the `sla_miss` notification hooks.
+
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+
+use super::db::{Db, DbError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlaKind {
+	/// Not finished `deadline_offset` after the run_date
+	Deadline,
+	/// Not finished `max_duration` after it started
+	MaxDuration,
+}
+
+impl SlaKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			SlaKind::Deadline => "deadline",
+			SlaKind::MaxDuration => "max_duration",
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sla {
+	/// Set if the SLA applies to a single task instead of the whole run
+	pub task_id: Option<String>,
+	pub deadline_offset: Option<Duration>,
+	pub max_duration: Option<Duration>,
+}
+
+/// When a run or task started and finished. `None` if it did not yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+	pub started_at: Option<DateTime<Utc>>,
+	pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlaMiss {
+	pub kind: SlaKind,
+	pub run_id: String,
+	pub run_date: DateTime<Utc>,
+	pub task_id: Option<String>,
+	/// When the run or task should have finished
+	pub due_at: DateTime<Utc>,
+}
+
+impl Sla {
+	pub fn from_row(row: &tokio_postgres::Row) -> Option<Sla> {
+		let deadline_offset_sec: Option<i64> = row.get("deadline_offset_sec");
+		let max_duration_sec: Option<i64> = row.get("max_duration_sec");
+		if deadline_offset_sec.is_none() && max_duration_sec.is_none() {
+			let workflow_id: &str = row.get("workflow_id");
+			warn!("SLA of workflow '{}' has neither a deadline_offset nor a max_duration", workflow_id);
+			return None;
+		}
+		Some(Sla {
+			task_id: row.get("task_id"),
+			deadline_offset: deadline_offset_sec.map(Duration::seconds),
+			max_duration: max_duration_sec.map(Duration::seconds),
+		})
+	}
+
+	/// Returns the parts of the SLA the run `run_id` or its task missed at `now`.
+	pub fn check(
+		&self,
+		run_id: &str,
+		run_date: DateTime<Utc>,
+		progress: &Progress,
+		now: DateTime<Utc>
+	) -> Vec<SlaMiss> {
+		let finished_at = progress.finished_at.unwrap_or(now);
+		let deadline = self.deadline_offset.map(|offset| (SlaKind::Deadline, run_date + offset));
+		let max_duration = match (self.max_duration, progress.started_at) {
+			(Some(max_duration), Some(started_at)) => Some((SlaKind::MaxDuration, started_at + max_duration)),
+			_ => None,
+		};
+		deadline
+			.into_iter()
+			.chain(max_duration)
+			.filter(|(_, due_at)| finished_at > *due_at)
+			.map(|(kind, due_at)| SlaMiss {
+				kind,
+				run_id: run_id.to_string(),
+				run_date,
+				task_id: self.task_id.clone(),
+				due_at,
+			})
+			.collect()
+	}
+}
+
+impl SlaMiss {
+	/// Describes the miss, with the due time in `timezone`.
+	pub fn message(&self, timezone: Tz) -> String {
+		let subject = match &self.task_id {
+			Some(task_id) => format!("Task '{}'", task_id),
+			None => "Run".to_string(),
+		};
+		let due_at = self.due_at.with_timezone(&timezone).to_rfc3339();
+		match self.kind {
+			SlaKind::Deadline => format!("{} missed its deadline of {}", subject, due_at),
+			SlaKind::MaxDuration => format!("{} exceeded its max duration at {}", subject, due_at),
+		}
+	}
+
+	/// Persists the miss. Returns false if it was recorded before, e.g. by the scheduler which owned the workflow
+	/// previously.
+	pub async fn record(&self, sql_client: &Db, workflow_id: &str) -> Result<bool, DbError> {
+		let row = sql_client.query_opt(
+			include_str!("record_sla_miss.sql"),
+			&[&workflow_id, &self.run_id, &self.run_date, &self.task_id, &self.kind.as_str(), &self.due_at]
+		).await?;
+		Ok(row.is_some())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn utc(date: &str) -> DateTime<Utc> {
+		date.parse().unwrap()
+	}
+
+	fn sla(deadline_offset: Option<Duration>, max_duration: Option<Duration>) -> Sla {
+		Sla { task_id: None, deadline_offset, max_duration }
+	}
+
+	fn progress(started_at: Option<&str>, finished_at: Option<&str>) -> Progress {
+		Progress { started_at: started_at.map(utc), finished_at: finished_at.map(utc) }
+	}
+
+	/// The kinds of the misses of `sla` at `now`, for a run of 2020-06-01T00:00:00Z.
+	fn missed(sla: &Sla, progress: &Progress, now: &str) -> Vec<SlaKind> {
+		sla.check("r1", utc("2020-06-01T00:00:00Z"), progress, utc(now)).iter().map(|m| m.kind).collect()
+	}
+
+	#[test]
+	fn deadlines_are_relative_to_the_run_date() {
+		let sla = sla(Some(Duration::hours(1)), None);
+		let not_started = progress(None, None);
+		assert_eq!(missed(&sla, &not_started, "2020-06-01T01:00:00Z"), vec![]);
+		// Runs which did not start miss their deadline as well
+		assert_eq!(missed(&sla, &not_started, "2020-06-01T01:00:01Z"), vec![SlaKind::Deadline]);
+
+		let finished_in_time = progress(Some("2020-06-01T00:10:00Z"), Some("2020-06-01T00:50:00Z"));
+		assert_eq!(missed(&sla, &finished_in_time, "2020-06-01T05:00:00Z"), vec![]);
+		let finished_late = progress(Some("2020-06-01T00:10:00Z"), Some("2020-06-01T01:30:00Z"));
+		assert_eq!(missed(&sla, &finished_late, "2020-06-01T05:00:00Z"), vec![SlaKind::Deadline]);
+	}
+
+	#[test]
+	fn max_durations_are_relative_to_the_start() {
+		let sla = sla(None, Some(Duration::minutes(30)));
+		assert_eq!(missed(&sla, &progress(None, None), "2020-06-02T00:00:00Z"), vec![]);
+
+		let running = progress(Some("2020-06-01T02:00:00Z"), None);
+		assert_eq!(missed(&sla, &running, "2020-06-01T02:30:00Z"), vec![]);
+		assert_eq!(missed(&sla, &running, "2020-06-01T02:31:00Z"), vec![SlaKind::MaxDuration]);
+
+		let finished_late = progress(Some("2020-06-01T02:00:00Z"), Some("2020-06-01T02:45:00Z"));
+		assert_eq!(missed(&sla, &finished_late, "2020-06-01T03:00:00Z"), vec![SlaKind::MaxDuration]);
+	}
+
+	#[test]
+	fn both_parts_are_checked() {
+		let sla = Sla {
+			task_id: Some("load".to_string()),
+			deadline_offset: Some(Duration::hours(1)),
+			max_duration: Some(Duration::minutes(30)),
+		};
+		let running = progress(Some("2020-06-01T00:45:00Z"), None);
+		let misses = sla.check("r1", utc("2020-06-01T00:00:00Z"), &running, utc("2020-06-01T01:20:00Z"));
+		assert_eq!(misses, vec![
+			SlaMiss {
+				kind: SlaKind::Deadline,
+				run_id: "r1".to_string(),
+				run_date: utc("2020-06-01T00:00:00Z"),
+				task_id: Some("load".to_string()),
+				due_at: utc("2020-06-01T01:00:00Z"),
+			},
+			SlaMiss {
+				kind: SlaKind::MaxDuration,
+				run_id: "r1".to_string(),
+				run_date: utc("2020-06-01T00:00:00Z"),
+				task_id: Some("load".to_string()),
+				due_at: utc("2020-06-01T01:15:00Z"),
+			},
+		]);
+	}
+}
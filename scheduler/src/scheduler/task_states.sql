@@ -0,0 +1 @@
+SELECT task_id, state FROM task_instance WHERE wiid = $1;
\ No newline at end of file
@@ -0,0 +1,113 @@
+use chrono::prelude::*;
+use chrono_tz::Tz;
+
+use flowty_types::openworkflow::{execution, Task};
+
+/// Values available to templates in task definitions.
+///
+/// Supported placeholders:
+/// - `{{ run_id }}`
+/// - `{{ run_date }}`: run_date in the workflow's timezone, RFC 3339 formatted
+/// - `{{ ds }}`: local date of the run_date, `YYYY-MM-DD`
+/// - `{{ conf.<key> }}`: value of `<key>` in the config payload of a triggered run
+///
+/// Unknown placeholders are left untouched.
+pub struct TemplateContext<'a> {
+	pub run_id: &'a str,
+	pub run_date: DateTime<Tz>,
+	pub conf: &'a serde_json::Value,
+}
+
+impl<'a> TemplateContext<'a> {
+	fn lookup(&self, key: &str) -> Option<String> {
+		match key {
+			"run_id" => Some(self.run_id.to_string()),
+			"run_date" => Some(self.run_date.to_rfc3339()),
+			"ds" => Some(self.run_date.format("%Y-%m-%d").to_string()),
+			k if k.starts_with("conf.") => {
+				self.conf.pointer(&format!("/{}", k["conf.".len()..].replace('.', "/"))).map(|v| match v {
+					serde_json::Value::String(s) => s.clone(),
+					v => v.to_string(),
+				})
+			},
+			_ => None,
+		}
+	}
+
+	pub fn render(&self, template: &str) -> String {
+		render(template, |key| self.lookup(key))
+	}
+
+	/// Returns a copy of the task with all templates in its execution rendered.
+	pub fn render_task(&self, task: &Task) -> Task {
+		let mut task = task.clone();
+		if let Some(execution) = task.execution.as_mut() {
+			if let Some(execution::Exec::Local(local)) = execution.exec.as_mut() {
+				local.command = self.render(&local.command);
+			}
+		}
+		task
+	}
+}
+
+/// Replaces every `{{ key }}` in `template` with the value `lookup` returns for it.
+/// Placeholders without a value are left untouched.
+pub fn render<F: Fn(&str) -> Option<String>>(template: &str, lookup: F) -> String {
+	let mut rendered = String::with_capacity(template.len());
+	let mut rest = template;
+	while let Some(start) = rest.find("{{") {
+		let end = match rest[start..].find("}}") {
+			Some(end) => start + end,
+			None => break,
+		};
+		rendered.push_str(&rest[..start]);
+		let key = rest[start + 2..end].trim();
+		match lookup(key) {
+			Some(value) => rendered.push_str(&value),
+			None => rendered.push_str(&rest[start..end + 2]),
+		}
+		rest = &rest[end + 2..];
+	}
+	rendered.push_str(rest);
+	rendered
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn render_with(template: &str, conf: serde_json::Value) -> String {
+		let run_date = Tz::Europe__Berlin.ymd(2020, 6, 1).and_hms(1, 30, 0);
+		let context = TemplateContext { run_id: "manual__1", run_date, conf: &conf };
+		context.render(template)
+	}
+
+	#[test]
+	fn placeholders_are_rendered() {
+		let conf = serde_json::json!({});
+		assert_eq!(render_with("{{ run_id }}", conf.clone()), "manual__1");
+		assert_eq!(render_with("{{run_id}}", conf.clone()), "manual__1");
+		assert_eq!(render_with("{{ run_date }}", conf.clone()), "2020-06-01T01:30:00+02:00");
+		// The local date, the UTC one is the day before
+		assert_eq!(render_with("load --date {{ ds }} --id {{ run_id }}", conf), "load --date 2020-06-01 --id manual__1");
+	}
+
+	#[test]
+	fn conf_values_are_rendered() {
+		let conf = serde_json::json!({"table": "sales", "limits": {"rows": 10}, "dry_run": true});
+		assert_eq!(render_with("{{ conf.table }}", conf.clone()), "sales");
+		assert_eq!(render_with("{{ conf.limits.rows }}", conf.clone()), "10");
+		assert_eq!(render_with("{{ conf.dry_run }}", conf.clone()), "true");
+		assert_eq!(render_with("{{ conf.limits }}", conf), "{\"rows\":10}");
+	}
+
+	#[test]
+	fn unknown_placeholders_are_kept() {
+		let conf = serde_json::json!({"table": "sales"});
+		assert_eq!(render_with("{{ conf.missing }}", conf.clone()), "{{ conf.missing }}");
+		assert_eq!(render_with("{{ unknown }} {{ run_id }}", conf.clone()), "{{ unknown }} manual__1");
+		assert_eq!(render_with("echo {{ run_id", conf.clone()), "echo {{ run_id");
+		assert_eq!(render_with("echo }} {{", conf.clone()), "echo }} {{");
+		assert_eq!(render_with("no templates", conf), "no templates");
+	}
+}
@@ -0,0 +1,13 @@
+-- The run is pinned to a workflow version when the scheduler picks it up: only the scheduler knows which versions
+-- are valid, the newest one may have been rejected.
+INSERT INTO workflow_instance (workflow_id, run_id, run_type, run_date, timezone, conf)
+SELECT
+	$1,
+	$2,
+	'manual',
+	$3,
+	COALESCE((SELECT timezone FROM workflow_setting WHERE workflow_id = $1), 'UTC'),
+	$4
+WHERE EXISTS (SELECT 1 FROM workflow WHERE workflow_id = $1)
+ON CONFLICT (workflow_id, run_id) DO NOTHING
+RETURNING wiid;
\ No newline at end of file
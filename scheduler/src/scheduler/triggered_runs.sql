@@ -0,0 +1,5 @@
+SELECT wiid, wid, run_id, run_type, run_state::TEXT AS run_state, run_date, timezone, conf, running_since
+FROM workflow_instance
+WHERE workflow_id = $1 AND run_type = 'manual' AND run_state = 'nothing'
+ORDER BY created_at, wiid
+LIMIT $2;
\ No newline at end of file
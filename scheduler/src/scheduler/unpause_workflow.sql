@@ -0,0 +1,2 @@
+INSERT INTO workflow_setting (workflow_id, is_paused, skip_until) VALUES ($1, FALSE, $2)
+ON CONFLICT (workflow_id) DO UPDATE SET is_paused = FALSE, skip_until = COALESCE($2, workflow_setting.skip_until);
\ No newline at end of file
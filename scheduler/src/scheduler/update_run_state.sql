@@ -1 +1,16 @@
-UPDATE workflow_instance SET run_state = $2 WHERE wiid = $1;
\ No newline at end of file
+UPDATE workflow_instance SET
+	-- A run which finished while a cancel was requested is cancelled, so the row does not stay cancelling
+	run_state = (CASE
+		WHEN run_state = 'cancelling' AND $2::TEXT IN ('success', 'failed', 'timed_out', 'skipped') THEN 'cancelled'
+		ELSE $2::TEXT
+	END)::runstate,
+	reason = CASE WHEN run_state = 'cancelling' AND $2::TEXT <> 'cancelled' THEN reason ELSE $3 END,
+	-- Run timeouts are measured from the first time the instance started running
+	running_since = CASE WHEN $2::TEXT = 'running' THEN COALESCE(running_since, NOW()) ELSE running_since END
+WHERE wiid = $1
+	-- Other states do not overwrite a cancel requested in the meantime. The scheduler picks it up on its next tick
+	AND (
+		run_state <> 'cancelling'
+		OR $2::TEXT IN ('cancelling', 'cancelled', 'success', 'failed', 'timed_out', 'skipped')
+	)
+RETURNING run_state::TEXT AS run_state;
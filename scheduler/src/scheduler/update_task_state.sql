@@ -0,0 +1,2 @@
+INSERT INTO task_instance (wiid, task_id, state) VALUES ($1, $2, $3)
+ON CONFLICT (wiid, task_id) DO UPDATE SET state = EXCLUDED.state;
@@ -0,0 +1,14 @@
+SELECT EXISTS (
+	SELECT 1 FROM workflow_instance
+	WHERE workflow_instance.workflow_id = $1
+	AND workflow_instance.run_date = $2
+	AND (
+		($3::TEXT IS NULL AND workflow_instance.run_state = 'success')
+		OR EXISTS (
+			SELECT 1 FROM task_instance
+			WHERE task_instance.wiid = workflow_instance.wiid
+			AND task_instance.task_id = $3
+			AND task_instance.state = 'success'
+		)
+	)
+) AS succeeded;
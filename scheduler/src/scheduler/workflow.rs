@@ -1,93 +1,918 @@
-extern crate cron;
-
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
-use cron::Schedule;
 use chrono::prelude::*;
+use chrono::Duration;
 use tokio::task::JoinHandle;
 
 use flowty_types::openworkflow;
-use super::workflow_instance::{WorkflowInstance, RunState};
+use flowty_types::openworkflow::Task;
+use flowty_types::FlowtyError;
+use super::db::Db;
+use super::dispatch::Dispatcher;
+use super::event;
+use super::notification::{Notification, NotificationEvent, Notifier};
+use super::schedule::Schedule;
+use super::settings::{WorkflowSettings, CatchupPolicy, OverlapPolicy};
+use super::sla::{Progress, SlaKind, SlaMiss};
+use super::workflow_instance::{scheduled_run_id, RunState, RunType, WorkflowInstance};
+
+/// Due occurrences and waiting triggered runs checked against SLAs per tick, oldest first. They missed their
+/// deadlines first, the newer ones are checked once the backlog shrinks.
+const SLA_BACKLOG_LIMIT: usize = 100;
 
 pub struct Workflow {
+	/// Version of the workflow definition which was harvested last
+	wid: i32,
 	pub workflow: openworkflow::Workflow,
+	settings: WorkflowSettings,
 	schedule: Schedule,
 	last_tick: Option<DateTime<Utc>>,
+	/// Schedule occurrences up to and including this date have been handled.
+	/// `None` means the workflow starts at its start_date.
+	scheduled_until: Option<DateTime<Utc>>,
 	workflow_instances: Vec<WorkflowInstance>,
+	/// The workflow was deleted or disabled. Active instances finish, no new ones are created.
+	retired: bool,
+	/// Its shard is handed to another scheduler. No instances are created and no tasks dispatched, the ones in
+	/// flight finish.
+	draining: bool,
+	/// Tasks of older workflow versions which restored instances were created from
+	versions: HashMap<i32, Vec<Task>>,
+	/// SLA misses recorded already, identified by run_id, task_id and kind. Only runs still checked are kept.
+	sla_misses: HashSet<(String, Option<String>, SlaKind)>,
 }
 
 impl Workflow {
-	pub fn new(workflow: openworkflow::Workflow) -> Workflow {
-		let schedule = Schedule::from_str(workflow.schedule.as_str().clone()).unwrap();
-		Workflow {workflow, schedule, last_tick: None, workflow_instances: Vec::new()}
+	pub fn new(wid: i32, workflow: openworkflow::Workflow, settings: WorkflowSettings) -> Result<Workflow, FlowtyError> {
+		let schedule = Schedule::from_str(&workflow.schedule)?;
+		Ok(Workflow {
+			wid,
+			workflow,
+			settings,
+			schedule,
+			last_tick: None,
+			scheduled_until: None,
+			workflow_instances: Vec::new(),
+			retired: false,
+			draining: false,
+			versions: HashMap::new(),
+			sla_misses: HashSet::new(),
+		})
 	}
 
-	pub fn update_workflow(&mut self, openworkflow: openworkflow::Workflow, reset_tick: bool) {
-		if openworkflow == self.workflow {
+	pub fn get_wid(&self) -> i32 {
+		self.wid
+	}
+
+	pub fn is_retired(&self) -> bool {
+		self.retired
+	}
+
+	/// Stops creating instances. The workflow can be dropped once `has_active_instances` turns false.
+	pub fn retire(&mut self) {
+		if !self.retired {
+			info!("Retiring workflow '{}'. Active instances finish, no new ones are created", self.workflow.workflow_id);
+			self.retired = true;
+		}
+	}
+
+	/// Stops creating instances and dispatching tasks, so the workflow can be handed to another scheduler once
+	/// `running_tasks` dropped to 0.
+	pub fn drain(&mut self) {
+		if !self.draining {
+			info!("Draining workflow '{}'. Tasks in flight finish, no new ones are dispatched", self.workflow.workflow_id);
+			self.draining = true;
+		}
+	}
+
+	/// Tasks dispatched by the instances which did not report a final status yet.
+	pub fn running_tasks(&self) -> usize {
+		self.workflow_instances.iter().map(|i| i.running_tasks()).sum()
+	}
+
+	/// Resumes a retired workflow, e.g. when it was enabled again before its last instances finished.
+	pub fn reinstate(&mut self) {
+		if self.retired {
+			info!("Reinstating workflow '{}'", self.workflow.workflow_id);
+			self.retired = false;
+		}
+	}
+
+	/// Returns the tasks of workflow version `wid`. Instances without a version use the current one.
+	/// Older versions are fetched from the DB once.
+	async fn tasks_of(&mut self, sql_client: &Db, wid: Option<i32>) -> Option<Vec<Task>> {
+		let wid = match wid {
+			Some(wid) if wid != self.wid => wid,
+			_ => return Some(self.workflow.tasks.clone()),
+		};
+		if let Some(tasks) = self.versions.get(&wid) {
+			return Some(tasks.clone());
+		}
+		let row = match sql_client.query_opt(include_str!("workflow_version.sql"), &[&self.workflow.workflow_id, &wid]).await {
+			Ok(Some(row)) => row,
+			Ok(None) => {
+				error!("Version {} of workflow '{}' does not exist anymore", wid, self.workflow.workflow_id);
+				return None;
+			},
+			Err(e) => {
+				error!("Failed to fetch version {} of workflow '{}':\n{}", wid, self.workflow.workflow_id, e);
+				return None;
+			}
+		};
+		match flowty_types::openworkflow_from_binary(row.get("openworkflow_message")) {
+			Ok(workflow) => {
+				trace!("Loaded version {} of workflow '{}'", wid, self.workflow.workflow_id);
+				self.versions.insert(wid, workflow.tasks.clone());
+				Some(workflow.tasks)
+			},
+			Err(e) => {
+				error!("Failed to parse version {} of workflow '{}':\n{}", wid, self.workflow.workflow_id, e);
+				None
+			}
+		}
+	}
+
+	/// Rebuilds the scheduling state from the DB on the next tick, e.g. after the DB was unreachable.
+	/// Instances persisted but not known in memory are adopted, known ones keep their in-memory state.
+	pub fn reconcile(&mut self) {
+		self.last_tick = None;
+	}
+
+	pub fn has_active_instances(&self) -> bool {
+		self.workflow_instances.iter().any(|i| i.is_active())
+	}
+
+	/// Active scheduled runs are the previous runs the overlap policy applies to. Triggered runs are not.
+	fn has_active_scheduled_instances(&self) -> bool {
+		self.workflow_instances.iter().any(|i| i.holds_slot() && i.get_run_type() == RunType::Scheduled)
+	}
+
+	pub fn update_settings(&mut self, settings: WorkflowSettings) {
+		if settings == self.settings {
 			return;
 		}
+		info!("Updating settings of workflow '{}': {:?}", self.workflow.workflow_id, settings);
+		self.settings = settings;
+	}
+
+	/// Replaces the workflow definition with version `wid`.
+	/// An invalid schedule is rejected and the previous definition and wid are kept, so new instances keep running
+	/// on the version they are pinned to. The caller keeps track of rejected versions, so they are not harvested
+	/// again, only a newer one is.
+	///
+	/// Existing instances keep the tasks they were created with and finish on that version.
+	/// On a schedule change, the next occurrence is computed from the new schedule after the last real run_date,
+	/// which is restored from the DB on the next tick.
+	pub async fn update_workflow(
+		&mut self,
+		sql_client: &Db,
+		wid: i32,
+		openworkflow: openworkflow::Workflow,
+		reset_tick: bool
+	) -> Result<(), FlowtyError> {
+		if openworkflow == self.workflow {
+			self.wid = wid;
+			return Ok(());
+		}
 
 		if self.workflow.schedule != openworkflow.schedule {
-			warn!("Changing schedule. This is can cause undefined behaviour and is not recommend!");
-			// ToDo: Actually make it work nicely
-			self.schedule = Schedule::from_str(openworkflow.schedule.as_str().clone()).unwrap();
+			self.schedule = Schedule::from_str(&openworkflow.schedule)?;
+			event::record(
+				sql_client,
+				&self.workflow.workflow_id,
+				"schedule_changed",
+				&format!("Schedule changed from '{}' to '{}'", self.workflow.schedule, openworkflow.schedule)
+			).await;
+			self.last_tick = None;
 		}
+		self.wid = wid;
 		self.workflow = openworkflow;
 		if reset_tick {
 			self.last_tick = None;
 		}
+		Ok(())
 	}
 
-	pub async fn tick(&mut self, sql_client: &tokio_postgres::Client, now: DateTime<Utc>) {
+	pub async fn tick(&mut self, sql_client: &Db, dispatcher: &Dispatcher, notifier: &Notifier, now: DateTime<Utc>) {
 		if self.last_tick.is_none() {
 			trace!("First tick");
-			self.last_tick = Some(now);
-			return;
+			self.restore_instances(sql_client).await;
+			self.restore_scheduled_until(sql_client, now).await;
 		}
 
-		self.run_instances(sql_client).await;
-		self.queue_instances(sql_client, now).await;
+		self.cancel_requested_instances(sql_client).await;
+		self.run_instances(sql_client, dispatcher).await;
+		if self.draining {
+			trace!("Workflow '{}' is draining. Not creating new instances", self.workflow.workflow_id);
+		} else if self.retired {
+			trace!("Workflow '{}' is retired. Not creating new instances", self.workflow.workflow_id);
+		} else if self.settings.is_paused {
+			trace!("Workflow '{}' is paused. Not creating new instances", self.workflow.workflow_id);
+		} else {
+			self.queue_instances(sql_client, now).await;
+		}
 
+		self.check_slas(sql_client, notifier, now).await;
+		self.send_notifications(notifier);
+		if !self.has_active_instances() {
+			self.versions.clear();
+		}
 		self.last_tick = Some(now);
 	}
 
-	async fn run_instances(&mut self, sql_client: &tokio_postgres::Client) {
-		let queued_instances = self.workflow_instances
-			.iter_mut()
-			.filter(|i| matches!(i.get_run_state(), RunState::Queued));
+	/// Sends the notifications the instances recorded during the tick to the workflow's hooks.
+	fn send_notifications(&mut self, notifier: &Notifier) {
+		for instance in self.workflow_instances.iter_mut() {
+			for notification in instance.take_notifications() {
+				notifier.notify(&self.settings.notification_hooks, &notification);
+			}
+		}
+	}
+
+	/// Records and notifies the SLA misses of the instances which were active during the tick, and of the due
+	/// occurrences and triggered runs which did not become instances yet because of backlog.
+	async fn check_slas(&mut self, sql_client: &Db, notifier: &Notifier, now: DateTime<Utc>) {
+		if self.settings.slas.is_empty() {
+			self.sla_misses.clear();
+			return;
+		}
+		let last_tick = self.last_tick;
+		let mut misses: Vec<SlaMiss> = Vec::new();
+		let instances = self.workflow_instances.iter().filter(|i| {
+			i.is_active() || i.get_finished_at().map_or(false, |f| last_tick.map_or(true, |last_tick| f > last_tick))
+		});
+		let mut checked: HashSet<String> = HashSet::new();
+		for instance in instances {
+			checked.insert(instance.get_run_id().to_string());
+			for sla in &self.settings.slas {
+				if let Some(progress) = instance.progress(sla.task_id.as_deref()) {
+					misses.extend(sla.check(instance.get_run_id(), instance.get_run_date(), &progress, now));
+				}
+			}
+		}
+		if !self.retired && !self.settings.is_paused {
+			let not_started = Progress { started_at: None, finished_at: None };
+			for run_date in self.due_run_dates(now, SLA_BACKLOG_LIMIT) {
+				let run_id = scheduled_run_id(run_date);
+				for sla in &self.settings.slas {
+					misses.extend(sla.check(&run_id, run_date, &not_started, now));
+				}
+				checked.insert(run_id);
+			}
+		}
+		// Only deadlines can pass before a run started
+		if self.settings.slas.iter().any(|sla| sla.deadline_offset.is_some()) {
+			let not_started = Progress { started_at: None, finished_at: None };
+			for (run_id, run_date) in self.waiting_triggered_runs(sql_client).await {
+				if checked.contains(&run_id) {
+					continue;
+				}
+				for sla in &self.settings.slas {
+					misses.extend(sla.check(&run_id, run_date, &not_started, now));
+				}
+				checked.insert(run_id);
+			}
+		}
+		// Runs are checked a last time on the tick after they finished and forgotten afterwards. Should one be checked
+		// again, e.g. when it re-enters the capped backlog, the sla_miss table still deduplicates its misses.
+		self.sla_misses.retain(|(run_id, _, _)| checked.contains(run_id));
+
+		for miss in misses {
+			let key = (miss.run_id.clone(), miss.task_id.clone(), miss.kind);
+			if self.sla_misses.contains(&key) {
+				continue;
+			}
+			match miss.record(sql_client, &self.workflow.workflow_id).await {
+				Ok(is_new) => {
+					self.sla_misses.insert(key);
+					if !is_new {
+						continue;
+					}
+					let message = miss.message(self.settings.timezone);
+					event::record(
+						sql_client,
+						&self.workflow.workflow_id,
+						"sla_missed",
+						&format!("Run '{}': {}", miss.run_id, message)
+					).await;
+					notifier.notify(&self.settings.notification_hooks, &Notification {
+						event: NotificationEvent::SlaMiss,
+						workflow_id: self.workflow.workflow_id.clone(),
+						run_id: miss.run_id,
+						run_date: miss.run_date.with_timezone(&self.settings.timezone),
+						task_id: miss.task_id,
+						message,
+					});
+				},
+				Err(e) => error!("Failed to record SLA miss of run '{}' of '{}':\n{}", miss.run_id, self.workflow.workflow_id, e),
+			}
+		}
+	}
+
+	/// Returns the run_id and run_date of the oldest triggered runs which wait for a max_active_runs slot.
+	async fn waiting_triggered_runs(&self, sql_client: &Db) -> Vec<(String, DateTime<Utc>)> {
+		let result = sql_client.query(
+			include_str!("triggered_runs.sql"), &[&self.workflow.workflow_id, &(SLA_BACKLOG_LIMIT as i64)]
+		).await;
+		match result {
+			Ok(rows) => rows.iter().map(|row| (row.get("run_id"), row.get("run_date"))).collect(),
+			Err(e) => {
+				error!("Failed to retrieve waiting triggered runs of '{}':\n{}", self.workflow.workflow_id, e);
+				Vec::new()
+			}
+		}
+	}
+
+	/// Adopts the active instances persisted in the DB, e.g. after taking over from another scheduler.
+	async fn restore_instances(&mut self, sql_client: &Db) {
+		let rows = match sql_client.query(include_str!("active_runs.sql"), &[&self.workflow.workflow_id]).await {
+			Ok(rows) => rows,
+			Err(e) => {
+				error!("Failed to restore active runs of '{}':\n{}", self.workflow.workflow_id, e);
+				return;
+			}
+		};
+		for row in rows {
+			let wiid: i32 = row.get("wiid");
+			if self.workflow_instances.iter().any(|i| i.get_wiid() == wiid) {
+				continue;
+			}
+			let tasks = match self.tasks_of(sql_client, row.get("wid")).await {
+				Some(tasks) => tasks,
+				None => continue,
+			};
+			match WorkflowInstance::from_row(&row, &self.workflow.workflow_id, &tasks) {
+				Ok(mut wi) => {
+					info!("Restoring active run of '{}' for {}", self.workflow.workflow_id, wi.local_run_date());
+					wi.restore_task_states(sql_client).await;
+					self.workflow_instances.push(wi);
+				},
+				Err(e) => error!("Failed to restore run of '{}': {}", self.workflow.workflow_id, e),
+			}
+		}
+	}
+
+	/// Starts cancelling the active instances for which a cancel was requested in the DB.
+	async fn cancel_requested_instances(&mut self, sql_client: &Db) {
+		if !self.has_active_instances() {
+			return;
+		}
+		let rows = match sql_client.query(include_str!("cancel_requests.sql"), &[&self.workflow.workflow_id]).await {
+			Ok(rows) => rows,
+			Err(e) => {
+				error!("Failed to retrieve cancel requests of '{}':\n{}", self.workflow.workflow_id, e);
+				return;
+			}
+		};
+		for row in rows {
+			let wiid: i32 = row.get("wiid");
+			if let Some(instance) = self.workflow_instances.iter_mut().find(|i| i.get_wiid() == wiid && i.is_active()) {
+				if let Err(e) = instance.cancel(sql_client).await {
+					error!("{}", e);
+				}
+			}
+		}
+	}
+
+	/// Picks up scheduling where the last persisted run left off, according to the catchup policy.
+	async fn restore_scheduled_until(&mut self, sql_client: &Db, now: DateTime<Utc>) {
+		let last_run_date: Option<DateTime<Utc>> = match sql_client.query_one(
+			include_str!("last_run_date.sql"), &[&self.workflow.workflow_id]
+		).await {
+			Ok(row) => row.get("run_date"),
+			Err(e) => {
+				error!("Failed to retrieve last run_date of '{}':\n{}", self.workflow.workflow_id, e);
+				None
+			}
+		};
+		trace!("Last run_date of '{}': {:?}", self.workflow.workflow_id, last_run_date);
+
+		self.scheduled_until = restored_scheduled_until(&self.settings, last_run_date, now);
+	}
+
+	/// Returns the point in time after which the next run_date is scheduled.
+	/// Honors start_date and skip_until.
+	fn schedule_cursor(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+		// Schedule::after is exclusive, so start_date itself has to be included explicitly.
+		let start = self.settings.start_date.map(|s| s - Duration::seconds(1));
+		let after = match (self.scheduled_until, start) {
+			(Some(scheduled_until), Some(start)) => scheduled_until.max(start),
+			(Some(scheduled_until), None) => scheduled_until,
+			(None, Some(start)) => start,
+			(None, None) => now,
+		};
+		match self.settings.skip_until {
+			Some(skip_until) => after.max(skip_until),
+			None => after,
+		}
+	}
+
+	/// Returns the next schedule occurrence after `now`, i.e. when this workflow needs the next tick.
+	/// None if the workflow does not create instances on its own.
+	pub fn next_run_date(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+		if self.settings.is_paused || self.retired || self.draining {
+			return None;
+		}
+		let after = self.schedule_cursor(now).max(now);
+		self.schedule.after(&self.settings, &after)
+			.next()
+			.filter(|run_date| self.settings.end_date.map_or(true, |end_date| *run_date <= end_date))
+	}
+
+	/// Returns the schedule occurrences which are due at `now` and should become workflow instances.
+	/// Honors start_date, end_date, skip_until and the catchup policy.
+	fn due_run_dates(&self, now: DateTime<Utc>, limit: usize) -> Vec<DateTime<Utc>> {
+		let after = self.schedule_cursor(now);
+		let until = match self.settings.end_date {
+			Some(end_date) if end_date < now => end_date,
+			_ => now,
+		};
+
+		let due = self.schedule.after(&self.settings, &after)
+			.take_while(|run_date| *run_date <= until);
+		match self.settings.catchup {
+			CatchupPolicy::All => due.take(limit).collect(),
+			CatchupPolicy::LatestOnly | CatchupPolicy::None => due.last().into_iter().take(limit).collect(),
+		}
+	}
+
+	/// Runs the active instances and dispatches their ready tasks, oldest instance and highest priority first.
+	/// Tasks exceeding max_active_tasks of the workflow or the scheduler wait for the next tick.
+	async fn run_instances(&mut self, sql_client: &Db, dispatcher: &Dispatcher) {
+		let settings = &self.settings;
+		let mut ready = Vec::new();
+		for (index, instance) in self.workflow_instances.iter_mut().enumerate().filter(|(_, i)| i.is_active()) {
+			for task in instance.run(sql_client, settings).await {
+				let priority = settings.task_priority(instance.get_task_id(task));
+				ready.push((dispatch_order(instance.get_run_date(), instance.get_wiid(), priority), index, task));
+			}
+		}
+		ready.sort_by_key(|(order, _, _)| *order);
 
-		for queued_instance in queued_instances {
-			queued_instance.run(sql_client).await;
+		if self.draining {
+			return;
+		}
+		let mut running = self.running_tasks();
+		let mut waiting = ready.len();
+		for (_, index, task) in ready {
+			if settings.max_active_tasks.map_or(false, |max_active_tasks| running >= max_active_tasks) {
+				trace!("{} ready tasks of '{}' wait for max_active_tasks", waiting, self.workflow.workflow_id);
+				break;
+			}
+			let slot = match dispatcher.acquire_slot() {
+				Some(slot) => slot,
+				None => {
+					trace!("{} ready tasks of '{}' wait for a free scheduler slot", waiting, self.workflow.workflow_id);
+					break;
+				}
+			};
+			if self.workflow_instances[index].dispatch(sql_client, task, dispatcher, slot).await {
+				running += 1;
+			}
+			waiting -= 1;
+		}
+	}
+
+	/// Persists the state of all active instances before the scheduler exits.
+	pub async fn shutdown(&mut self, sql_client: &Db) {
+		for instance in self.workflow_instances.iter_mut().filter(|i| i.is_active()) {
+			instance.flush(sql_client).await;
 		}
 	}
 
-	async fn queue_instances(&mut self, sql_client: &tokio_postgres::Client, now: DateTime<Utc>) {
-		let active_instances: Vec<&WorkflowInstance> = self.workflow_instances
+	fn remaining_slots(&self) -> usize {
+		let active_instances = self.workflow_instances
 			.iter()
-			.filter(|i| matches!(i.get_run_state(), RunState::Queued | RunState::Running))
-			.collect();
+			.filter(|i| i.holds_slot())
+			.count();
 
-		if active_instances.len() as u32 >= self.workflow.max_active_runs {
+		if active_instances as u32 >= self.workflow.max_active_runs {
 			trace!("Amount of active runs exceeds max active runs for '{}': {} >= {}",
 				self.workflow.workflow_id,
-				active_instances.len(),
+				active_instances,
 				self.workflow.max_active_runs);
-			return;
+			return 0;
 		}
-		let remaining_slots = self.workflow.max_active_runs as usize - active_instances.len();
+		let remaining_slots = self.workflow.max_active_runs as usize - active_instances;
 		trace!("{} remaining slots for workflow '{}'", remaining_slots, self.workflow.workflow_id);
+		remaining_slots
+	}
 
-		for instance in self.schedule.after(&self.last_tick.unwrap()).take(remaining_slots) {
-			if instance > now {
+	/// Picks up manually triggered runs. They count against max_active_runs like scheduled ones.
+	async fn queue_triggered_instances(&mut self, sql_client: &Db) {
+		let remaining_slots = self.remaining_slots();
+		if remaining_slots == 0 {
+			return;
+		}
+
+		let rows = match sql_client.query(
+			include_str!("triggered_runs.sql"), &[&self.workflow.workflow_id, &(remaining_slots as i64)]
+		).await {
+			Ok(rows) => rows,
+			Err(e) => {
+				error!("Failed to retrieve triggered runs of '{}':\n{}", self.workflow.workflow_id, e);
+				return;
+			}
+		};
+		for row in rows {
+			let wiid: i32 = row.get("wiid");
+			if self.workflow_instances.iter().any(|i| i.get_wiid() == wiid) {
+				continue;
+			}
+			// Triggered runs are pinned to the version scheduled here, the newest one may have been rejected
+			let wid: Option<i32> = row.get("wid");
+			if wid.is_none() {
+				let result = sql_client.execute(include_str!("pin_workflow_version.sql"), &[&wiid, &self.wid]).await;
+				if let Err(e) = result {
+					error!("Failed to pin triggered run of '{}' to version {}:\n{}", self.workflow.workflow_id, self.wid, e);
+					continue;
+				}
+			}
+			let tasks = match self.tasks_of(sql_client, wid).await {
+				Some(tasks) => tasks,
+				None => continue,
+			};
+			match WorkflowInstance::from_row(&row, &self.workflow.workflow_id, &tasks) {
+				Ok(mut wi) => {
+					info!("Picking up triggered run of '{}' for {}", self.workflow.workflow_id, wi.local_run_date());
+					match wi.queue(sql_client).await {
+						Ok(()) => self.workflow_instances.push(wi),
+						Err(e) => error!("Failed to queue triggered run of '{}': {}", self.workflow.workflow_id, e),
+					}
+				},
+				Err(e) => error!("Failed to create triggered run of '{}': {}", self.workflow.workflow_id, e),
+			}
+		}
+	}
+
+	/// Stops the active scheduled runs in favour of the occurrence at `run_date`.
+	async fn cancel_scheduled_instances(&mut self, sql_client: &Db, run_date: DateTime<Utc>) {
+		let reason = format!("Cancelled by the run for {}", run_date.with_timezone(&self.settings.timezone));
+		let active_instances = self.workflow_instances
+			.iter_mut()
+			.filter(|i| i.holds_slot() && i.get_run_type() == RunType::Scheduled);
+		for instance in active_instances {
+			warn!("Cancelling run '{}' of '{}' as a newer run is due", instance.get_run_id(), self.workflow.workflow_id);
+			if let Err(e) = instance.stop(sql_client, RunState::Cancelled, "run_cancelled", reason.clone()).await {
+				error!("{}", e);
+			}
+		}
+	}
+
+	/// Creates instances for the due schedule occurrences, according to the overlap policy.
+	async fn queue_instances(&mut self, sql_client: &Db, now: DateTime<Utc>) {
+		self.queue_triggered_instances(sql_client).await;
+
+		let limit = due_limit(self.settings.overlap, self.has_active_scheduled_instances(), self.remaining_slots());
+		if limit == 0 {
+			return;
+		}
+
+		for instance in self.due_run_dates(now, limit) {
+			match overlap(self.settings.overlap, self.has_active_scheduled_instances()) {
+				Overlap::Skip => {
+					let result = WorkflowInstance::skip_occurrence(
+						sql_client,
+						&self.workflow.workflow_id,
+						self.wid,
+						instance,
+						self.settings.timezone,
+						"The previous run was still active"
+					).await;
+					match result {
+						Ok(()) => self.scheduled_until = Some(instance),
+						Err(e) => {
+							error!("Failed to skip run of '{}' for {}:\n{}", self.workflow.workflow_id, instance, e);
+							break;
+						}
+					}
+					continue;
+				},
+				// Stopped instances do not hold their slot any more, even while their tasks are still terminated
+				Overlap::CancelPrevious => self.cancel_scheduled_instances(sql_client, instance).await,
+				Overlap::Run => (),
+			}
+			if self.remaining_slots() == 0 {
 				break;
 			}
-			info!("Creating workflow instance for '{}' at time {}", self.workflow.workflow_id, instance);
-			if let Ok(mut wi) = WorkflowInstance::new(
-				sql_client, &self.workflow.workflow_id, &self.workflow.tasks, instance
+			info!(
+				"Creating workflow instance for '{}' at time {}",
+				self.workflow.workflow_id,
+				instance.with_timezone(&self.settings.timezone)
+			);
+			match WorkflowInstance::new(
+				sql_client, &self.workflow.workflow_id, self.wid, &self.workflow.tasks, instance, self.settings.timezone
 				).await {
-				wi.queue(sql_client).await;
-				self.workflow_instances.push(wi);
+				Ok(wi) => {
+					if !self.workflow_instances.iter().any(|i| i.get_wiid() == wi.get_wiid()) {
+						self.workflow_instances.push(wi);
+					}
+					self.scheduled_until = Some(instance);
+				},
+				Err(_) => break,
 			}
 		}
 	}
 }
+
+/// Sort key of a ready task: tasks of older instances are dispatched first, the ones of an instance by descending
+/// priority.
+fn dispatch_order(run_date: DateTime<Utc>, wiid: i32, priority: i64) -> (DateTime<Utc>, i32, Reverse<i64>) {
+	(run_date, wiid, Reverse(priority))
+}
+
+/// Where scheduling resumes after `last_run_date`, the latest persisted run_date, according to the catchup policy.
+fn restored_scheduled_until(
+	settings: &WorkflowSettings,
+	last_run_date: Option<DateTime<Utc>>,
+	now: DateTime<Utc>
+) -> Option<DateTime<Utc>> {
+	match (settings.catchup, last_run_date) {
+		(CatchupPolicy::None, Some(last_run_date)) => Some(last_run_date.max(now)),
+		(CatchupPolicy::None, None) => Some(now),
+		(_, Some(last_run_date)) => Some(last_run_date),
+		(_, None) if settings.start_date.is_some() => None,
+		(_, None) => Some(now),
+	}
+}
+
+/// What happens to a due schedule occurrence
+#[derive(Debug, PartialEq)]
+enum Overlap {
+	/// It is run once a max_active_runs slot is free
+	Run,
+	/// It is recorded as skipped
+	Skip,
+	/// The active scheduled runs are stopped and it is run
+	CancelPrevious,
+}
+
+/// Decides on a due occurrence by the overlap `policy`, `previous_active` tells if a scheduled run is active.
+fn overlap(policy: OverlapPolicy, previous_active: bool) -> Overlap {
+	match policy {
+		OverlapPolicy::Skip if previous_active => Overlap::Skip,
+		OverlapPolicy::CancelPrevious if previous_active => Overlap::CancelPrevious,
+		_ => Overlap::Run,
+	}
+}
+
+/// How many due occurrences are handled on one tick.
+fn due_limit(policy: OverlapPolicy, previous_active: bool, remaining_slots: usize) -> usize {
+	match policy {
+		OverlapPolicy::Allow => remaining_slots,
+		OverlapPolicy::Queue if previous_active => 0,
+		OverlapPolicy::Queue => remaining_slots.min(1),
+		// Every due occurrence is handled right away, either by running or by skipping it
+		OverlapPolicy::Skip | OverlapPolicy::CancelPrevious => usize::MAX,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn utc(date: &str) -> DateTime<Utc> {
+		date.parse().unwrap()
+	}
+
+	fn workflow(schedule: &str, settings: WorkflowSettings) -> Workflow {
+		let openworkflow = openworkflow::Workflow {
+			workflow_id: "wf".to_string(),
+			schedule: schedule.to_string(),
+			max_active_runs: 1,
+			..Default::default()
+		};
+		Workflow::new(1, openworkflow, settings).unwrap()
+	}
+
+	/// An hourly workflow which handled the occurrences up to `scheduled_until`.
+	fn hourly(settings: WorkflowSettings, scheduled_until: Option<&str>) -> Workflow {
+		let mut workflow = workflow("@hourly", settings);
+		workflow.scheduled_until = scheduled_until.map(utc);
+		workflow
+	}
+
+	#[test]
+	fn missed_occurrences_follow_the_catchup_policy() {
+		let now = utc("2020-06-01T03:30:00Z");
+		let due = |catchup, limit| {
+			let settings = WorkflowSettings { catchup, ..Default::default() };
+			hourly(settings, Some("2020-06-01T00:00:00Z")).due_run_dates(now, limit)
+		};
+		assert_eq!(due(CatchupPolicy::All, 10), vec![
+			utc("2020-06-01T01:00:00Z"),
+			utc("2020-06-01T02:00:00Z"),
+			utc("2020-06-01T03:00:00Z"),
+		]);
+		assert_eq!(due(CatchupPolicy::All, 2), vec![utc("2020-06-01T01:00:00Z"), utc("2020-06-01T02:00:00Z")]);
+		assert_eq!(due(CatchupPolicy::LatestOnly, 10), vec![utc("2020-06-01T03:00:00Z")]);
+		assert_eq!(due(CatchupPolicy::None, 10), vec![utc("2020-06-01T03:00:00Z")]);
+		assert_eq!(due(CatchupPolicy::All, 0), Vec::<DateTime<Utc>>::new());
+	}
+
+	#[test]
+	fn scheduling_resumes_by_the_catchup_policy() {
+		let now = utc("2020-06-01T03:30:00Z");
+		let last_run_date = Some(utc("2020-06-01T00:00:00Z"));
+		let start_date = Some(utc("2020-05-01T00:00:00Z"));
+		let restored = |catchup, start_date, last_run_date| {
+			let settings = WorkflowSettings { catchup, start_date, ..Default::default() };
+			restored_scheduled_until(&settings, last_run_date, now)
+		};
+		assert_eq!(restored(CatchupPolicy::All, None, last_run_date), last_run_date);
+		assert_eq!(restored(CatchupPolicy::LatestOnly, None, last_run_date), last_run_date);
+		assert_eq!(restored(CatchupPolicy::None, None, last_run_date), Some(now));
+		// A run_date ahead of now, e.g. of a triggered run, is not scheduled again
+		let ahead = Some(utc("2020-06-01T05:00:00Z"));
+		assert_eq!(restored(CatchupPolicy::None, None, ahead), ahead);
+		// Without runs, the schedule starts at start_date, or now without one
+		assert_eq!(restored(CatchupPolicy::All, start_date, None), None);
+		assert_eq!(restored(CatchupPolicy::All, None, None), Some(now));
+		assert_eq!(restored(CatchupPolicy::None, start_date, None), Some(now));
+	}
+
+	#[test]
+	fn start_date_is_included() {
+		let now = utc("2020-06-01T03:30:00Z");
+		let settings = WorkflowSettings { start_date: Some(utc("2020-06-01T02:00:00Z")), ..Default::default() };
+		let workflow = hourly(settings, None);
+		assert_eq!(workflow.schedule_cursor(now), utc("2020-06-01T01:59:59Z"));
+		assert_eq!(workflow.due_run_dates(now, 10), vec![utc("2020-06-01T02:00:00Z"), utc("2020-06-01T03:00:00Z")]);
+
+		// Handled occurrences after start_date are not scheduled again
+		let settings = WorkflowSettings { start_date: Some(utc("2020-06-01T00:00:00Z")), ..Default::default() };
+		let workflow = hourly(settings, Some("2020-06-01T02:00:00Z"));
+		assert_eq!(workflow.due_run_dates(now, 10), vec![utc("2020-06-01T03:00:00Z")]);
+	}
+
+	#[test]
+	fn end_date_stops_scheduling() {
+		let now = utc("2020-06-01T03:30:00Z");
+		let settings = WorkflowSettings { end_date: Some(utc("2020-06-01T02:00:00Z")), ..Default::default() };
+		let workflow = hourly(settings, Some("2020-06-01T00:00:00Z"));
+		assert_eq!(workflow.due_run_dates(now, 10), vec![utc("2020-06-01T01:00:00Z"), utc("2020-06-01T02:00:00Z")]);
+		assert_eq!(workflow.next_run_date(now), None);
+
+		let settings = WorkflowSettings { end_date: Some(utc("2020-06-01T05:00:00Z")), ..Default::default() };
+		let workflow = hourly(settings, Some("2020-06-01T03:00:00Z"));
+		assert_eq!(workflow.next_run_date(now), Some(utc("2020-06-01T04:00:00Z")));
+	}
+
+	#[test]
+	fn skipped_occurrences_are_not_scheduled() {
+		let now = utc("2020-06-01T03:30:00Z");
+		let settings = WorkflowSettings { skip_until: Some(utc("2020-06-01T02:00:00Z")), ..Default::default() };
+		let workflow = hourly(settings, Some("2020-06-01T00:00:00Z"));
+		assert_eq!(workflow.schedule_cursor(now), utc("2020-06-01T02:00:00Z"));
+		assert_eq!(workflow.due_run_dates(now, 10), vec![utc("2020-06-01T03:00:00Z")]);
+	}
+
+	#[test]
+	fn cursor_starts_now_without_history() {
+		let now = utc("2020-06-01T03:30:00Z");
+		let workflow = hourly(WorkflowSettings::default(), None);
+		assert_eq!(workflow.schedule_cursor(now), now);
+		assert_eq!(workflow.due_run_dates(now, 10), Vec::<DateTime<Utc>>::new());
+		assert_eq!(workflow.next_run_date(now), Some(utc("2020-06-01T04:00:00Z")));
+
+		let settings = WorkflowSettings { is_paused: true, ..Default::default() };
+		assert_eq!(hourly(settings, None).next_run_date(now), None);
+	}
+
+	/// A DB nothing listens on, so recording events fails right away.
+	fn unreachable_db() -> Db {
+		let config = crate::config::SchedulerConfig {
+			psql_url: "postgres://postgres@127.0.0.1:1".to_string(),
+			db_max_retries: 0,
+			..Default::default()
+		};
+		Db::new(&config).unwrap().0
+	}
+
+	#[tokio::test]
+	async fn schedule_changes_apply_after_the_last_run_date() {
+		let db = unreachable_db();
+		let now = utc("2020-06-01T03:30:00Z");
+		let mut workflow = hourly(WorkflowSettings::default(), Some("2020-06-01T02:00:00Z"));
+		workflow.last_tick = Some(now);
+
+		let mut changed = workflow.workflow.clone();
+		changed.schedule = "@daily".to_string();
+		workflow.update_workflow(&db, 2, changed, false).await.unwrap();
+
+		assert_eq!(workflow.get_wid(), 2);
+		assert_eq!(workflow.workflow.schedule, "@daily");
+		// The last run_date is restored from the DB on the next tick
+		assert_eq!(workflow.last_tick, None);
+		assert_eq!(workflow.due_run_dates(now, 10), Vec::<DateTime<Utc>>::new());
+		assert_eq!(workflow.next_run_date(now), Some(utc("2020-06-02T00:00:00Z")));
+	}
+
+	#[tokio::test]
+	async fn invalid_schedules_keep_the_previous_version() {
+		let db = unreachable_db();
+		let mut workflow = hourly(WorkflowSettings::default(), Some("2020-06-01T02:00:00Z"));
+
+		let mut changed = workflow.workflow.clone();
+		changed.schedule = "@often".to_string();
+		assert!(workflow.update_workflow(&db, 2, changed, false).await.is_err());
+
+		assert_eq!(workflow.get_wid(), 1);
+		assert_eq!(workflow.workflow.schedule, "@hourly");
+		let now = utc("2020-06-01T03:30:00Z");
+		assert_eq!(workflow.due_run_dates(now, 10), vec![utc("2020-06-01T03:00:00Z")]);
+	}
+
+	#[tokio::test]
+	async fn unchanged_schedules_keep_the_tick() {
+		let db = unreachable_db();
+		let now = utc("2020-06-01T03:30:00Z");
+		let mut workflow = hourly(WorkflowSettings::default(), Some("2020-06-01T02:00:00Z"));
+		workflow.last_tick = Some(now);
+
+		// The same definition stored as a new version
+		let same = workflow.workflow.clone();
+		workflow.update_workflow(&db, 2, same, false).await.unwrap();
+		assert_eq!(workflow.get_wid(), 2);
+		assert_eq!(workflow.last_tick, Some(now));
+
+		let mut changed = workflow.workflow.clone();
+		changed.max_active_runs = 2;
+		workflow.update_workflow(&db, 3, changed, false).await.unwrap();
+		assert_eq!(workflow.get_wid(), 3);
+		assert_eq!(workflow.last_tick, Some(now));
+
+		let mut changed = workflow.workflow.clone();
+		changed.max_active_runs = 3;
+		workflow.update_workflow(&db, 4, changed, true).await.unwrap();
+		assert_eq!(workflow.last_tick, None);
+	}
+
+	#[test]
+	fn older_runs_and_higher_priorities_are_dispatched_first() {
+		let settings = WorkflowSettings {
+			task_priorities: vec![("load".to_string(), 10), ("cleanup".to_string(), -1)].into_iter().collect(),
+			..Default::default()
+		};
+		let older = utc("2020-06-01T00:00:00Z");
+		let newer = utc("2020-06-01T01:00:00Z");
+		let mut ready = vec![
+			(newer, 3, "load"),
+			(older, 2, "cleanup"),
+			(older, 2, "extract"),
+			(older, 2, "load"),
+			// Triggered for the same run_date, but created later
+			(older, 4, "load"),
+		];
+		ready.sort_by_key(|(run_date, wiid, task_id)| dispatch_order(*run_date, *wiid, settings.task_priority(task_id)));
+		assert_eq!(ready, vec![
+			(older, 2, "load"),
+			(older, 2, "extract"),
+			(older, 2, "cleanup"),
+			(older, 4, "load"),
+			(newer, 3, "load"),
+		]);
+	}
+
+	#[test]
+	fn overlap_decisions() {
+		let cases = [
+			(OverlapPolicy::Allow, false, Overlap::Run),
+			(OverlapPolicy::Allow, true, Overlap::Run),
+			(OverlapPolicy::Queue, false, Overlap::Run),
+			(OverlapPolicy::Queue, true, Overlap::Run),
+			(OverlapPolicy::Skip, false, Overlap::Run),
+			(OverlapPolicy::Skip, true, Overlap::Skip),
+			(OverlapPolicy::CancelPrevious, false, Overlap::Run),
+			(OverlapPolicy::CancelPrevious, true, Overlap::CancelPrevious),
+		];
+		for (policy, previous_active, expected) in cases.iter() {
+			assert_eq!(overlap(*policy, *previous_active), *expected, "{:?}, previous active: {}", policy, previous_active);
+		}
+	}
+
+	#[test]
+	fn due_limits() {
+		let cases = [
+			(OverlapPolicy::Allow, false, 3, 3),
+			(OverlapPolicy::Allow, true, 0, 0),
+			(OverlapPolicy::Queue, false, 3, 1),
+			(OverlapPolicy::Queue, false, 0, 0),
+			(OverlapPolicy::Queue, true, 3, 0),
+			(OverlapPolicy::Skip, true, 0, usize::MAX),
+			(OverlapPolicy::CancelPrevious, true, 0, usize::MAX),
+		];
+		for (policy, previous_active, remaining_slots, expected) in cases.iter() {
+			assert_eq!(
+				due_limit(*policy, *previous_active, *remaining_slots),
+				*expected,
+				"{:?}, previous active: {}, {} remaining slots",
+				policy,
+				previous_active,
+				remaining_slots
+			);
+		}
+	}
+}
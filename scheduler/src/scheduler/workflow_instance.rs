@@ -1,74 +1,225 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
 use chrono::prelude::*;
+use chrono_tz::Tz;
 
-use tokio;
-use tokio::task::JoinHandle;
-use tonic::Request;
+use tokio::sync::{mpsc, oneshot};
 
 use flowty_types;
-use flowty_types::{Dag, FlowtyError};
-use flowty_types::openworkflow::execution_broker_client::ExecutionBrokerClient;
-use flowty_types::openworkflow::executor_client::ExecutorClient;
-use flowty_types::openworkflow::{
-	Task,
-	SearchRequest,
-	ExecutorDefinition,
-	ExecutorKind
-};
-
-use crate::utils;
+use flowty_types::{Dag, FlowtyError, NodeIndex};
+use flowty_types::openworkflow::{Task, ExecutionStatus};
+
+use super::db::{Db, DbError, StateWrite};
+use super::dispatch::{Dispatcher, Slot, TaskHandle, TaskStatus};
+use super::notification::{Notification, NotificationEvent};
+use super::dependency::{Dependency, DependencyState};
+use super::event;
+use super::sensor::{Sensor, SensorKind, SensorState};
+use super::settings::WorkflowSettings;
+use super::sla::Progress;
+use super::template::TemplateContext;
 
 /*
 	RunState is a state automaton:
-	Nothing => Queued
-	Queued => Running
-	Running => {Success, Failed}
-	Failed => Queued
+	Nothing => {Queued, Skipped, Cancelled}
+	Queued => {WaitingUpstream, Running, TimedOut, Cancelled, Cancelling, Skipped}
+	WaitingUpstream => {Running, TimedOut, Cancelled, Cancelling}
+	Running => {Success, Failed, TimedOut, Cancelled, Cancelling, UpForRetry}
+	UpForRetry => {Queued, Running, TimedOut, Cancelled, Cancelling}
+	Cancelling => Cancelled
+	The transitions are enforced by `RunState::check_transition`.
 */
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RunState {
 	Nothing,
 	Queued,
+	/// Waiting for a dependency on another workflow
+	WaitingUpstream,
 	Running,
 	Success,
 	Failed,
+	/// Cancel was requested. Waiting for the in-flight tasks to terminate
+	Cancelling,
+	/// Stopped before it finished, e.g. by a newer run
+	Cancelled,
+	/// The occurrence was never run
+	Skipped,
+	/// Nothing is in flight, failed tasks wait for their retry_interval to pass
+	UpForRetry,
+	/// Exceeded the run timeout or waited too long on upstream workflows
+	TimedOut,
+}
+
+impl RunState {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			RunState::Nothing => "nothing",
+			RunState::Queued => "queued",
+			RunState::WaitingUpstream => "waiting_upstream",
+			RunState::Running => "running",
+			RunState::Success => "success",
+			RunState::Failed => "failed",
+			RunState::Cancelling => "cancelling",
+			RunState::Cancelled => "cancelled",
+			RunState::Skipped => "skipped",
+			RunState::UpForRetry => "up_for_retry",
+			RunState::TimedOut => "timed_out",
+		}
+	}
+
+	pub fn from_str(run_state: &str) -> RunState {
+		match run_state {
+			"queued" => RunState::Queued,
+			"waiting_upstream" => RunState::WaitingUpstream,
+			"running" => RunState::Running,
+			"success" => RunState::Success,
+			"failed" => RunState::Failed,
+			"cancelling" => RunState::Cancelling,
+			"cancelled" => RunState::Cancelled,
+			"skipped" => RunState::Skipped,
+			"up_for_retry" => RunState::UpForRetry,
+			"timed_out" => RunState::TimedOut,
+			_ => RunState::Nothing,
+		}
+	}
+
+	/// Final states, a run in one of them never changes again.
+	pub fn is_finished(&self) -> bool {
+		matches!(
+			self,
+			RunState::Success | RunState::Failed | RunState::Cancelled | RunState::Skipped | RunState::TimedOut
+		)
+	}
+
+	pub fn can_transition_to(&self, next: RunState) -> bool {
+		use RunState::*;
+		match (self, next) {
+			(Nothing, Queued) | (Nothing, Skipped) | (Nothing, Cancelled) => true,
+			(Queued, WaitingUpstream) | (Queued, Running) | (Queued, TimedOut) | (Queued, Cancelled) => true,
+			(Queued, Cancelling) | (Queued, Skipped) => true,
+			(WaitingUpstream, Running) | (WaitingUpstream, TimedOut) | (WaitingUpstream, Cancelled) => true,
+			(WaitingUpstream, Cancelling) => true,
+			(Running, Success) | (Running, Failed) | (Running, TimedOut) | (Running, Cancelled) => true,
+			(Running, Cancelling) | (Running, UpForRetry) => true,
+			(UpForRetry, Queued) | (UpForRetry, Running) | (UpForRetry, TimedOut) | (UpForRetry, Cancelled) => true,
+			(UpForRetry, Cancelling) => true,
+			(Cancelling, Cancelled) => true,
+			_ => false,
+		}
+	}
+
+	/// Returns an error if the run `run_id` must not change from this state to `next`.
+	pub fn check_transition(&self, run_id: &str, next: RunState) -> Result<(), FlowtyError> {
+		if self.can_transition_to(next) {
+			Ok(())
+		} else {
+			Err(FlowtyError::IllegalRunStateTransition {
+				run_id: run_id.to_string(),
+				from: self.as_str().to_string(),
+				to: next.as_str().to_string(),
+			})
+		}
+	}
+}
+
+/// Whether an instance was created by the schedule or triggered manually.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunType {
+	Scheduled,
+	Manual,
+}
+
+impl RunType {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			RunType::Scheduled => "scheduled",
+			RunType::Manual => "manual",
+		}
+	}
+
+	pub fn from_str(run_type: &str) -> RunType {
+		match run_type {
+			"manual" => RunType::Manual,
+			_ => RunType::Scheduled,
+		}
+	}
+}
+
+/// Prefix of the run_ids of scheduled instances. Manual runs may not use it.
+pub const SCHEDULED_PREFIX: &str = "scheduled__";
+
+/// The run_id of the scheduled instance for `run_date`.
+pub fn scheduled_run_id(run_date: DateTime<Utc>) -> String {
+	format!("{}{}", SCHEDULED_PREFIX, run_date.to_rfc3339())
 }
 
+/// A single run of a workflow.
+/// The instance owns the tasks it was created with, so it finishes on that version of the workflow.
 pub struct WorkflowInstance {
 	wiid: i32,
 	workflow_id: String,
+	run_id: String,
+	run_type: RunType,
 	run_state: RunState,
 	run_date: DateTime<Utc>,
+	timezone: Tz,
+	/// Config payload of a triggered run, available to task templates.
+	conf: serde_json::Value,
+	tasks: Vec<Task>,
 	dag: Dag,
-	task_handles: Vec<JoinHandle<()>>,
+	/// When the instance left the queue. Dependency timeouts are measured from here.
+	started_at: Option<DateTime<Utc>>,
+	/// When the instance started running. Run timeouts are measured from here.
+	running_since: Option<DateTime<Utc>>,
+	/// When the instance reached a final state.
+	finished_at: Option<DateTime<Utc>>,
+	task_handles: HashMap<NodeIndex, TaskHandle>,
+	/// Tasks waiting for a dependency on another workflow, with the time they became ready. Their dependency
+	/// timeouts are measured from there.
+	waiting_tasks: HashMap<NodeIndex, DateTime<Utc>>,
+	/// First and last poke of running sensors
+	sensor_pokes: HashMap<NodeIndex, (DateTime<Utc>, DateTime<Utc>)>,
+	/// Results of the HTTP pokes running in the background
+	http_pokes: HashMap<NodeIndex, oneshot::Receiver<bool>>,
+	/// Failed tasks waiting for their retry_interval to pass
+	retry_at: HashMap<NodeIndex, DateTime<Utc>>,
+	/// Start of the first attempt and end of the tasks this instance ran. Retries keep the start.
+	task_times: HashMap<NodeIndex, (DateTime<Utc>, Option<DateTime<Utc>>)>,
+	/// Final state, event and reason of a stopped instance, until the streams of its cancelled tasks ended
+	stopping: Option<(RunState, String, String)>,
+	/// Notifications recorded since the workflow took them last
+	notifications: Vec<Notification>,
+	status_tx: mpsc::UnboundedSender<TaskStatus>,
+	status_rx: mpsc::UnboundedReceiver<TaskStatus>,
 }
 
 impl WorkflowInstance {
+	/// Creates a scheduled instance of workflow version `wid` and persists it in the queued state.
+	/// Inserting and queueing happen in one statement, so a scheduler dying in between cannot leave it behind.
+	/// If the instance exists already, e.g. because an insert was retried, the existing one is returned.
 	pub async fn new(
-		sql_client: &tokio_postgres::Client,
+		sql_client: &Db,
 		workflow_id: &String,
+		wid: i32,
 		tasks: &Vec<Task>,
-		run_date: DateTime<Utc>
+		run_date: DateTime<Utc>,
+		timezone: Tz
 	) -> Result<WorkflowInstance, FlowtyError> {
-		let result = sql_client.query_one(include_str!("new_workflow_instance.sql"), &[workflow_id, &run_date.to_rfc3339()]).await;
+		let run_id = scheduled_run_id(run_date);
+		let conf = serde_json::Value::Object(serde_json::Map::new());
+		let result = sql_client.query_one(
+			include_str!("new_workflow_instance.sql"),
+			&[workflow_id, &wid, &run_id, &RunType::Scheduled.as_str(), &run_date, &timezone.name(), &conf]
+		).await;
 		match result {
 			Ok(row) => {
-				let dag = Dag::try_from(tasks);
-				match dag {
-					Ok(dag) => {
-						Ok(WorkflowInstance {
-							wiid: row.get("wiid"),
-							workflow_id: workflow_id.to_string(),
-							run_state: RunState::Nothing,
-							run_date,
-							dag,
-							task_handles: Vec::new(),
-						})
-					},
-					Err(e) => {
-						Err(e)
-					}
-				}
+				let mut instance = WorkflowInstance::build(
+					row.get("wiid"), workflow_id, run_id, RunType::Scheduled, run_date, timezone, tasks
+				)?;
+				let run_state: &str = row.get("run_state");
+				instance.run_state = RunState::from_str(run_state);
+				Ok(instance)
 			},
 			Err(e) => {
 				error!("Failed to insert workflow_instance into database:{}\nScheduler state might de-sync!", e);
@@ -77,29 +228,159 @@ impl WorkflowInstance {
 		}
 	}
 
+	/// Persists a scheduled occurrence which is not run, in the skipped state.
+	pub async fn skip_occurrence(
+		sql_client: &Db,
+		workflow_id: &str,
+		wid: i32,
+		run_date: DateTime<Utc>,
+		timezone: Tz,
+		reason: &str
+	) -> Result<(), DbError> {
+		let run_id = scheduled_run_id(run_date);
+		info!("Skipping run '{}' of workflow '{}': {}", run_id, workflow_id, reason);
+		sql_client.execute(
+			include_str!("skipped_workflow_instance.sql"),
+			&[&workflow_id, &wid, &run_id, &run_date, &timezone.name(), &reason]
+		).await?;
+		Ok(())
+	}
+
+	/// Creates an instance from a persisted row, e.g. a triggered run or an instance left over by another scheduler.
+	/// `tasks` have to be the ones of the workflow version the instance was created from.
+	pub fn from_row(
+		row: &tokio_postgres::Row,
+		workflow_id: &String,
+		tasks: &Vec<Task>
+	) -> Result<WorkflowInstance, FlowtyError> {
+		let timezone: &str = row.get("timezone");
+		let run_type: &str = row.get("run_type");
+		let run_state: &str = row.get("run_state");
+		let mut instance = WorkflowInstance::build(
+			row.get("wiid"),
+			workflow_id,
+			row.get("run_id"),
+			RunType::from_str(run_type),
+			row.get("run_date"),
+			timezone.parse().unwrap_or(Tz::UTC),
+			tasks
+		)?;
+		instance.conf = row.get("conf");
+		instance.running_since = row.get("running_since");
+		instance.run_state = RunState::from_str(run_state);
+		Ok(instance)
+	}
+
+	/// Restores the finished tasks of an instance from the DB.
+	/// Tasks which were in flight are dispatched again.
+	pub async fn restore_task_states(&mut self, sql_client: &Db) {
+		let rows = match sql_client.query(include_str!("task_states.sql"), &[&self.wiid]).await {
+			Ok(rows) => rows,
+			Err(e) => {
+				error!("Failed to restore task states of '{}':\n{}", self.run_id, e);
+				return;
+			}
+		};
+		let nodes: Vec<NodeIndex> = self.dag.node_indices().collect();
+		for row in rows {
+			let task_id: &str = row.get("task_id");
+			let state: &str = row.get("state");
+			let node = match nodes.iter().find(|n| self.dag.get_task_instance(**n).get_task_id() == task_id) {
+				Some(node) => *node,
+				None => continue,
+			};
+			match state {
+				"success" => self.dag.set_execution_status(node, ExecutionStatus::Success),
+				"failed" => self.dag.set_execution_status(node, ExecutionStatus::Failed),
+				"skipped" => self.dag.skip(node),
+				_ => trace!("Task '{}' of '{}' was {}. Running it again", task_id, self.run_id, state),
+			};
+		}
+	}
+
+	fn build(
+		wiid: i32,
+		workflow_id: &String,
+		run_id: String,
+		run_type: RunType,
+		run_date: DateTime<Utc>,
+		timezone: Tz,
+		tasks: &Vec<Task>
+	) -> Result<WorkflowInstance, FlowtyError> {
+		let dag = Dag::try_from(tasks)?;
+		let (status_tx, status_rx) = mpsc::unbounded_channel();
+		Ok(WorkflowInstance {
+			wiid,
+			workflow_id: workflow_id.to_string(),
+			run_id,
+			run_type,
+			run_state: RunState::Nothing,
+			run_date,
+			timezone,
+			conf: serde_json::Value::Object(serde_json::Map::new()),
+			tasks: tasks.clone(),
+			dag,
+			started_at: None,
+			running_since: None,
+			finished_at: None,
+			task_handles: HashMap::new(),
+			waiting_tasks: HashMap::new(),
+			sensor_pokes: HashMap::new(),
+			http_pokes: HashMap::new(),
+			retry_at: HashMap::new(),
+			task_times: HashMap::new(),
+			stopping: None,
+			notifications: Vec::new(),
+			status_tx,
+			status_rx,
+		})
+	}
+
+	/// Persists the task statuses reported so far. Called on shutdown, so finished tasks are not run again
+	/// by the scheduler which adopts the instance.
+	pub async fn flush(&mut self, sql_client: &Db) {
+		self.collect_task_status(sql_client).await;
+	}
+
 	/// Update the internal run_state and the run_state in the DB.
-	/// Does not perform any checks!
-	async fn update_run_state(&mut self, sql_client: &tokio_postgres::Client, run_state: RunState) {
-		let state: &str = (match run_state {
-			RunState::Nothing => "nothing",
-			RunState::Queued => "queued",
-			RunState::Running => "running",
-			RunState::Success => "success",
-			RunState::Failed => "failed",
-		}).into();
-		let result = sql_client.execute(include_str!("update_run_state.sql"), &[&self.wiid, &state]).await;
-		match result {
-			Err(e) => error!("Failed to update workflow_instance:{}\nScheduler state might de-sync!", e),
-			_ => (),
+	async fn update_run_state(&mut self, sql_client: &Db, run_state: RunState) -> Result<(), FlowtyError> {
+		self.update_run_state_with_reason(sql_client, run_state, None).await
+	}
+
+	/// Every change of the run_state goes through here. Transitions the automaton does not allow are rejected,
+	/// staying in the current state is a no-op. A final state written after a cancel was requested in the DB ends
+	/// up as cancelled, which the instance adopts.
+	async fn update_run_state_with_reason(
+		&mut self,
+		sql_client: &Db,
+		run_state: RunState,
+		reason: Option<String>
+	) -> Result<(), FlowtyError> {
+		if self.run_state == run_state {
+			return Ok(());
+		}
+		self.run_state.check_transition(&self.run_id, run_state)?;
+		let written = sql_client
+			.write_state(StateWrite::RunState { wiid: self.wiid, run_state: run_state.as_str(), reason })
+			.await
+			.map(|written| RunState::from_str(&written));
+		let run_state = match written {
+			Some(RunState::Cancelled) if run_state != RunState::Cancelled => {
+				info!("Run '{}' of workflow '{}' was cancelled before it ended", self.run_id, self.workflow_id);
+				RunState::Cancelled
+			},
+			_ => run_state,
 		};
+		if matches!(run_state, RunState::Running) && self.running_since.is_none() {
+			self.running_since = Some(Utc::now());
+		}
 		self.run_state = run_state;
+		self.finished_at = if self.is_active() { None } else { Some(Utc::now()) };
+		Ok(())
 	}
 
-	pub async fn queue(&mut self, sql_client: &tokio_postgres::Client) {
-		if matches!(self.run_state, RunState::Queued | RunState::Running | RunState::Success) {
-			return;
-		}
-		self.update_run_state(sql_client, RunState::Queued).await;
+	pub async fn queue(&mut self, sql_client: &Db) -> Result<(), FlowtyError> {
+		self.update_run_state(sql_client, RunState::Queued).await?;
 		/*
 			Err(e) => {
 				error!("Failed to find an apprioriate executor: {}", e);
@@ -114,67 +395,649 @@ impl WorkflowInstance {
 				self.run_state = RunState::Failed;
 			}
 		*/
+		Ok(())
+	}
+
+	/// Starts a queued instance and returns every task whose run_condition and dependencies are met.
+	/// The returned tasks are dispatched by the workflow as task slots allow.
+	/// Sensors are evaluated by the scheduler itself instead of being dispatched.
+	/// Called on every tick while the instance is active.
+	pub async fn run(&mut self, sql_client: &Db, settings: &WorkflowSettings) -> Vec<NodeIndex> {
+		match self.advance(sql_client, settings).await {
+			Ok(ready) => ready,
+			Err(e) => {
+				error!("{}", e);
+				Vec::new()
+			}
+		}
 	}
 
-	pub async fn run(&mut self, sql_client: &tokio_postgres::Client) {
-		info!("Starting workflow '{}'", self.workflow_id);
+	async fn advance(&mut self, sql_client: &Db, settings: &WorkflowSettings) -> Result<Vec<NodeIndex>, FlowtyError> {
+		if matches!(self.run_state, RunState::Cancelling) || self.stopping.is_some() {
+			self.collect_task_status(sql_client).await;
+			if self.running_tasks() == 0 {
+				match self.stopping {
+					Some(_) => self.finish_stop(sql_client).await?,
+					None => self.finish_cancel(sql_client).await?,
+				}
+			}
+			return Ok(Vec::new());
+		}
+		if matches!(self.run_state, RunState::UpForRetry) {
+			if self.retry_at.is_empty() {
+				// Restored from the DB, the retry times only live in memory
+				info!("Queueing run '{}' of workflow '{}' again", self.run_id, self.workflow_id);
+				self.update_run_state(sql_client, RunState::Queued).await?;
+			} else if self.retry_at.values().any(|retry_at| *retry_at <= Utc::now()) {
+				info!("Retrying failed tasks of run '{}' of workflow '{}'", self.run_id, self.workflow_id);
+				self.update_run_state(sql_client, RunState::Running).await?;
+			}
+		}
+		let dependencies = &settings.dependencies;
+		if matches!(self.run_state, RunState::Queued | RunState::WaitingUpstream) {
+			if self.started_at.is_none() {
+				self.started_at = Some(Utc::now());
+			}
+			let workflow_dependencies: Vec<&Dependency> = dependencies.iter().filter(|d| d.task_id.is_none()).collect();
+			let waiting_since = self.started_at.unwrap_or_else(Utc::now);
+			match self.check_dependencies(sql_client, &workflow_dependencies, waiting_since).await {
+				DependencyState::Met => (),
+				DependencyState::Waiting => {
+					if matches!(self.run_state, RunState::Queued) {
+						info!("Run '{}' of workflow '{}' is waiting on upstream workflows", self.run_id, self.workflow_id);
+						self.update_run_state(sql_client, RunState::WaitingUpstream).await?;
+					}
+					return Ok(Vec::new());
+				},
+				DependencyState::TimedOut => {
+					let reason = "Timed out waiting on upstream workflows".to_string();
+					self.record_notification(NotificationEvent::Failure, None, reason.clone());
+					self.update_run_state_with_reason(sql_client, RunState::TimedOut, Some(reason)).await?;
+					return Ok(Vec::new());
+				},
+			};
+
+			info!(
+				"Starting {} run '{}' of workflow '{}' for {}",
+				self.run_type.as_str(),
+				self.run_id,
+				self.workflow_id,
+				self.local_run_date()
+			);
+			self.update_run_state(sql_client, RunState::Running).await?;
+		}
+		self.collect_task_status(sql_client).await;
 
+		if let (Some(run_timeout), Some(running_since)) = (settings.run_timeout, self.running_since) {
+			if Utc::now() - running_since > run_timeout {
+				self.time_out(sql_client, run_timeout).await?;
+				return Ok(Vec::new());
+			}
+		}
+
+		let mut ready = Vec::new();
 		match self.dag.next() {
 			Some(next_tasks) => {
 				for task in next_tasks {
-					let ti = self.dag.get_task_instance(task);
-					match ti.get_executor_definition() {
-						Ok(ed) => {
-							let executor = find_executor(ed).await;
-							match executor {
-								Ok(executor_uri) => {
-									let handle = tokio::spawn(async {
-										match ExecutorClient::connect(executor_uri).await {
-											Ok(mut executor) => (),
-											_ => (),
-										};
-									});
-								},
-								Err(_fe) => {
-									// todo: Fail task
-								}
+					if self.task_handles.contains_key(&task) {
+						continue;
+					}
+					if self.retry_at.get(&task).map_or(false, |retry_at| *retry_at > Utc::now()) {
+						continue;
+					}
+					let task_id = self.dag.get_task_instance(task).get_task_id().to_string();
+					let task_dependencies: Vec<&Dependency> = dependencies
+						.iter()
+						.filter(|d| d.task_id.as_ref() == Some(&task_id))
+						.collect();
+					let waited = self.waiting_tasks.get(&task).cloned();
+					let waiting_since = waited.unwrap_or_else(Utc::now);
+					match self.check_dependencies(sql_client, &task_dependencies, waiting_since).await {
+						DependencyState::Met => {
+							self.waiting_tasks.remove(&task);
+							match settings.sensors.iter().find(|s| s.task_id == task_id) {
+								Some(sensor) => self.sense(sql_client, task, sensor).await,
+								None => ready.push(task),
 							};
 						},
-						Err(_fe) => {
-							// todo: Fail Task
-						}
+						DependencyState::Waiting => {
+							if waited.is_none() {
+								info!("Task '{}' of '{}' is waiting on upstream workflows", task_id, self.run_id);
+								self.waiting_tasks.insert(task, waiting_since);
+								self.update_task_state(sql_client, &task_id, "waiting_upstream").await;
+							}
+						},
+						DependencyState::TimedOut => {
+							self.waiting_tasks.remove(&task);
+							self.fail_task(sql_client, task).await;
+						},
 					};
 				}
 			},
-			None => self.finish(sql_client).await,
+			None => self.finish(sql_client).await?,
 		};
+		let waits_for_retries_only = ready.is_empty()
+			&& self.running_tasks() == 0
+			&& self.sensor_pokes.is_empty()
+			&& self.waiting_tasks.is_empty()
+			&& !self.retry_at.is_empty();
+		if matches!(self.run_state, RunState::Running) && waits_for_retries_only {
+			info!("Run '{}' of workflow '{}' is waiting to retry failed tasks", self.run_id, self.workflow_id);
+			self.update_run_state(sql_client, RunState::UpForRetry).await?;
+		}
+		Ok(ready)
+	}
+
+	/// Checks the dependencies against the upstream runs in the DB. Timeouts are measured from `waiting_since`.
+	async fn check_dependencies(
+		&self,
+		sql_client: &Db,
+		dependencies: &[&Dependency],
+		waiting_since: DateTime<Utc>
+	) -> DependencyState {
+		let mut state = DependencyState::Met;
+		for dependency in dependencies {
+			match dependency.check(sql_client, &self.run_date, &waiting_since).await {
+				DependencyState::Met => (),
+				DependencyState::Waiting => state = DependencyState::Waiting,
+				DependencyState::TimedOut => {
+					error!(
+						"Run '{}' of workflow '{}' timed out waiting on upstream workflow {}",
+						self.run_id,
+						self.workflow_id,
+						dependency.describe()
+					);
+					return DependencyState::TimedOut;
+				},
+			}
+		}
+		state
 	}
 
-	pub async fn finish(&mut self, sql_client: &tokio_postgres::Client) {
+	/// Applies the status updates reported by the executor streams to the Dag and persists them.
+	/// A failed task with retries left is reset, to be dispatched again once its retry_interval passed.
+	async fn collect_task_status(&mut self, sql_client: &Db) {
+		while let Ok((task, attempt, status)) = self.status_rx.try_recv() {
+			let task_id = self.dag.get_task_instance(task).get_task_id().to_string();
+			if attempt != self.dag.get_retries(task) {
+				trace!("Ignoring {:?} of attempt {} of task '{}' of '{}'", status, attempt, task_id, self.run_id);
+				continue;
+			}
+			if self.dag.get_task_instance(task).get_execution_status() == Some(status) {
+				continue;
+			}
+			trace!("Task '{}' of '{}' is {:?}", task_id, self.run_id, status);
+			let cancelling = matches!(self.run_state, RunState::Cancelling) || self.stopping.is_some();
+			if status == ExecutionStatus::Failed && !cancelling {
+				if let Some(retry_interval) = self.dag.retry(task) {
+					warn!(
+						"Task '{}' of '{}' failed. Retrying in {}s (retry {})",
+						task_id,
+						self.run_id,
+						retry_interval.num_seconds(),
+						self.dag.get_retries(task)
+					);
+					self.task_handles.remove(&task);
+					self.retry_at.insert(task, Utc::now() + retry_interval);
+					self.update_task_state(sql_client, &task_id, "up_for_retry").await;
+					let message = format!("Attempt {} failed, retrying in {}s", attempt + 1, retry_interval.num_seconds());
+					self.record_notification(NotificationEvent::Retry, Some(task_id), message);
+					continue;
+				}
+			}
+			self.dag.set_execution_status(task, status);
+			if matches!(status, ExecutionStatus::Success | ExecutionStatus::Failed) {
+				self.end_task(task);
+			}
+			let state = match status {
+				ExecutionStatus::Failed if cancelling => "cancelled".to_string(),
+				_ => format!("{:?}", status).to_lowercase(),
+			};
+			self.update_task_state(sql_client, &task_id, &state).await;
+			match status {
+				ExecutionStatus::Success => {
+					self.record_notification(NotificationEvent::Success, Some(task_id), "Task succeeded".to_string());
+				},
+				ExecutionStatus::Failed if !cancelling => {
+					let message = format!("Task failed after {} attempts", attempt + 1);
+					self.record_notification(NotificationEvent::Failure, Some(task_id), message);
+				},
+				_ => (),
+			}
+		}
+	}
 
+	fn record_notification(&mut self, event: NotificationEvent, task_id: Option<String>, message: String) {
+		self.notifications.push(Notification {
+			event,
+			workflow_id: self.workflow_id.clone(),
+			run_id: self.run_id.clone(),
+			run_date: self.local_run_date(),
+			task_id,
+			message,
+		});
+	}
+
+	/// Hands over the notifications recorded since the last call.
+	pub fn take_notifications(&mut self) -> Vec<Notification> {
+		std::mem::take(&mut self.notifications)
+	}
+
+	async fn update_task_state(&self, sql_client: &Db, task_id: &str, state: &str) {
+		sql_client.write_state(StateWrite::TaskState {
+			wiid: self.wiid,
+			task_id: task_id.to_string(),
+			state: state.to_string(),
+		}).await;
+	}
+
+	async fn fail_task(&mut self, sql_client: &Db, task: NodeIndex) {
+		let task_id = self.dag.get_task_instance(task).get_task_id().to_string();
+		self.dag.set_execution_status(task, ExecutionStatus::Failed);
+		self.end_task(task);
+		self.update_task_state(sql_client, &task_id, "failed").await;
+		self.record_notification(NotificationEvent::Failure, Some(task_id), "Task failed".to_string());
+	}
+
+	/// Pokes a sensor if its poke interval elapsed and applies the result to the Dag.
+	/// HTTP pokes run in the background, their result is applied on the tick after it arrived.
+	async fn sense(&mut self, sql_client: &Db, task: NodeIndex, sensor: &Sensor) {
+		let now = Utc::now();
+		let success = match self.http_pokes.get_mut(&task).map(|poke| poke.try_recv()) {
+			Some(Err(oneshot::error::TryRecvError::Empty)) => return,
+			Some(result) => {
+				self.http_pokes.remove(&task);
+				result.unwrap_or(false)
+			},
+			None => {
+				let started_at = match self.sensor_pokes.get(&task) {
+					Some((_, last_poke)) if !sensor.is_due(*last_poke, now) => return,
+					Some((started_at, _)) => *started_at,
+					None => {
+						info!("Starting sensor '{}' of '{}'", sensor.task_id, self.run_id);
+						self.update_task_state(sql_client, &sensor.task_id, "sensing").await;
+						self.task_times.entry(task).or_insert((now, None));
+						now
+					},
+				};
+				self.sensor_pokes.insert(task, (started_at, now));
+
+				let target = sensor.render_target(&self.template_context());
+				if matches!(sensor.kind, SensorKind::Http) {
+					self.http_pokes.insert(task, sensor.spawn_http_poke(target));
+					return;
+				}
+				sensor.poke(sql_client, &target).await
+			},
+		};
+
+		let started_at = self.sensor_pokes.get(&task).map_or(now, |(started_at, _)| *started_at);
+		match sensor.state(success, started_at, Utc::now()) {
+			SensorState::Success => {
+				info!("Sensor '{}' of '{}' succeeded", sensor.task_id, self.run_id);
+				self.sensor_pokes.remove(&task);
+				self.dag.set_execution_status(task, ExecutionStatus::Success);
+				self.end_task(task);
+				self.update_task_state(sql_client, &sensor.task_id, "success").await;
+			},
+			SensorState::Waiting => (),
+			SensorState::TimedOut => {
+				self.sensor_pokes.remove(&task);
+				if sensor.soft_fail {
+					warn!("Sensor '{}' of '{}' timed out. Skipping it", sensor.task_id, self.run_id);
+					self.dag.skip(task);
+					self.end_task(task);
+					self.update_task_state(sql_client, &sensor.task_id, "skipped").await;
+				} else {
+					error!("Sensor '{}' of '{}' timed out", sensor.task_id, self.run_id);
+					self.fail_task(sql_client, task).await;
+				}
+			},
+		};
+	}
+
+	/// Sends the task to an executor, holding `slot` until it finished.
+	/// Returns false if the task could not be dispatched and failed instead.
+	pub async fn dispatch(&mut self, sql_client: &Db, task: NodeIndex, dispatcher: &Dispatcher, slot: Slot) -> bool {
+		let ti = self.dag.get_task_instance(task);
+		let task_id = ti.get_task_id().to_string();
+		let executor = match ti.get_executor_definition() {
+			Ok(ed) => dispatcher.find_executor(ed).await,
+			Err(fe) => Err(fe),
+		};
+		let definition = self.tasks.iter().find(|t| t.task_id == task_id).cloned();
+
+		match (executor, definition) {
+			(Ok(executor_uri), Some(definition)) => {
+				let definition = self.template_context().render_task(&definition);
+				let status_tx = self.status_tx.clone();
+				info!("Dispatching task '{}' of '{}' to {}", task_id, self.run_id, executor_uri);
+				let attempt = self.dag.get_retries(task);
+				let execution_id = format!("{}-{}-{}", self.wiid, task.index(), attempt);
+				let handle = dispatcher.spawn(slot, executor_uri, definition, task, attempt, execution_id, status_tx);
+				self.task_handles.insert(task, handle);
+				self.task_times.entry(task).or_insert((Utc::now(), None));
+				self.retry_at.remove(&task);
+				true
+			},
+			(Err(fe), _) => {
+				error!("Failed to dispatch task '{}' of '{}': {}", task_id, self.run_id, fe);
+				self.fail_task(sql_client, task).await;
+				false
+			},
+			(_, None) => {
+				error!("Task '{}' of '{}' has no definition", task_id, self.run_id);
+				self.fail_task(sql_client, task).await;
+				false
+			},
+		}
+	}
+
+	/// Stops an instance which exceeded the run timeout of its workflow.
+	async fn time_out(&mut self, sql_client: &Db, run_timeout: chrono::Duration) -> Result<(), FlowtyError> {
+		let reason = format!("Timed out after running for {}s", run_timeout.num_seconds());
+		error!("Run '{}' of workflow '{}': {}", self.run_id, self.workflow_id, reason);
+		self.stop(sql_client, RunState::TimedOut, "run_timed_out", reason).await
+	}
+
+	/// Ends an active instance in `run_state` with `reason` and records `event`.
+	/// The executors of in-flight tasks are asked to terminate them like on `cancel`. The instance ends right away
+	/// if none are in flight, otherwise on a later tick once the streams of all of them ended.
+	pub async fn stop(
+		&mut self,
+		sql_client: &Db,
+		run_state: RunState,
+		event: &str,
+		reason: String
+	) -> Result<(), FlowtyError> {
+		if self.stopping.is_some() {
+			return Ok(());
+		}
+		self.run_state.check_transition(&self.run_id, run_state)?;
+		self.cancel_in_flight_tasks();
+		self.stopping = Some((run_state, event.to_string(), reason));
+		if self.running_tasks() == 0 {
+			self.finish_stop(sql_client).await?;
+		}
+		Ok(())
+	}
+
+	async fn finish_stop(&mut self, sql_client: &Db) -> Result<(), FlowtyError> {
+		let (run_state, event, reason) = match self.stopping.take() {
+			Some(stopping) => stopping,
+			None => return Ok(()),
+		};
+		self.task_handles.clear();
+		self.skip_pending_tasks(sql_client).await;
+		event::record(sql_client, &self.workflow_id, &event, &format!("Run '{}': {}", self.run_id, reason)).await;
+		self.update_run_state_with_reason(sql_client, run_state, Some(reason.clone())).await?;
+		if matches!(self.run_state, RunState::TimedOut) {
+			self.record_notification(NotificationEvent::Failure, None, reason);
+		}
+		Ok(())
+	}
+
+	/// Requests cancellation of the instance. The executors of in-flight tasks are asked to terminate them.
+	/// The instance turns cancelled on a later tick, once the streams of all of them ended.
+	pub async fn cancel(&mut self, sql_client: &Db) -> Result<(), FlowtyError> {
+		if matches!(self.run_state, RunState::Cancelling) || self.stopping.is_some() {
+			return Ok(());
+		}
+		let reason = "Cancelled on request".to_string();
+		self.update_run_state_with_reason(sql_client, RunState::Cancelling, Some(reason)).await?;
+		info!("Cancelling run '{}' of workflow '{}'", self.run_id, self.workflow_id);
+		self.cancel_in_flight_tasks();
+		Ok(())
+	}
+
+	/// Asks the executors of the in-flight tasks to terminate them. Their streams keep being read until they end.
+	fn cancel_in_flight_tasks(&mut self) {
+		let in_flight: Vec<NodeIndex> = self.task_handles
+			.keys()
+			.cloned()
+			.filter(|task| self.is_in_flight(*task))
+			.collect();
+		for task in in_flight {
+			info!("Cancelling task '{}' of '{}'", self.get_task_id(task), self.run_id);
+			if let Some(handle) = self.task_handles.get_mut(&task) {
+				handle.cancel();
+			}
+		}
+	}
+
+	async fn finish_cancel(&mut self, sql_client: &Db) -> Result<(), FlowtyError> {
+		self.task_handles.clear();
+		self.skip_pending_tasks(sql_client).await;
+		event::record(sql_client, &self.workflow_id, "run_cancelled", &format!("Run '{}' was cancelled", self.run_id)).await;
+		self.update_run_state_with_reason(sql_client, RunState::Cancelled, Some("Cancelled on request".to_string())).await
+	}
+
+	/// Skips the tasks which did not start yet.
+	async fn skip_pending_tasks(&mut self, sql_client: &Db) {
+		let pending: Vec<NodeIndex> = self.dag
+			.node_indices()
+			.filter(|task| {
+				let ti = self.dag.get_task_instance(*task);
+				ti.get_execution_status().is_none() && !ti.is_skipped()
+			})
+			.collect();
+		for task in pending {
+			let task_id = self.get_task_id(task).to_string();
+			self.dag.skip(task);
+			self.update_task_state(sql_client, &task_id, "skipped").await;
+		}
+		self.waiting_tasks.clear();
+		self.sensor_pokes.clear();
+		self.http_pokes.clear();
+		self.retry_at.clear();
+	}
+
+	pub async fn finish(&mut self, sql_client: &Db) -> Result<(), FlowtyError> {
+		let run_state = if self.dag.is_success() { RunState::Success } else { RunState::Failed };
+		info!("Finished run '{}' of workflow '{}'", self.run_id, self.workflow_id);
+		self.task_handles.clear();
+		self.update_run_state(sql_client, run_state).await?;
+		match self.run_state {
+			RunState::Success => self.record_notification(NotificationEvent::Success, None, "Run succeeded".to_string()),
+			RunState::Failed => self.record_notification(NotificationEvent::Failure, None, "Run failed".to_string()),
+			_ => (),
+		}
+		Ok(())
+	}
+
+	/// Records the end of a task which started or failed to start.
+	fn end_task(&mut self, task: NodeIndex) {
+		let now = Utc::now();
+		let (_, finished_at) = self.task_times.entry(task).or_insert((now, None));
+		if finished_at.is_none() {
+			*finished_at = Some(now);
+		}
+	}
+
+	/// When the run, or its task `task_id` if set, started and finished, to check SLAs against.
+	/// None if it is not known, e.g. for tasks which finished before the instance was restored.
+	pub fn progress(&self, task_id: Option<&str>) -> Option<Progress> {
+		let task_id = match task_id {
+			Some(task_id) => task_id,
+			None => return Some(Progress { started_at: self.running_since, finished_at: self.finished_at }),
+		};
+		let task = self.dag.node_indices().find(|task| self.get_task_id(*task) == task_id)?;
+		match self.task_times.get(&task) {
+			Some((started_at, finished_at)) => Some(Progress { started_at: Some(*started_at), finished_at: *finished_at }),
+			None => {
+				let ti = self.dag.get_task_instance(task);
+				if ti.get_execution_status().is_some() || ti.is_skipped() {
+					return None;
+				}
+				// Tasks which never started, e.g. below a failed one, end along with the run
+				Some(Progress { started_at: None, finished_at: self.finished_at })
+			},
+		}
+	}
+
+	fn template_context(&self) -> TemplateContext {
+		TemplateContext {
+			run_id: &self.run_id,
+			run_date: self.local_run_date(),
+			conf: &self.conf,
+		}
 	}
 
 	pub fn get_run_state(&self) -> &RunState {
 		&self.run_state
 	}
+
+	/// Active instances are advanced on every tick.
+	pub fn is_active(&self) -> bool {
+		matches!(
+			self.run_state,
+			RunState::Queued
+				| RunState::WaitingUpstream
+				| RunState::Running
+				| RunState::UpForRetry
+				| RunState::Cancelling
+		)
+	}
+
+	/// Active instances count against max_active_runs, unless they were stopped and only wait for their cancelled
+	/// tasks to end.
+	pub fn holds_slot(&self) -> bool {
+		self.is_active() && self.stopping.is_none()
+	}
+
+	pub fn get_run_type(&self) -> RunType {
+		self.run_type
+	}
+
+	pub fn get_run_id(&self) -> &str {
+		&self.run_id
+	}
+
+	pub fn get_wiid(&self) -> i32 {
+		self.wiid
+	}
+
+	pub fn get_run_date(&self) -> DateTime<Utc> {
+		self.run_date
+	}
+
+	pub fn get_finished_at(&self) -> Option<DateTime<Utc>> {
+		self.finished_at
+	}
+
+	pub fn get_task_id(&self, task: NodeIndex) -> &str {
+		self.dag.get_task_instance(task).get_task_id()
+	}
+
+	/// Dispatched tasks which did not report a final status yet. They count against max_active_tasks.
+	pub fn running_tasks(&self) -> usize {
+		self.task_handles.keys().filter(|task| self.is_in_flight(**task)).count()
+	}
+
+	fn is_in_flight(&self, task: NodeIndex) -> bool {
+		!matches!(
+			self.dag.get_task_instance(task).get_execution_status(),
+			Some(ExecutionStatus::Success) | Some(ExecutionStatus::Failed)
+		)
+	}
+
+	/// The run_date on the wall clock of the workflow's timezone.
+	pub fn local_run_date(&self) -> DateTime<Tz> {
+		self.run_date.with_timezone(&self.timezone)
+	}
 }
 
-/// Asks the ExecutionBroker for a fitting executor.
-/// Returns the URI to the executor
-async fn find_executor(definition: &ExecutorDefinition) -> Result<String, FlowtyError> {
-	let broker_uri = utils::get_env("EXECUTION_BROKER_URI", "http://[::1]:50051".into());
-	match ExecutionBrokerClient::connect(broker_uri).await {
-		Ok(mut client) => {
-			match client.find_executor(Request::new(SearchRequest {
-				executor_definition: Some(ExecutorDefinition {
-					kind: ExecutorKind::Local.into(),
-					specs: definition.specs.clone(),
-				})
-			})).await {
-				Ok(response) => Ok(response.into_inner().uri),
-				Err(_) => Err(FlowtyError::ExecutorNotFound)
-			}
-		},
-		_ => Err(FlowtyError::ExecutionBrokerUnreachable)
+/// Dropping the task handles alone does not stop the tasks. An instance is dropped while tasks are in flight when
+/// its workflow stops being scheduled here, e.g. because the shard was lost. Hanging up on the tasks makes their
+/// executors terminate them, so they do not keep running next to the ones the new owner dispatches.
+impl Drop for WorkflowInstance {
+	fn drop(&mut self) {
+		for (task, handle) in &self.task_handles {
+			if self.is_in_flight(*task) {
+				warn!("Hanging up on task '{}' of '{}'", self.get_task_id(*task), self.run_id);
+				handle.abort();
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ALL: [RunState; 11] = [
+		RunState::Nothing,
+		RunState::Queued,
+		RunState::WaitingUpstream,
+		RunState::Running,
+		RunState::Success,
+		RunState::Failed,
+		RunState::Cancelling,
+		RunState::Cancelled,
+		RunState::Skipped,
+		RunState::UpForRetry,
+		RunState::TimedOut,
+	];
+
+	#[test]
+	fn transitions() {
+		use RunState::*;
+		let allowed: &[(RunState, &[RunState])] = &[
+			(Nothing, &[Queued, Skipped, Cancelled]),
+			(Queued, &[WaitingUpstream, Running, TimedOut, Cancelled, Cancelling, Skipped]),
+			(WaitingUpstream, &[Running, TimedOut, Cancelled, Cancelling]),
+			(Running, &[Success, Failed, TimedOut, Cancelled, Cancelling, UpForRetry]),
+			(UpForRetry, &[Queued, Running, TimedOut, Cancelled, Cancelling]),
+			(Cancelling, &[Cancelled]),
+			(Success, &[]),
+			(Failed, &[]),
+			(Cancelled, &[]),
+			(Skipped, &[]),
+			(TimedOut, &[]),
+		];
+		assert_eq!(allowed.len(), ALL.len());
+		for (from, targets) in allowed {
+			for to in ALL.iter() {
+				assert_eq!(
+					from.can_transition_to(*to),
+					targets.contains(to),
+					"{:?} => {:?}",
+					from,
+					to
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn illegal_transition_is_an_error() {
+		assert!(RunState::Running.check_transition("r1", RunState::UpForRetry).is_ok());
+		match RunState::Success.check_transition("r1", RunState::Running) {
+			Err(FlowtyError::IllegalRunStateTransition { run_id, from, to }) => {
+				assert_eq!(run_id, "r1");
+				assert_eq!(from, "success");
+				assert_eq!(to, "running");
+			}
+			other => panic!("Unexpected result {:?}", other),
+		}
+	}
+
+	#[test]
+	fn stopped_instances_free_their_slot() {
+		let mut instance = WorkflowInstance::build(
+			1, &"wf".to_string(), "r1".to_string(), RunType::Scheduled, Utc::now(), Tz::UTC, &Vec::new()
+		).unwrap();
+		instance.run_state = RunState::Running;
+		assert!(instance.holds_slot());
+		instance.stopping = Some((RunState::Cancelled, "run_cancelled".to_string(), "Cancelled".to_string()));
+		assert!(instance.is_active());
+		assert!(!instance.holds_slot());
+	}
+
+	#[test]
+	fn names_round_trip() {
+		for state in ALL.iter() {
+			assert_eq!(RunState::from_str(state.as_str()), *state);
+		}
 	}
 }
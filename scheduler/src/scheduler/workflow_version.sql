@@ -0,0 +1 @@
+SELECT wid, openworkflow_message FROM workflow WHERE workflow_id = $1 AND wid = $2;
\ No newline at end of file
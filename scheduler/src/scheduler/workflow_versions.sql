@@ -0,0 +1,6 @@
+SELECT workflow.wid, COUNT(workflow_instance.wiid) AS runs
+FROM workflow
+LEFT JOIN workflow_instance ON workflow_instance.wid = workflow.wid
+WHERE workflow.workflow_id = $1
+GROUP BY workflow.wid
+ORDER BY workflow.wid DESC;
\ No newline at end of file
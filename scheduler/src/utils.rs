@@ -1,3 +0,0 @@
-pub fn get_env(env: &str, default: String) -> String {
-	dotenv::var(env).unwrap_or_else(|_| default)
-}
\ No newline at end of file